@@ -0,0 +1,96 @@
+use std::io::{self, Read, Write};
+
+use serde_bytes::ByteBuf;
+
+use crate::SqliteStoreError;
+
+/// One session in an archive: its id as text, its expiry in Unix nanoseconds
+/// and the `data` column exactly as stored.
+pub(crate) type Entry = (String, i64, ByteBuf);
+
+/// Writes `entry` as a MessagePack frame prefixed with its length as a
+/// little-endian `u32`.
+pub(crate) fn write_entry(w: &mut impl Write, entry: &Entry) -> Result<(), SqliteStoreError> {
+    let frame = rmp_serde::to_vec(entry)?;
+    let len = u32::try_from(frame.len())
+        .map_err(|_| SqliteStoreError::Serialize("archive entry exceeds 4 GiB".into()))?;
+
+    w.write_all(&len.to_le_bytes())?;
+    w.write_all(&frame)?;
+
+    Ok(())
+}
+
+/// Reads the next frame written by [`write_entry`], or `None` at the end of
+/// the stream.
+pub(crate) fn read_entry(r: &mut impl Read) -> Result<Option<Entry>, SqliteStoreError> {
+    let mut len = [0; 4];
+    let mut filled = 0;
+
+    // Running out of input is only fine between frames.
+    while filled < len.len() {
+        match r.read(&mut len[filled..]) {
+            Ok(0) if filled == 0 => return Ok(None),
+            Ok(0) => return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+            Ok(n) => filled += n,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err.into()),
+        }
+    }
+
+    // The buffer grows with what is actually read rather than being sized
+    // up front, so a corrupt length can't allocate gigabytes.
+    let len = u32::from_le_bytes(len);
+    let mut frame = Vec::new();
+    r.take(len.into()).read_to_end(&mut frame)?;
+    if frame.len() < len as usize {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+    }
+
+    Ok(Some(rmp_serde::from_slice(&frame)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_bytes::ByteBuf;
+
+    use super::*;
+
+    #[test]
+    fn round_trips_entries() {
+        let entries = [
+            ("a".to_owned(), 1, ByteBuf::from(vec![1, 2, 3])),
+            ("b".to_owned(), i64::MAX, ByteBuf::new()),
+        ];
+
+        let mut archive = Vec::new();
+        for entry in &entries {
+            write_entry(&mut archive, entry).unwrap();
+        }
+
+        let mut r = archive.as_slice();
+        for entry in &entries {
+            assert_eq!(read_entry(&mut r).unwrap().as_ref(), Some(entry));
+        }
+        assert_eq!(read_entry(&mut r).unwrap(), None);
+    }
+
+    #[test]
+    fn rejects_a_length_past_the_end_of_the_stream() {
+        let mut archive = u32::MAX.to_le_bytes().to_vec();
+        archive.extend_from_slice(b"short");
+
+        assert!(matches!(
+            read_entry(&mut archive.as_slice()),
+            Err(SqliteStoreError::Io(err)) if err.kind() == io::ErrorKind::UnexpectedEof
+        ));
+    }
+
+    #[test]
+    fn rejects_a_truncated_length() {
+        assert!(matches!(
+            read_entry(&mut [1, 0].as_slice()),
+            Err(SqliteStoreError::Io(err)) if err.kind() == io::ErrorKind::UnexpectedEof
+        ));
+    }
+}
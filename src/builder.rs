@@ -0,0 +1,535 @@
+use std::{fmt, path::PathBuf, sync::Arc, time::Duration};
+
+use r2d2_sqlite::{
+    SqliteConnectionManager,
+    rusqlite::{Connection, Error as SqlError, OpenFlags},
+};
+
+use crate::{PragmaConfig, SqliteStore, SqliteStoreError, is_valid_identifier};
+
+/// Builds a [`SqliteStore`] together with its connection pool.
+///
+/// Created through [`SqliteStore::builder`]. Without a [`path`](Self::path)
+/// the store is backed by an in-memory database, as with
+/// [`SqliteStore::in_memory`].
+#[derive(Clone, Debug, Default)]
+pub struct SqliteStoreBuilder {
+    source: Source,
+    pragmas: Option<PragmaConfig>,
+    max_connections: Option<u32>,
+    min_idle: Option<u32>,
+    connection_timeout: Option<Duration>,
+    test_on_checkout: Option<bool>,
+    read_only: bool,
+    auto_migrate: bool,
+    attach: Option<(PathBuf, String)>,
+    open_flags: Option<OpenFlags>,
+    init: Option<ConnectionInit>,
+}
+
+type ConnectionInitFn = dyn Fn(&mut Connection) -> Result<(), SqlError> + Send + Sync;
+
+/// The closure set with [`SqliteStoreBuilder::with_init`].
+#[derive(Clone)]
+struct ConnectionInit(Arc<ConnectionInitFn>);
+
+impl fmt::Debug for ConnectionInit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ConnectionInit")
+    }
+}
+
+/// Where the pool's connections are opened.
+#[derive(Clone, Debug, Default)]
+enum Source {
+    File(PathBuf),
+    #[default]
+    Memory,
+    SharedMemory(String),
+}
+
+impl SqliteStoreBuilder {
+    /// Opens (or creates) the database file at `path`.
+    pub fn path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.source = Source::File(path.into());
+        self
+    }
+
+    /// Uses an in-memory database, undoing an earlier [`path`](Self::path).
+    pub fn in_memory(mut self) -> Self {
+        self.source = Source::Memory;
+        self
+    }
+
+    /// Uses the in-memory database called `name`, shared by every connection
+    /// opened with that name in this process, including other pools.
+    ///
+    /// Unlike [`in_memory`](Self::in_memory), the pool isn't limited to one
+    /// connection. The database only exists while a connection to it is
+    /// open, so the pool never closes its connections for being idle or old,
+    /// which would otherwise take the database and its sessions with them
+    /// once r2d2's `idle_timeout` or `max_lifetime` passes. Connections share
+    /// it through SQLite's shared cache, where a connection blocked by
+    /// another's write fails with `SQLITE_LOCKED` straight away instead of
+    /// honouring the busy timeout; a [`RetryPolicy`](crate::RetryPolicy)
+    /// smooths over that for concurrent writers.
+    pub fn in_memory_shared(mut self, name: impl Into<String>) -> Self {
+        self.source = Source::SharedMemory(name.into());
+        self
+    }
+
+    /// Attaches the database file at `path` as `schema` on each connection and
+    /// keeps the session table there, through
+    /// [`SqliteStore::with_schema_prefix`], e.g. to back sessions up apart
+    /// from the main database while still being able to join against them.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `schema` isn't a plain identifier, as with
+    /// [`SqliteStore::with_schema_prefix`].
+    pub fn attach(mut self, path: impl Into<PathBuf>, schema: impl Into<String>) -> Self {
+        let schema = schema.into();
+
+        assert!(
+            is_valid_identifier(&schema),
+            "invalid schema name {schema:?}: expected [A-Za-z_][A-Za-z0-9_]*"
+        );
+
+        self.attach = Some((path.into(), schema));
+        self
+    }
+
+    /// Applies `pragmas` to each connection as the pool opens it, for the
+    /// [attached database](Self::attach) as well as the main one.
+    pub fn with_pragmas(mut self, pragmas: PragmaConfig) -> Self {
+        self.pragmas = Some(pragmas);
+        self
+    }
+
+    /// Caps the pool at `max` connections (r2d2's `max_size`). Defaults to 10
+    /// for files and 1 for in-memory databases.
+    pub fn max_connections(mut self, max: u32) -> Self {
+        self.max_connections = Some(max);
+        self
+    }
+
+    /// Keeps at least `min` idle connections open; defaults to
+    /// `max_connections`.
+    pub fn min_idle(mut self, min: u32) -> Self {
+        self.min_idle = Some(min);
+        self
+    }
+
+    /// How long a checkout waits for a free connection before failing with
+    /// [`SqliteStoreError::PoolTimeout`]; defaults to 30 seconds.
+    pub fn connection_timeout(mut self, timeout: Duration) -> Self {
+        self.connection_timeout = Some(timeout);
+        self
+    }
+
+    /// Whether r2d2 checks that a connection still works before handing it
+    /// out (`test_on_check_out`); on by default, as in r2d2.
+    ///
+    /// The check runs an empty statement on every checkout, costing a little
+    /// latency per operation, and retires a connection that can no longer
+    /// run statements instead of failing the request with it. For a session
+    /// store that is usually worth keeping; turning it off suits pools whose
+    /// connections can't go bad, like in-memory ones, when latency matters
+    /// most.
+    pub fn with_test_on_checkout(mut self, enabled: bool) -> Self {
+        self.test_on_checkout = Some(enabled);
+        self
+    }
+
+    /// Opens every connection with `SQLITE_OPEN_READ_ONLY` and builds a
+    /// [read-only store](SqliteStore::new_read_only).
+    ///
+    /// The database must already exist and be migrated, and its journal mode
+    /// can't be changed, so [pragmas](Self::with_pragmas) should keep the one
+    /// it was created with.
+    pub fn read_only(mut self) -> Self {
+        self.read_only = true;
+        self
+    }
+
+    /// Opens every connection with `flags` instead of rusqlite's defaults,
+    /// e.g. to add `SQLITE_OPEN_SHARED_CACHE`.
+    ///
+    /// `flags` must hold exactly one of `SQLITE_OPEN_READ_ONLY` and
+    /// `SQLITE_OPEN_READ_WRITE`; with the former the store is built
+    /// [read-only](Self::read_only). `SQLITE_OPEN_URI` is added for
+    /// [shared in-memory](Self::in_memory_shared) databases, which need it.
+    /// [`build`](Self::build) fails with [`SqliteStoreError::InvalidConfig`]
+    /// on flags that contradict the rest of the configuration.
+    pub fn with_open_flags(mut self, flags: OpenFlags) -> Self {
+        self.open_flags = Some(flags);
+        self
+    }
+
+    /// Runs `init` on each connection as the pool opens it, e.g. to register
+    /// custom SQL functions or set pragmas [`PragmaConfig`] doesn't cover.
+    ///
+    /// It runs after the [attached database](Self::attach) and
+    /// [pragmas](Self::with_pragmas) are set up, so it can override them. An
+    /// error fails the checkout, and with it [`build`](Self::build) for the
+    /// connections r2d2 opens up front.
+    pub fn with_init(
+        mut self,
+        init: impl Fn(&mut Connection) -> Result<(), SqlError> + Send + Sync + 'static,
+    ) -> Self {
+        self.init = Some(ConnectionInit(Arc::new(init)));
+        self
+    }
+
+    /// Runs [`SqliteStore::migrate`] as part of [`build`](Self::build), so the
+    /// store is ready to use straight away.
+    pub fn auto_migrate(mut self) -> Self {
+        self.auto_migrate = true;
+        self
+    }
+
+    pub fn build(self) -> Result<SqliteStore, SqliteStoreError> {
+        let read_only = self.read_only
+            || self
+                .open_flags
+                .is_some_and(|flags| flags.contains(OpenFlags::SQLITE_OPEN_READ_ONLY));
+
+        if let Some(flags) = self.open_flags {
+            if flags.contains(OpenFlags::SQLITE_OPEN_READ_ONLY)
+                == flags.contains(OpenFlags::SQLITE_OPEN_READ_WRITE)
+            {
+                return Err(SqliteStoreError::InvalidConfig(
+                    "open flags need exactly one of SQLITE_OPEN_READ_ONLY and SQLITE_OPEN_READ_WRITE",
+                ));
+            }
+
+            if self.read_only && flags.contains(OpenFlags::SQLITE_OPEN_READ_WRITE) {
+                return Err(SqliteStoreError::InvalidConfig(
+                    "read_only() conflicts with SQLITE_OPEN_READ_WRITE",
+                ));
+            }
+        }
+
+        if read_only && self.auto_migrate {
+            return Err(SqliteStoreError::InvalidConfig(
+                "auto_migrate() needs a writable database",
+            ));
+        }
+
+        let mut pool = r2d2::Pool::builder();
+
+        let shared_memory = matches!(self.source, Source::SharedMemory(_));
+
+        let mut manager = match self.source {
+            Source::File(path) => SqliteConnectionManager::file(path),
+            Source::Memory => {
+                pool = pool.max_size(1);
+                SqliteConnectionManager::memory()
+            }
+            Source::SharedMemory(name) => {
+                // r2d2's reaper closes every expired idle connection at once
+                // and only replaces them later, dropping the database in
+                // between, so connections are kept for the pool's lifetime.
+                pool = pool.idle_timeout(None).max_lifetime(None);
+
+                // The name becomes part of a URI, so characters with a meaning
+                // there are escaped.
+                let name = name
+                    .replace('%', "%25")
+                    .replace('?', "%3f")
+                    .replace('#', "%23");
+
+                SqliteConnectionManager::file(format!("file:{name}?mode=memory&cache=shared"))
+            }
+        };
+
+        let flags = match self.open_flags {
+            Some(flags) if shared_memory => Some(flags | OpenFlags::SQLITE_OPEN_URI),
+            Some(flags) => Some(flags),
+            None if self.read_only => Some(
+                OpenFlags::SQLITE_OPEN_READ_ONLY
+                    | OpenFlags::SQLITE_OPEN_URI
+                    | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+            ),
+            None => None,
+        };
+
+        if let Some(flags) = flags {
+            manager = manager.with_flags(flags);
+        }
+
+        let schema = self.attach.as_ref().map(|(_, schema)| schema.clone());
+
+        if self.attach.is_some() || self.pragmas.is_some() || self.init.is_some() {
+            let attach = self.attach;
+            let pragmas = self.pragmas;
+            let init = self.init;
+
+            // Attaching first lets the pragmas cover the attached database
+            // too.
+            manager = manager.with_init(move |conn| {
+                if let Some((path, schema)) = &attach {
+                    conn.execute(
+                        &format!("attach database ?1 as {schema}"),
+                        [path.to_string_lossy()],
+                    )?;
+                }
+
+                if let Some(pragmas) = &pragmas {
+                    let attached = attach.as_ref().map(|(_, schema)| schema.as_str());
+                    pragmas.apply(conn, attached)?;
+                }
+
+                match &init {
+                    Some(init) => init.0(conn),
+                    None => Ok(()),
+                }
+            });
+        }
+
+        if let Some(max) = self.max_connections {
+            pool = pool.max_size(max);
+        }
+
+        if let Some(min) = self.min_idle {
+            pool = pool.min_idle(Some(min));
+        }
+
+        if let Some(timeout) = self.connection_timeout {
+            pool = pool.connection_timeout(timeout);
+        }
+
+        if let Some(enabled) = self.test_on_checkout {
+            pool = pool.test_on_check_out(enabled);
+        }
+
+        let pool = pool.build(manager)?;
+
+        let mut store = if read_only {
+            SqliteStore::new_read_only(pool)
+        } else {
+            SqliteStore::new(pool)
+        };
+
+        if let Some(schema) = schema {
+            store = store.with_schema_prefix(schema);
+        }
+
+        if self.auto_migrate {
+            store.migrate_with_conn(&mut *store.conn()?)?;
+        }
+
+        Ok(store)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use time::{Duration, OffsetDateTime};
+    use tower_sessions_core::{
+        SessionStore,
+        session::{Id, Record},
+    };
+
+    use super::*;
+
+    fn record() -> Record {
+        Record {
+            id: Id::default(),
+            data: Default::default(),
+            expiry_date: OffsetDateTime::now_utc() + Duration::hours(1),
+        }
+    }
+
+    #[tokio::test]
+    async fn shared_memory_is_seen_by_every_connection() {
+        let store = SqliteStore::builder()
+            .in_memory_shared("shared_memory_is_seen_by_every_connection")
+            .max_connections(4)
+            .build()
+            .unwrap();
+        store.migrate().unwrap();
+
+        let mut record = record();
+        store.create(&mut record).await.unwrap();
+
+        // Hold three connections so the last one is a different one again.
+        let held = (0..3)
+            .map(|_| store.pool().get().unwrap())
+            .collect::<Vec<_>>();
+        let conn = store.pool().get().unwrap();
+        let count: i64 = conn
+            .query_row("select count(*) from tower_sessions", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, 1);
+        drop((held, conn));
+
+        assert_eq!(store.load(&record.id).await.unwrap(), Some(record));
+    }
+
+    #[test]
+    fn shared_memory_connections_are_never_reaped() {
+        let store = SqliteStore::builder()
+            .in_memory_shared("shared_memory_connections_are_never_reaped")
+            .min_idle(0)
+            .build()
+            .unwrap();
+
+        assert_eq!(store.pool().idle_timeout(), None);
+        assert_eq!(store.pool().max_lifetime(), None);
+    }
+
+    #[test]
+    fn pool_size_defaults_and_overrides() {
+        let memory = SqliteStore::builder().build().unwrap();
+        assert_eq!(memory.pool().max_size(), 1);
+
+        let shared = SqliteStore::builder()
+            .in_memory_shared("pool_size_defaults_and_overrides")
+            .build()
+            .unwrap();
+        assert_eq!(shared.pool().max_size(), 10);
+
+        let capped = SqliteStore::builder()
+            .in_memory_shared("pool_size_defaults_and_overrides_capped")
+            .max_connections(3)
+            .min_idle(1)
+            .build()
+            .unwrap();
+        assert_eq!(capped.pool().max_size(), 3);
+        assert_eq!(capped.pool().min_idle(), Some(1));
+
+        let held = (0..3)
+            .map(|_| capped.pool().get().unwrap())
+            .collect::<Vec<_>>();
+        assert!(
+            capped
+                .pool()
+                .get_timeout(std::time::Duration::from_millis(10))
+                .is_err()
+        );
+        drop(held);
+    }
+
+    #[tokio::test]
+    async fn explicit_open_flags_open_a_working_store() {
+        let store = SqliteStore::builder()
+            .in_memory_shared("explicit_open_flags_open_a_working_store")
+            .with_open_flags(
+                OpenFlags::SQLITE_OPEN_READ_WRITE
+                    | OpenFlags::SQLITE_OPEN_CREATE
+                    | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+            )
+            .auto_migrate()
+            .build()
+            .unwrap();
+
+        let mut record = record();
+        store.create(&mut record).await.unwrap();
+        assert_eq!(store.load(&record.id).await.unwrap(), Some(record));
+    }
+
+    #[test]
+    fn contradictory_open_flags_are_rejected() {
+        let build = |builder: SqliteStoreBuilder| match builder.build() {
+            Err(SqliteStoreError::InvalidConfig(message)) => message,
+            other => panic!("expected an invalid configuration, got {other:?}"),
+        };
+
+        assert!(
+            build(SqliteStore::builder().with_open_flags(
+                OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_READ_WRITE
+            ))
+            .contains("exactly one")
+        );
+        assert!(
+            build(SqliteStore::builder().with_open_flags(OpenFlags::SQLITE_OPEN_CREATE))
+                .contains("exactly one")
+        );
+        assert!(
+            build(
+                SqliteStore::builder()
+                    .read_only()
+                    .with_open_flags(OpenFlags::SQLITE_OPEN_READ_WRITE)
+            )
+            .contains("read_only()")
+        );
+        assert!(
+            build(
+                SqliteStore::builder()
+                    .with_open_flags(OpenFlags::SQLITE_OPEN_READ_ONLY)
+                    .auto_migrate()
+            )
+            .contains("auto_migrate()")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_on_checkout_defaults_on_and_can_be_turned_off() {
+        assert!(
+            SqliteStore::builder()
+                .build()
+                .unwrap()
+                .pool()
+                .test_on_check_out()
+        );
+
+        for enabled in [true, false] {
+            let store = SqliteStore::builder()
+                .with_test_on_checkout(enabled)
+                .auto_migrate()
+                .build()
+                .unwrap();
+            assert_eq!(store.pool().test_on_check_out(), enabled);
+
+            let mut record = record();
+            store.create(&mut record).await.unwrap();
+            assert_eq!(store.load(&record.id).await.unwrap(), Some(record));
+        }
+    }
+
+    #[test]
+    fn init_runs_on_every_connection_after_the_pragmas() {
+        use r2d2_sqlite::rusqlite::functions::FunctionFlags;
+
+        let store = SqliteStore::builder()
+            .max_connections(2)
+            .with_pragmas(PragmaConfig {
+                synchronous: crate::Synchronous::Off,
+                ..Default::default()
+            })
+            .with_init(|conn| {
+                conn.pragma_update(None, "synchronous", "FULL")?;
+                conn.create_scalar_function(
+                    "double",
+                    1,
+                    FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC,
+                    |ctx| Ok(ctx.get::<i64>(0)? * 2),
+                )
+            })
+            .build()
+            .unwrap();
+
+        let conns = [store.pool().get().unwrap(), store.pool().get().unwrap()];
+        for conn in &conns {
+            let doubled: i64 = conn
+                .query_row("select double(21)", [], |row| row.get(0))
+                .unwrap();
+            assert_eq!(doubled, 42);
+            let synchronous: i64 = conn
+                .query_row("pragma synchronous", [], |row| row.get(0))
+                .unwrap();
+            assert_eq!(synchronous, 2);
+        }
+    }
+
+    #[test]
+    fn failing_init_fails_the_build() {
+        let res = SqliteStore::builder()
+            .with_init(|conn| conn.execute_batch("select no_such_function()"))
+            .connection_timeout(std::time::Duration::from_millis(100))
+            .build();
+
+        assert!(res.is_err());
+    }
+}
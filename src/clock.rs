@@ -0,0 +1,23 @@
+use std::fmt::Debug;
+
+use time::OffsetDateTime;
+
+/// Where the store reads the current time from when deciding whether a
+/// session has expired; see
+/// [`SqliteStore::with_clock`](crate::SqliteStore::with_clock).
+///
+/// Swapping in a manually advanced clock makes expiry testable, since
+/// `tokio::time` pausing has no effect on [`OffsetDateTime::now_utc`].
+pub trait Clock: Debug + Send + Sync + 'static {
+    fn now(&self) -> OffsetDateTime;
+}
+
+/// The default clock, reading the system time.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> OffsetDateTime {
+        OffsetDateTime::now_utc()
+    }
+}
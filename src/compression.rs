@@ -0,0 +1,63 @@
+use crate::{COMPRESSED_MARKER, SqliteStoreError};
+
+/// zstd compression level used by [`SqliteStore::with_compression`].
+///
+/// [`SqliteStore::with_compression`]: crate::SqliteStore::with_compression
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CompressionLevel(i32);
+
+impl CompressionLevel {
+    pub const FASTEST: Self = Self(1);
+    pub const BEST: Self = Self(19);
+
+    /// Any level zstd accepts; out of range values are clamped by zstd.
+    pub const fn new(level: i32) -> Self {
+        Self(level)
+    }
+}
+
+impl Default for CompressionLevel {
+    fn default() -> Self {
+        Self(zstd::DEFAULT_COMPRESSION_LEVEL)
+    }
+}
+
+pub(crate) fn compress(bytes: &[u8], level: CompressionLevel) -> Result<Vec<u8>, SqliteStoreError> {
+    let mut blob = vec![COMPRESSED_MARKER];
+    zstd::stream::copy_encode(bytes, &mut blob, level.0).map_err(SqliteStoreError::Compression)?;
+    Ok(blob)
+}
+
+pub(crate) fn decompress(blob: &[u8]) -> Result<Vec<u8>, SqliteStoreError> {
+    zstd::stream::decode_all(blob).map_err(SqliteStoreError::Compression)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_behind_the_marker() {
+        let bytes = b"session data ".repeat(100);
+
+        for level in [
+            CompressionLevel::FASTEST,
+            CompressionLevel::default(),
+            CompressionLevel::BEST,
+        ] {
+            let blob = compress(&bytes, level).unwrap();
+
+            assert_eq!(blob[0], COMPRESSED_MARKER);
+            assert!(blob.len() < bytes.len() / 10);
+            assert_eq!(decompress(&blob[1..]).unwrap(), bytes);
+        }
+    }
+
+    #[test]
+    fn rejects_data_that_isnt_zstd() {
+        assert!(matches!(
+            decompress(b"not zstd"),
+            Err(SqliteStoreError::Compression(_))
+        ));
+    }
+}
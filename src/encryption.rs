@@ -0,0 +1,107 @@
+use std::fmt;
+
+use chacha20poly1305::{
+    XChaCha20Poly1305, XNonce,
+    aead::{Aead, Generate, KeyInit},
+};
+
+use crate::{ENCRYPTED_MARKER, SqliteStoreError};
+
+const NONCE_LEN: usize = 24;
+
+/// Seals blobs with XChaCha20-Poly1305 under a fixed key.
+#[derive(Clone)]
+pub(crate) struct Encryption {
+    cipher: XChaCha20Poly1305,
+}
+
+impl fmt::Debug for Encryption {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Encryption").finish_non_exhaustive()
+    }
+}
+
+impl Encryption {
+    pub(crate) fn new(key: [u8; 32]) -> Self {
+        Self {
+            cipher: XChaCha20Poly1305::new(&key.into()),
+        }
+    }
+
+    /// Returns the marker, a fresh random nonce and the ciphertext, in that
+    /// order.
+    pub(crate) fn encrypt(&self, plaintext: &[u8]) -> Result<Vec<u8>, SqliteStoreError> {
+        let nonce = XNonce::generate();
+
+        let ciphertext = self
+            .cipher
+            .encrypt(&nonce, plaintext)
+            .map_err(|_| SqliteStoreError::Encryption("encrypting the session failed".into()))?;
+
+        let mut blob = Vec::with_capacity(1 + NONCE_LEN + ciphertext.len());
+        blob.push(ENCRYPTED_MARKER);
+        blob.extend_from_slice(&nonce);
+        blob.extend_from_slice(&ciphertext);
+
+        Ok(blob)
+    }
+
+    /// Opens a blob produced by [`encrypt`](Self::encrypt), without its
+    /// marker byte.
+    pub(crate) fn decrypt(&self, sealed: &[u8]) -> Result<Vec<u8>, SqliteStoreError> {
+        if sealed.len() < NONCE_LEN {
+            return Err(SqliteStoreError::Encryption(
+                "encrypted blob is too short".into(),
+            ));
+        }
+
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+        let nonce = XNonce::try_from(nonce).expect("nonce slice has the nonce length");
+
+        self.cipher.decrypt(&nonce, ciphertext).map_err(|_| {
+            SqliteStoreError::Encryption(
+                "decryption failed: wrong key or tampered session data".into(),
+            )
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_with_a_fresh_nonce_each_time() {
+        let encryption = Encryption::new([7; 32]);
+
+        let first = encryption.encrypt(b"session").unwrap();
+        let second = encryption.encrypt(b"session").unwrap();
+
+        assert_eq!(first[0], ENCRYPTED_MARKER);
+        assert_ne!(first, second);
+        assert_eq!(encryption.decrypt(&first[1..]).unwrap(), b"session");
+        assert_eq!(encryption.decrypt(&second[1..]).unwrap(), b"session");
+    }
+
+    #[test]
+    fn rejects_the_wrong_key_and_tampering() {
+        let blob = Encryption::new([7; 32]).encrypt(b"session").unwrap();
+
+        assert!(matches!(
+            Encryption::new([8; 32]).decrypt(&blob[1..]),
+            Err(SqliteStoreError::Encryption(_))
+        ));
+
+        let mut tampered = blob.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(matches!(
+            Encryption::new([7; 32]).decrypt(&tampered[1..]),
+            Err(SqliteStoreError::Encryption(_))
+        ));
+
+        assert!(matches!(
+            Encryption::new([7; 32]).decrypt(&blob[1..NONCE_LEN]),
+            Err(SqliteStoreError::Encryption(message)) if message.contains("too short")
+        ));
+    }
+}
@@ -0,0 +1,78 @@
+use std::{
+    ops::{Deref, DerefMut},
+    time::{Duration, Instant},
+};
+
+use r2d2::{ManageConnection, PooledConnection};
+use r2d2_sqlite::rusqlite::Connection;
+
+/// How many virtual machine instructions SQLite runs between checks of the
+/// query budget.
+const CHECK_EVERY_OPS: i32 = 1000;
+
+/// A connection checked out of a store's pool.
+///
+/// With a [query interrupt](crate::SqliteStore::with_query_interrupt) budget,
+/// a progress handler aborts statements once the budget has passed since the
+/// checkout, and is removed again when the connection goes back to the pool
+/// so other users of the pool aren't affected.
+pub(crate) struct StoreConn<M>
+where
+    M: ManageConnection<Connection = Connection>,
+{
+    conn: PooledConnection<M>,
+    interruptible: bool,
+}
+
+impl<M> StoreConn<M>
+where
+    M: ManageConnection<Connection = Connection>,
+{
+    pub(crate) fn new(conn: PooledConnection<M>, budget: Option<Duration>) -> Self {
+        let Some(budget) = budget else {
+            return Self {
+                conn,
+                interruptible: false,
+            };
+        };
+
+        let start = Instant::now();
+        conn.progress_handler(CHECK_EVERY_OPS, Some(move || start.elapsed() > budget));
+
+        Self {
+            conn,
+            interruptible: true,
+        }
+    }
+}
+
+impl<M> Deref for StoreConn<M>
+where
+    M: ManageConnection<Connection = Connection>,
+{
+    type Target = Connection;
+
+    fn deref(&self) -> &Connection {
+        &self.conn
+    }
+}
+
+impl<M> DerefMut for StoreConn<M>
+where
+    M: ManageConnection<Connection = Connection>,
+{
+    fn deref_mut(&mut self) -> &mut Connection {
+        &mut self.conn
+    }
+}
+
+impl<M> Drop for StoreConn<M>
+where
+    M: ManageConnection<Connection = Connection>,
+{
+    fn drop(&mut self) {
+        if self.interruptible {
+            self.conn.progress_handler(0, None::<fn() -> bool>);
+        }
+    }
+}
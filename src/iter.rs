@@ -0,0 +1,135 @@
+use std::collections::VecDeque;
+
+use r2d2::ManageConnection;
+use r2d2_sqlite::rusqlite::{Connection, params, types::Value};
+use tower_sessions_core::{
+    session::{Id, Record},
+    session_store,
+};
+
+use crate::{Data, SqliteStore, SqliteStoreError};
+
+/// Walks a session table in id order, one page of rows per query.
+///
+/// Returned by [`SqliteStore::iter_records`].
+pub(crate) struct RecordIter<M>
+where
+    M: ManageConnection<Connection = Connection>,
+{
+    store: SqliteStore<M>,
+    /// The id of the last row fetched, which the next page starts after.
+    last_id: Option<Value>,
+    /// The fetched rows not yet yielded, as their id, data and expiry.
+    page: VecDeque<(Value, Vec<u8>, i64)>,
+    done: bool,
+}
+
+impl<M> RecordIter<M>
+where
+    M: ManageConnection<Connection = Connection>,
+{
+    pub(crate) fn new(store: SqliteStore<M>) -> Self {
+        Self {
+            store,
+            last_id: None,
+            page: VecDeque::new(),
+            done: false,
+        }
+    }
+
+    fn fetch_page(&mut self) -> Result<(), SqliteStoreError> {
+        let after = if self.last_id.is_some() {
+            "where id > ?2"
+        } else {
+            ""
+        };
+        let query = format!(
+            "select id, data, expiry_date from {table} {after} order by id limit ?1",
+            table = self.store.table
+        );
+        let conn = self.store.conn()?;
+        let mut stmt = conn.prepare_cached(&query)?;
+
+        let limit = self.store.batch_size as i64;
+        let mut rows = match &self.last_id {
+            Some(last_id) => stmt.query(params![limit, last_id])?,
+            None => stmt.query(params![limit])?,
+        };
+
+        while let Some(row) = rows.next()? {
+            let id: Value = row.get(0)?;
+            let Data(data) = row.get(1)?;
+            self.last_id = Some(id.clone());
+            self.page.push_back((id, data, row.get(2)?));
+        }
+
+        if self.page.len() < self.store.batch_size {
+            self.done = true;
+        }
+
+        Ok(())
+    }
+}
+
+impl<M> Iterator for RecordIter<M>
+where
+    M: ManageConnection<Connection = Connection>,
+{
+    type Item = session_store::Result<(Id, Record)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.page.is_empty()
+            && !self.done
+            && let Err(err) = self.fetch_page()
+        {
+            self.done = true;
+            return Some(Err(err.into()));
+        }
+
+        let (id, data, expiry_date) = self.page.pop_front()?;
+
+        Some(
+            self.store
+                .decode_row((&id).into(), &data, expiry_date)
+                .map_err(Into::into),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use time::{Duration, OffsetDateTime};
+
+    use super::*;
+    use crate::SqliteStore;
+
+    #[tokio::test]
+    async fn yields_every_session_once_a_page_at_a_time() {
+        let store = SqliteStore::in_memory().unwrap().with_batch_size(100);
+        store.migrate().unwrap();
+
+        let records: Vec<_> = (0..1000)
+            .map(|n| Record {
+                id: Id::default(),
+                data: Default::default(),
+                // Expired sessions are exported too.
+                expiry_date: OffsetDateTime::now_utc() + Duration::minutes(n - 100),
+            })
+            .collect();
+        store.save_many(&records).await.unwrap();
+
+        let mut iter = RecordIter::new(store);
+        let mut seen = HashSet::new();
+        while let Some(item) = iter.next() {
+            let (id, record) = item.unwrap();
+            assert_eq!(record.id, id);
+            assert!(seen.insert(id), "{id} yielded twice");
+            assert!(iter.page.len() < 100);
+        }
+
+        let stored: HashSet<_> = records.iter().map(|record| record.id).collect();
+        assert_eq!(seen, stored);
+    }
+}
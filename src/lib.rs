@@ -1,17 +1,66 @@
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, HashMap},
+    fmt,
+    io::{Read, Write},
+    path::Path,
+    sync::Arc,
+    time::Instant,
+};
+
 use async_trait::async_trait;
+use dashmap::DashMap;
+use r2d2::ManageConnection;
 use time::OffsetDateTime;
 
 use r2d2_sqlite::{
     SqliteConnectionManager,
-    rusqlite::{Error as SqlError, OptionalExtension, params},
+    rusqlite::{
+        Connection, Error as SqlError, ErrorCode, OptionalExtension, Params, Row,
+        TransactionBehavior, ffi, params, params_from_iter,
+        types::{FromSql, FromSqlResult, Value, ValueRef},
+    },
 };
 
 use tower_sessions_core::{
-    SessionStore,
+    ExpiredDeletion, SessionStore,
     session::{Id, Record},
     session_store,
 };
 
+mod archive;
+mod builder;
+mod clock;
+#[cfg(feature = "compression")]
+mod compression;
+#[cfg(feature = "encryption")]
+mod encryption;
+mod interrupt;
+mod iter;
+mod observer;
+mod pragma;
+mod retry;
+mod serializer;
+mod sharded;
+mod single;
+#[cfg(test)]
+mod tests;
+mod typed;
+
+pub use builder::SqliteStoreBuilder;
+pub use clock::{Clock, SystemClock};
+#[cfg(feature = "compression")]
+pub use compression::CompressionLevel;
+pub use observer::{Operation, SessionEvent, StoreObserver};
+pub use pragma::{JournalMode, PragmaConfig, Synchronous};
+pub use retry::RetryPolicy;
+#[cfg(feature = "json")]
+pub use serializer::JsonSerializer;
+pub use serializer::{MessagePackSerializer, SessionSerializer};
+pub use sharded::ShardedSqliteStore;
+pub use single::{SingleConnStore, SingleConnectionManager};
+pub use typed::TypedSqliteStore;
+
 #[derive(thiserror::Error, Debug)]
 pub enum SqliteStoreError {
     #[error(transparent)]
@@ -20,147 +69,4540 @@ pub enum SqliteStoreError {
     #[error(transparent)]
     R2d2(#[from] r2d2::Error),
 
+    /// No pooled connection became available within the pool's
+    /// `connection_timeout`.
+    #[error("Connection pool exhausted: {0}")]
+    PoolTimeout(r2d2::Error),
+
     #[error(transparent)]
     Encode(#[from] rmp_serde::encode::Error),
 
     #[error(transparent)]
     Decode(#[from] rmp_serde::decode::Error),
+
+    #[error("No unused session id found after {0} attempts")]
+    IdCollisionExhausted(usize),
+
+    #[error("Serializing failed with: {0}")]
+    Serialize(String),
+
+    #[error("Deserializing failed with: {0}")]
+    Deserialize(String),
+
+    #[cfg(feature = "json")]
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+
+    #[error("Compression failed with: {0}")]
+    Compression(std::io::Error),
+
+    #[error("Encryption failed with: {0}")]
+    Encryption(String),
+
+    /// Reading or writing an [archive](SqliteStore::export_to_writer) failed.
+    #[error("Archive I/O failed with: {0}")]
+    Io(#[from] std::io::Error),
+
+    /// A stored session id, shown here as SQL would, doesn't parse back into
+    /// an [`Id`], e.g. because the row was written by hand.
+    #[error("Invalid stored session id {0}")]
+    InvalidId(String),
+
+    /// A stored record names another session than the row holding it, e.g.
+    /// because the row's id was changed by hand.
+    #[error("Session {row} holds the record of session {record}")]
+    IdMismatch { row: Id, record: Id },
+
+    /// The session id a record was to be stored under is taken.
+    #[error("Session id {0} is already in use")]
+    DuplicateId(Id),
+
+    /// An operation didn't finish within the
+    /// [operation timeout](SqliteStore::with_operation_timeout).
+    #[error("Operation timed out after {0:?}")]
+    Timeout(std::time::Duration),
+
+    /// A statement was aborted for running past the
+    /// [query interrupt](SqliteStore::with_query_interrupt) budget.
+    #[error("Query interrupted after exceeding its {0:?} budget")]
+    Interrupted(std::time::Duration),
+
+    /// The session table is missing a column the store needs, or declares it
+    /// with another type; see [`SqliteStore::verify_schema`].
+    #[error("Session table doesn't match the expected schema: expected {expected}, found {found}")]
+    SchemaMismatch { expected: String, found: String },
+
+    /// A write was attempted through a [read-only](SqliteStore::new_read_only)
+    /// store.
+    #[error("Store is read-only")]
+    ReadOnly,
+
+    /// The store or its [builder](SqliteStoreBuilder) was given settings that
+    /// can't be combined.
+    #[error("Invalid store configuration: {0}")]
+    InvalidConfig(&'static str),
+
+    /// A stored blob starts with a format tag this version of the crate
+    /// doesn't know, e.g. one written by a newer version.
+    #[error("Unknown blob format tag {0:#04x}")]
+    UnknownFormat(u8),
+
+    /// The linked SQLite library is older than a configured option needs.
+    #[error("{feature} needs SQLite {required} or newer, found {found}")]
+    UnsupportedSqlite {
+        feature: &'static str,
+        required: &'static str,
+        found: &'static str,
+    },
+
+    #[error(transparent)]
+    Join(#[from] tokio::task::JoinError),
+}
+
+impl SqliteStoreError {
+    /// Whether SQLite reported the database as busy or locked, meaning the
+    /// same operation may well succeed if tried again shortly.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            SqliteStoreError::Rusqlite(err) => matches!(
+                err.sqlite_error_code(),
+                Some(ErrorCode::DatabaseBusy | ErrorCode::DatabaseLocked)
+            ),
+            _ => false,
+        }
+    }
 }
 
 impl From<SqliteStoreError> for session_store::Error {
     fn from(err: SqliteStoreError) -> Self {
         match err {
+            SqliteStoreError::Rusqlite(SqlError::SqliteFailure(failure, message)) => {
+                // Keep the extended result code machine-readable at the end.
+                let message = message.unwrap_or_else(|| failure.to_string());
+                session_store::Error::Backend(format!(
+                    "{message} (sqlite code {})",
+                    failure.extended_code
+                ))
+            }
             SqliteStoreError::Rusqlite(inner) => session_store::Error::Backend(inner.to_string()),
             SqliteStoreError::R2d2(inner) => session_store::Error::Backend(inner.to_string()),
+            err @ SqliteStoreError::PoolTimeout(_) => {
+                session_store::Error::Backend(err.to_string())
+            }
             SqliteStoreError::Decode(inner) => session_store::Error::Decode(inner.to_string()),
             SqliteStoreError::Encode(inner) => session_store::Error::Encode(inner.to_string()),
+            err @ SqliteStoreError::IdCollisionExhausted(_) => {
+                session_store::Error::Backend(err.to_string())
+            }
+            SqliteStoreError::Serialize(inner) => session_store::Error::Encode(inner),
+            SqliteStoreError::Deserialize(inner) => session_store::Error::Decode(inner),
+            // Records always encode to JSON, so only decoding can fail.
+            #[cfg(feature = "json")]
+            SqliteStoreError::Json(inner) => session_store::Error::Decode(inner.to_string()),
+            SqliteStoreError::Compression(inner) => session_store::Error::Decode(inner.to_string()),
+            SqliteStoreError::Encryption(inner) => session_store::Error::Decode(inner),
+            err @ SqliteStoreError::Io(_) => session_store::Error::Backend(err.to_string()),
+            err @ SqliteStoreError::InvalidId(_) => session_store::Error::Decode(err.to_string()),
+            err @ SqliteStoreError::IdMismatch { .. } => {
+                session_store::Error::Decode(err.to_string())
+            }
+            err @ SqliteStoreError::DuplicateId(_) => {
+                session_store::Error::Backend(err.to_string())
+            }
+            err @ SqliteStoreError::Timeout(_) => session_store::Error::Backend(err.to_string()),
+            err @ SqliteStoreError::Interrupted(_) => {
+                session_store::Error::Backend(err.to_string())
+            }
+            err @ SqliteStoreError::SchemaMismatch { .. } => {
+                session_store::Error::Backend(err.to_string())
+            }
+            err @ SqliteStoreError::ReadOnly => session_store::Error::Backend(err.to_string()),
+            err @ SqliteStoreError::InvalidConfig(_) => {
+                session_store::Error::Backend(err.to_string())
+            }
+            err @ SqliteStoreError::UnknownFormat(_) => {
+                session_store::Error::Decode(err.to_string())
+            }
+            err @ SqliteStoreError::UnsupportedSqlite { .. } => {
+                session_store::Error::Backend(err.to_string())
+            }
+            SqliteStoreError::Join(inner) => session_store::Error::Backend(inner.to_string()),
+        }
+    }
+}
+
+/// Upper bound on the ids bound into a single `in (...)` list, keeping every
+/// statement under SQLite's historical limit of 999 bound variables.
+const ID_CHUNK_SIZE: usize = 900;
+
+/// Schema changes applied in order by `SqliteStore::migrate`, with
+/// `{table}` standing in for the configured table name.
+///
+/// Steps are never edited once released; schema changes append a new one.
+const MIGRATIONS: &[&str] = &[
+    r#"
+    create table if not exists {table} (
+        id text primary key not null,
+        data blob not null,
+        expiry_date integer not null
+    ){strict};
+    create index if not exists {schema}idx_{name}_expiry on {name}(expiry_date);
+"#,
+    // Expiry dates move from whole seconds to nanoseconds, saturating like
+    // `unix_nanos` does. Any nanosecond value after 1970-01-12 is above the
+    // cutoff, so rows that are already converted are left alone.
+    r#"
+    update {table} set expiry_date = case
+        when expiry_date > 9223372036 then 9223372036854775807
+        else expiry_date * 1000000000
+    end
+    where expiry_date < 1000000000000000;
+"#,
+    r#"
+    alter table {table} add column user_id text;
+    create index if not exists {schema}idx_{name}_user_id on {name}(user_id);
+"#,
+    // Existing rows count as created when the column is added.
+    r#"
+    alter table {table} add column created_at integer;
+    update {table} set created_at = cast(strftime('%s', 'now') as integer) * 1000000000;
+    create index if not exists {schema}idx_{name}_created_at on {name}(created_at);
+"#,
+    r#"
+    alter table {table} add column version integer not null default 0;
+"#,
+    r#"
+    alter table {table} add column tenant text;
+    create index if not exists {schema}idx_{name}_tenant on {name}(tenant);
+"#,
+    r#"
+    alter table {table} add column last_accessed integer;
+    create index if not exists {schema}idx_{name}_last_accessed on {name}(last_accessed);
+"#,
+];
+
+/// The table recording how many [`MIGRATIONS`] each session table has had,
+/// kept in the same database as the tables it describes.
+const VERSIONS_TABLE: &str = "tower_sessions_schema_versions";
+
+/// Rebuilds a fully migrated session table with its `data` column declared
+/// `text`, turning blobs that hold JSON into TEXT on the way. SQLite can't
+/// change a column's type in place.
+///
+/// Blobs that aren't JSON, such as compressed or encrypted records, are
+/// copied as they are: a `text` column in a table that isn't strict stores
+/// blobs unchanged.
+#[cfg(feature = "json")]
+const JSON_DATA_REBUILD: &str = r#"
+    create temp table {name}_rebuild as select
+        id, data, expiry_date, user_id, created_at, version, tenant, last_accessed
+    from {table};
+    drop table {table};
+    create table {table} (
+        id text primary key not null,
+        data text not null,
+        expiry_date integer not null,
+        user_id text,
+        created_at integer,
+        version integer not null default 0,
+        tenant text,
+        last_accessed integer
+    );
+    insert into {table}
+        (id, data, expiry_date, user_id, created_at, version, tenant, last_accessed)
+    select
+        id,
+        case
+            when typeof(data) = 'blob' and json_valid(cast(data as text)) then cast(data as text)
+            else data
+        end,
+        expiry_date, user_id, created_at, version, tenant, last_accessed
+    from temp.{name}_rebuild;
+    drop table temp.{name}_rebuild;
+    create index {schema}idx_{name}_expiry on {name}(expiry_date);
+    create index {schema}idx_{name}_user_id on {name}(user_id);
+    create index {schema}idx_{name}_created_at on {name}(created_at);
+    create index {schema}idx_{name}_tenant on {name}(tenant);
+    create index {schema}idx_{name}_last_accessed on {name}(last_accessed);
+"#;
+
+/// The columns, with their declared types, that the queries rely on once
+/// every migration has run. The `data` column's type depends on the
+/// configuration, see `SqliteStore::data_column_type`.
+const EXPECTED_COLUMNS: &[(&str, &str)] = &[
+    ("id", "text"),
+    ("data", "blob"),
+    ("expiry_date", "integer"),
+    ("user_id", "text"),
+    ("created_at", "integer"),
+    ("version", "integer"),
+    ("tenant", "text"),
+    ("last_accessed", "integer"),
+];
+
+/// How stale, in nanoseconds, a session's `last_accessed` may get before a
+/// [tracked](SqliteStore::with_track_access) load writes it again, so bursts
+/// of reads of one session cost a single write.
+const ACCESS_WRITE_INTERVAL: i64 = 1_000_000_000;
+
+/// How many ids `create` tries before giving up. Random 128-bit ids collide
+/// so rarely that running out points at a broken RNG or database.
+const MAX_CREATE_ATTEMPTS: usize = 10;
+
+/// First byte of an uncompressed, unencrypted blob written with
+/// [format tags](SqliteStore::with_format_tags), followed by the serializer's
+/// output.
+const PLAIN_MARKER: u8 = 0x01;
+
+/// First byte of every compressed blob.
+///
+/// Neither MessagePack nor JSON encodings of a record can start with this
+/// byte, so rows written before compression was enabled still decode.
+const COMPRESSED_MARKER: u8 = 0x02;
+
+/// First byte of every encrypted blob, followed by the nonce and ciphertext.
+const ENCRYPTED_MARKER: u8 = 0x03;
+
+/// The highest first byte reserved for format tags. Records from serializers
+/// that can start with such a byte are always tagged, see
+/// [`SessionSerializer::needs_format_tag`], so untagged rows are told apart
+/// from tagged ones, and a reserved byte without a meaning yet is an unknown
+/// format.
+const LAST_FORMAT_MARKER: u8 = 0x1f;
+
+/// The bytes in a `data` column, which holds BLOBs or, for records encoded
+/// as UTF-8, TEXT.
+struct Data(Vec<u8>);
+
+impl FromSql for Data {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        value.as_bytes().map(|bytes| Self(bytes.to_vec()))
+    }
+}
+
+/// Result of [`SqliteStore::load_detailed`].
+#[derive(Clone, Debug, PartialEq)]
+pub enum LoadOutcome {
+    Found(Record),
+    /// The session exists but its expiry date has passed.
+    Expired,
+    NotFound,
+}
+
+/// A snapshot of the connection pool's usage; see [`SqliteStore::pool_state`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PoolState {
+    /// Connections currently open, idle or checked out.
+    pub connections: u32,
+    /// Open connections waiting to be checked out.
+    pub idle_connections: u32,
+}
+
+impl PoolState {
+    /// Connections currently checked out.
+    pub fn in_use(&self) -> u32 {
+        self.connections - self.idle_connections
+    }
+}
+
+/// A release of the linked SQLite library; see [`SqliteStore::sqlite_version`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Version {
+    pub major: u32,
+    pub minor: u32,
+    pub patch: u32,
+}
+
+impl Version {
+    /// Splits a version number as SQLite encodes it, e.g. `3040001` for
+    /// 3.40.1.
+    pub fn from_number(number: u32) -> Self {
+        Self {
+            major: number / 1_000_000,
+            minor: number / 1_000 % 1_000,
+            patch: number % 1_000,
+        }
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+/// What [`load`](SessionStore::load) does with a row whose data fails to
+/// decode; see [`SqliteStore::with_corrupt_blob_policy`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CorruptBlobPolicy {
+    /// Fail the load with the decode error.
+    #[default]
+    Error,
+    /// Report the session as missing and leave the row alone.
+    TreatAsMissing,
+    /// Delete the row and report the session as missing. The load reports it
+    /// missing even if the delete fails, e.g. on a locked database.
+    DeleteAndMiss,
+}
+
+/// How [`create`](SessionStore::create) makes sure a new session's id isn't
+/// taken; see [`SqliteStore::with_collision_strategy`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CollisionStrategy {
+    /// Look the id up before inserting, at the cost of an extra query.
+    PreCheck,
+    /// Insert with `on conflict do nothing` and pick a new id if nothing was
+    /// inserted.
+    #[default]
+    OnConflictRetry,
+    /// Insert directly, retrying once with a new id if the insert fails on
+    /// the primary key.
+    AssumeUnique,
+}
+
+/// Which of a group of duplicate sessions
+/// [`deduplicate`](SqliteStore::deduplicate) keeps.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DedupKeep {
+    /// The most recently created one.
+    Newest,
+    /// The one expiring last.
+    LatestExpiry,
+}
+
+/// A session store over an r2d2 pool of rusqlite connections.
+///
+/// Pools from any manager handing out [`Connection`]s work; the manager
+/// defaults to r2d2_sqlite's [`SqliteConnectionManager`], so plain
+/// `SqliteStore` names the usual store.
+#[derive(Debug)]
+pub struct SqliteStore<M = SqliteConnectionManager>
+where
+    M: ManageConnection<Connection = Connection>,
+{
+    pool: r2d2::Pool<M>,
+    table_name: Arc<str>,
+    /// The attached database the table lives in, if not `main`.
+    schema: Option<Arc<str>>,
+    /// How queries refer to the table: its name, qualified by `schema`.
+    table: Arc<str>,
+    serializer: Arc<dyn SessionSerializer>,
+    #[cfg(feature = "compression")]
+    compression: Option<CompressionLevel>,
+    #[cfg(feature = "encryption")]
+    encryption: Option<Arc<encryption::Encryption>>,
+    batch_size: usize,
+    user_id_extractor: Option<RecordExtractor>,
+    tenant_extractor: Option<RecordExtractor>,
+    lazy_expiry_deletion: bool,
+    retry: Option<RetryPolicy>,
+    observer: Option<Arc<dyn StoreObserver>>,
+    change_listener: Option<ChangeListener>,
+    read_only: bool,
+    corrupt_blob_policy: CorruptBlobPolicy,
+    clock: Arc<dyn Clock>,
+    operation_timeout: Option<std::time::Duration>,
+    query_interrupt: Option<std::time::Duration>,
+    binary_ids: bool,
+    verify_schema_on_migrate: bool,
+    strict_tables: bool,
+    expiry_inclusive: bool,
+    skip_unchanged_saves: bool,
+    track_access: bool,
+    format_tags: bool,
+    collision_strategy: CollisionStrategy,
+    /// Per-session locks taken by `with_session_lock`, shared by clones.
+    session_locks: Arc<DashMap<Id, Arc<tokio::sync::Mutex<()>>>>,
+    /// Stands in for the linked SQLite's version number, so tests can take
+    /// the paths meant for older versions.
+    #[cfg(test)]
+    sqlite_version_override: Option<u32>,
+    /// Replaces the random ids `create` and `reserve_id` generate, so tests
+    /// can force collisions.
+    #[cfg(test)]
+    generated_id: Option<Id>,
+}
+
+// Derived `Clone` would require `M: Clone`, which managers rarely are.
+impl<M> Clone for SqliteStore<M>
+where
+    M: ManageConnection<Connection = Connection>,
+{
+    fn clone(&self) -> Self {
+        Self {
+            pool: self.pool.clone(),
+            table_name: self.table_name.clone(),
+            schema: self.schema.clone(),
+            table: self.table.clone(),
+            serializer: self.serializer.clone(),
+            #[cfg(feature = "compression")]
+            compression: self.compression,
+            #[cfg(feature = "encryption")]
+            encryption: self.encryption.clone(),
+            batch_size: self.batch_size,
+            user_id_extractor: self.user_id_extractor.clone(),
+            tenant_extractor: self.tenant_extractor.clone(),
+            lazy_expiry_deletion: self.lazy_expiry_deletion,
+            retry: self.retry,
+            observer: self.observer.clone(),
+            change_listener: self.change_listener.clone(),
+            read_only: self.read_only,
+            corrupt_blob_policy: self.corrupt_blob_policy,
+            clock: self.clock.clone(),
+            operation_timeout: self.operation_timeout,
+            query_interrupt: self.query_interrupt,
+            binary_ids: self.binary_ids,
+            verify_schema_on_migrate: self.verify_schema_on_migrate,
+            strict_tables: self.strict_tables,
+            expiry_inclusive: self.expiry_inclusive,
+            skip_unchanged_saves: self.skip_unchanged_saves,
+            track_access: self.track_access,
+            format_tags: self.format_tags,
+            collision_strategy: self.collision_strategy,
+            session_locks: self.session_locks.clone(),
+            #[cfg(test)]
+            sqlite_version_override: self.sqlite_version_override,
+            #[cfg(test)]
+            generated_id: self.generated_id,
         }
     }
 }
 
-#[derive(Clone, Debug)]
-pub struct SqliteStore {
-    pool: r2d2::Pool<SqliteConnectionManager>,
+type RecordExtractorFn = dyn Fn(&Record) -> Option<String> + Send + Sync;
+
+/// A closure deriving a column value from a record, e.g. its user id.
+#[derive(Clone)]
+struct RecordExtractor(Arc<RecordExtractorFn>);
+
+impl fmt::Debug for RecordExtractor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("RecordExtractor")
+    }
+}
+
+type ChangeListenerFn = dyn Fn(SessionEvent) + Send + Sync;
+
+/// The callback set with [`SqliteStore::with_change_listener`].
+#[derive(Clone)]
+struct ChangeListener(Arc<ChangeListenerFn>);
+
+impl fmt::Debug for ChangeListener {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ChangeListener")
+    }
 }
 
 impl SqliteStore {
-    pub fn new(r2d2_conn_pool: r2d2::Pool<SqliteConnectionManager>) -> Self {
+    /// Starts configuring a store along with the pool behind it.
+    pub fn builder() -> SqliteStoreBuilder {
+        SqliteStoreBuilder::default()
+    }
+
+    /// Opens (or creates) the database file at `path` behind a pool with
+    /// r2d2's default settings.
+    pub fn from_path(path: impl AsRef<Path>) -> Result<Self, SqliteStoreError> {
+        Self::builder().path(path.as_ref()).build()
+    }
+
+    /// Creates a store backed by a private in-memory database, which lives as
+    /// long as the store and its clones.
+    ///
+    /// The pool holds a single connection, so all operations take turns on it.
+    /// r2d2_sqlite shares one in-memory database between a manager's
+    /// connections through SQLite's shared cache, and concurrent shared-cache
+    /// connections fail with `SQLITE_LOCKED` rather than waiting for each
+    /// other.
+    pub fn in_memory() -> Result<Self, SqliteStoreError> {
+        Self::builder().in_memory().build()
+    }
+
+    /// Creates a store backed by the in-memory database `name`, which any
+    /// number of pooled connections (and other stores opened with the same
+    /// name) share. The pool keeps its connections open for as long as it
+    /// lives, since the database goes away with the last of them. See
+    /// [`SqliteStoreBuilder::in_memory_shared`] for the locking caveats.
+    pub fn in_memory_shared(name: impl Into<String>) -> Result<Self, SqliteStoreError> {
+        Self::builder().in_memory_shared(name).build()
+    }
+}
+
+/// A [migrated](SqliteStore::migrate) store over a private in-memory
+/// database, as from [`SqliteStore::in_memory`], for tests and prototypes.
+///
+/// ```rust
+/// use tower_sessions_core::{SessionStore, session::{Id, Record}};
+/// use tower_sessions_r2d2_sqlite_store::SqliteStore;
+///
+/// let store = SqliteStore::default();
+/// let mut record = Record {
+///     id: Id::default(),
+///     data: Default::default(),
+///     expiry_date: time::OffsetDateTime::now_utc() + time::Duration::hours(1),
+/// };
+///
+/// let runtime = tokio::runtime::Builder::new_current_thread().build()?;
+/// runtime.block_on(async {
+///     store.create(&mut record).await?;
+///     assert!(store.load(&record.id).await?.is_some());
+///     Ok::<(), tower_sessions_core::session_store::Error>(())
+/// })?;
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+///
+/// # Panics
+///
+/// Panics if SQLite can't open the database or create the table, which
+/// points at a broken SQLite build rather than anything the caller did.
+impl Default for SqliteStore {
+    fn default() -> Self {
+        Self::builder()
+            .in_memory()
+            .auto_migrate()
+            .build()
+            .expect("failed to set up an in-memory session store")
+    }
+}
+
+impl<M> SqliteStore<M>
+where
+    M: ManageConnection<Connection = Connection>,
+{
+    pub fn new(r2d2_conn_pool: r2d2::Pool<M>) -> Self {
         Self {
             pool: r2d2_conn_pool,
+            table_name: "tower_sessions".into(),
+            schema: None,
+            table: "tower_sessions".into(),
+            #[cfg(not(feature = "json"))]
+            serializer: Arc::new(MessagePackSerializer),
+            #[cfg(feature = "json")]
+            serializer: Arc::new(JsonSerializer),
+            #[cfg(feature = "compression")]
+            compression: None,
+            #[cfg(feature = "encryption")]
+            encryption: None,
+            batch_size: 500,
+            user_id_extractor: None,
+            tenant_extractor: None,
+            lazy_expiry_deletion: false,
+            retry: None,
+            observer: None,
+            change_listener: None,
+            read_only: false,
+            corrupt_blob_policy: CorruptBlobPolicy::Error,
+            clock: Arc::new(SystemClock),
+            operation_timeout: None,
+            query_interrupt: None,
+            binary_ids: false,
+            verify_schema_on_migrate: false,
+            strict_tables: false,
+            expiry_inclusive: false,
+            skip_unchanged_saves: false,
+            track_access: false,
+            format_tags: false,
+            collision_strategy: CollisionStrategy::OnConflictRetry,
+            session_locks: Arc::default(),
+            #[cfg(test)]
+            sqlite_version_override: None,
+            #[cfg(test)]
+            generated_id: None,
+        }
+    }
+
+    /// Creates a store over `r2d2_conn_pool` and [migrates](Self::migrate) the
+    /// default `tower_sessions` table right away.
+    ///
+    /// Stores using another [table name](Self::with_table_name) still need
+    /// their own `migrate` call once it is set.
+    pub fn new_migrated(r2d2_conn_pool: r2d2::Pool<M>) -> Result<Self, SqliteStoreError> {
+        let store = Self::new(r2d2_conn_pool);
+        store.migrate_with_conn(&mut *store.conn()?)?;
+        Ok(store)
+    }
+
+    /// Creates a store that only reads from `r2d2_conn_pool`, e.g. a pool over
+    /// a read-only replica.
+    ///
+    /// Every write, including [`migrate`](Self::migrate) and
+    /// [`delete_expired`](ExpiredDeletion::delete_expired), fails with
+    /// [`SqliteStoreError::ReadOnly`] before touching the database, and
+    /// [lazy expiry deletion](Self::with_lazy_expiry_deletion) is skipped.
+    /// The pool's connections aren't reopened; see
+    /// [`SqliteStoreBuilder::read_only`] for one opened with
+    /// `SQLITE_OPEN_READ_ONLY`.
+    pub fn new_read_only(r2d2_conn_pool: r2d2::Pool<M>) -> Self {
+        Self {
+            read_only: true,
+            ..Self::new(r2d2_conn_pool)
+        }
+    }
+
+    /// Stores sessions in `name` instead of the default `tower_sessions`
+    /// table, e.g. to keep several independent stores in one database.
+    ///
+    /// # Panics
+    ///
+    /// Table names are spliced into the SQL text since they can't be bound as
+    /// parameters, so anything that isn't a plain identifier
+    /// (`[A-Za-z_][A-Za-z0-9_]*`) is rejected with a panic.
+    pub fn with_table_name(mut self, name: impl Into<String>) -> Self {
+        let name = name.into();
+
+        assert!(
+            is_valid_identifier(&name),
+            "invalid session table name {name:?}: expected [A-Za-z_][A-Za-z0-9_]*"
+        );
+
+        self.table_name = name.into();
+        self.table = format!("{}{}", self.schema_prefix(), self.table_name).into();
+        self
+    }
+
+    /// Keeps the session table in the attached database `schema` rather than
+    /// in `main`, so queries use `schema.tower_sessions`.
+    ///
+    /// Every pooled connection has to attach the database under that name
+    /// before use, e.g. from r2d2_sqlite's `with_init`;
+    /// [`SqliteStoreBuilder::attach`] does this. [`migrate`](Self::migrate)
+    /// tracks the schema version in the attached database.
+    ///
+    /// # Panics
+    ///
+    /// Like table names, anything that isn't a plain identifier is rejected
+    /// with a panic.
+    pub fn with_schema_prefix(mut self, schema: impl Into<String>) -> Self {
+        let schema = schema.into();
+
+        assert!(
+            is_valid_identifier(&schema),
+            "invalid schema name {schema:?}: expected [A-Za-z_][A-Za-z0-9_]*"
+        );
+
+        self.schema = Some(schema.into());
+        self.table = format!("{}{}", self.schema_prefix(), self.table_name).into();
+        self
+    }
+
+    /// Encodes records with `serializer` instead of the default
+    /// [`MessagePackSerializer`], or `JsonSerializer` with the `json` feature.
+    ///
+    /// Rows already written with another serializer won't decode afterwards.
+    pub fn with_serializer(mut self, serializer: impl SessionSerializer) -> Self {
+        self.serializer = Arc::new(serializer);
+        self
+    }
+
+    /// Compresses serialized records with zstd before writing them.
+    ///
+    /// Uncompressed rows written earlier keep loading, and turning compression
+    /// off again later still reads the compressed ones.
+    #[cfg(feature = "compression")]
+    pub fn with_compression(mut self, level: CompressionLevel) -> Self {
+        self.compression = Some(level);
+        self
+    }
+
+    /// Encrypts session data at rest with XChaCha20-Poly1305 under `key`.
+    ///
+    /// Once set, rows that aren't encrypted with this key fail to load with
+    /// [`SqliteStoreError::Encryption`], reported as a decode error. Losing
+    /// the key makes every stored session unreadable.
+    #[cfg(feature = "encryption")]
+    pub fn with_encryption_key(mut self, key: [u8; 32]) -> Self {
+        self.encryption = Some(Arc::new(encryption::Encryption::new(key)));
+        self
+    }
+
+    /// Chooses how [`create`](SessionStore::create) guards against id
+    /// collisions; defaults to [`CollisionStrategy::OnConflictRetry`], which
+    /// needs a single statement when the id is free.
+    pub fn with_collision_strategy(mut self, strategy: CollisionStrategy) -> Self {
+        self.collision_strategy = strategy;
+        self
+    }
+
+    /// Makes [`migrate`](Self::migrate) finish with a
+    /// [`verify_schema`](Self::verify_schema) check, rolling the migration
+    /// back if the table doesn't match.
+    pub fn with_schema_verification(mut self, enabled: bool) -> Self {
+        self.verify_schema_on_migrate = enabled;
+        self
+    }
+
+    /// Stores ids as their 16 little-endian bytes in a BLOB rather than as
+    /// base64 text, for smaller rows and a smaller primary key index.
+    ///
+    /// Lookups only match ids written in the same form, so pick this before
+    /// the first session is stored and keep it for the database's lifetime.
+    /// Can't be combined with [strict tables](Self::with_strict_tables).
+    pub fn with_binary_ids(mut self, enabled: bool) -> Self {
+        self.binary_ids = enabled;
+        self
+    }
+
+    /// Has [`migrate`](Self::migrate) create the session table as a `STRICT`
+    /// table, so SQLite rejects values that don't match a column's declared
+    /// type, such as a non-integer `expiry_date`.
+    ///
+    /// Needs SQLite 3.37 or newer; `migrate` fails with
+    /// [`SqliteStoreError::UnsupportedSqlite`] on older versions. Only a table
+    /// the migration creates is affected, not one that already exists. The
+    /// `data` column is declared `blob`, so records are always bound as blobs,
+    /// even as JSON, and the `id` column is `text`, so `migrate` fails with
+    /// [`SqliteStoreError::InvalidConfig`] when this is combined with [binary
+    /// ids](Self::with_binary_ids).
+    ///
+    /// ```rust
+    /// use tower_sessions_r2d2_sqlite_store::SqliteStore;
+    ///
+    /// let store = SqliteStore::in_memory()?.with_strict_tables(true);
+    /// store.migrate()?;
+    ///
+    /// let inserted = store.pool().get()?.execute(
+    ///     "insert into tower_sessions (id, data, expiry_date) values ('a', x'00', 'soon')",
+    ///     [],
+    /// );
+    /// assert!(inserted.is_err());
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    pub fn with_strict_tables(mut self, enabled: bool) -> Self {
+        self.strict_tables = enabled;
+        self
+    }
+
+    /// Treats a session whose expiry is exactly now as still live, rather than
+    /// as expired as it is by default.
+    ///
+    /// Applies to every lookup, count and deletion that compares expiries with
+    /// the current time, so a session is always either live or expired.
+    pub fn with_expiry_inclusive(mut self, inclusive: bool) -> Self {
+        self.expiry_inclusive = inclusive;
+        self
+    }
+
+    /// Has [`save`](SessionStore::save) compare the record with the stored
+    /// one first and leave the row alone if its data is the same, only moving
+    /// `expiry_date` if that changed, as [`touch`](Self::touch) does.
+    ///
+    /// This trades a read and a decode on every save for fewer blob rewrites
+    /// and less WAL churn when most requests don't change their session.
+    /// Skipped saves report no changes from
+    /// [`save_returning_changes`](Self::save_returning_changes) and don't bump
+    /// the row's [version](Self::version_of).
+    pub fn with_skip_unchanged_saves(mut self, enabled: bool) -> Self {
+        self.skip_unchanged_saves = enabled;
+        self
+    }
+
+    /// Records when each session was last read in its `last_accessed` column,
+    /// for [`idle_since`](Self::idle_since), on every
+    /// [`load`](SessionStore::load) and [`load_and_touch`](Self::load_and_touch).
+    ///
+    /// This turns reads into writes, so a load only writes the column when
+    /// the stored value is more than a second old, and the column is accurate
+    /// to about that. Read-only stores never write it.
+    pub fn with_track_access(mut self, enabled: bool) -> Self {
+        self.track_access = enabled;
+        self
+    }
+
+    /// Prefixes uncompressed, unencrypted blobs with a one-byte format tag, as
+    /// compressed and encrypted ones already are, so every row says how it
+    /// was written. Serializers that [need the
+    /// tag](SessionSerializer::needs_format_tag) get it either way.
+    ///
+    /// Blobs starting with a reserved tag this version doesn't know fail to
+    /// decode with [`SqliteStoreError::UnknownFormat`], and untagged rows keep
+    /// decoding as plain serializer output whatever this is set to. Tagged
+    /// JSON is no longer valid JSON, so leave this off to query rows with
+    /// `json_extract`; older versions of the crate can't read tagged rows
+    /// either.
+    pub fn with_format_tags(mut self, enabled: bool) -> Self {
+        self.format_tags = enabled;
+        self
+    }
+
+    /// Fills the `user_id` column from each record as it is saved, enabling
+    /// [`delete_by_user`](Self::delete_by_user) and
+    /// [`sessions_for_user`](Self::sessions_for_user).
+    ///
+    /// Rows saved while no extractor is set, or for which it returns `None`,
+    /// belong to no user.
+    pub fn with_user_id_extractor(
+        mut self,
+        extractor: impl Fn(&Record) -> Option<String> + Send + Sync + 'static,
+    ) -> Self {
+        self.user_id_extractor = Some(RecordExtractor(Arc::new(extractor)));
+        self
+    }
+
+    /// Fills the `tenant` column from each record as it is saved, so one
+    /// table can be shared by tenants that are counted and purged separately
+    /// through [`count_for_tenant`](Self::count_for_tenant),
+    /// [`delete_for_tenant`](Self::delete_for_tenant) and
+    /// [`active_ids_for_tenant`](Self::active_ids_for_tenant).
+    ///
+    /// Session ids stay unique across the whole table rather than per tenant,
+    /// since lookups only have the id to go on. Rows saved while no extractor
+    /// is set, or for which it returns `None`, belong to no tenant.
+    pub fn with_tenant_extractor(
+        mut self,
+        extractor: impl Fn(&Record) -> Option<String> + Send + Sync + 'static,
+    ) -> Self {
+        self.tenant_extractor = Some(RecordExtractor(Arc::new(extractor)));
+        self
+    }
+
+    /// Makes [`load`](SessionStore::load) delete the session it was asked for
+    /// when that session has expired, for deployments without a background
+    /// [`delete_expired`](ExpiredDeletion::delete_expired) task.
+    pub fn with_lazy_expiry_deletion(mut self, enabled: bool) -> Self {
+        self.lazy_expiry_deletion = enabled;
+        self
+    }
+
+    /// Decides what [`load`](SessionStore::load) does when a row's data can't
+    /// be decoded, e.g. after a botched serializer change; defaults to
+    /// [`CorruptBlobPolicy::Error`].
+    pub fn with_corrupt_blob_policy(mut self, policy: CorruptBlobPolicy) -> Self {
+        self.corrupt_blob_policy = policy;
+        self
+    }
+
+    /// Reads the current time from `clock` instead of the system clock when
+    /// telling live sessions from expired ones.
+    pub fn with_clock(mut self, clock: impl Clock) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// Fails any async operation that takes longer than `timeout` with
+    /// [`SqliteStoreError::Timeout`], including the time spent waiting for a
+    /// connection.
+    ///
+    /// The blocking work itself can't be cancelled: a timed-out query keeps
+    /// running on tokio's blocking pool until SQLite returns, and a write may
+    /// still commit. Pair this with a `busy_timeout` so such queries do
+    /// return eventually.
+    pub fn with_operation_timeout(mut self, timeout: std::time::Duration) -> Self {
+        self.operation_timeout = Some(timeout);
+        self
+    }
+
+    /// Aborts SQLite statements once `budget` has passed since the operation
+    /// checked out its connection, failing it with
+    /// [`SqliteStoreError::Interrupted`].
+    ///
+    /// Unlike [`with_operation_timeout`](Self::with_operation_timeout) this
+    /// stops the query itself, through a progress handler that SQLite calls
+    /// every thousand or so virtual machine steps, and an interrupted write
+    /// is rolled back. Time spent waiting for a connection doesn't count
+    /// towards the budget. The handler is removed before the connection goes
+    /// back to the pool.
+    pub fn with_query_interrupt(mut self, budget: std::time::Duration) -> Self {
+        self.query_interrupt = Some(budget);
+        self
+    }
+
+    /// Retries `create`, `save`, `delete` and
+    /// [`save_if_unchanged`](Self::save_if_unchanged) with exponential backoff
+    /// when they fail because the database is busy or locked.
+    ///
+    /// Other errors, and failures of any other operation, are returned right
+    /// away.
+    pub fn with_retry(mut self, policy: RetryPolicy) -> Self {
+        self.retry = Some(policy);
+        self
+    }
+
+    /// Reports the outcome and duration of every `create`, `save`, `load`,
+    /// `delete` and `delete_expired` call to `observer`.
+    pub fn with_observer(mut self, observer: impl StoreObserver) -> Self {
+        self.observer = Some(Arc::new(observer));
+        self
+    }
+
+    /// Calls `listener` with a [`SessionEvent`] after each session that
+    /// `create`, `save`, `delete`, `delete_expired` or a lazy expiry deletion
+    /// in `load` changed, once the change is committed.
+    ///
+    /// Covers the [`SessionStore`] and [`ExpiredDeletion`] methods along with
+    /// [`create_unchecked`](Self::create_unchecked),
+    /// [`save_returning_changes`](Self::save_returning_changes),
+    /// [`delete_returning_changes`](Self::delete_returning_changes),
+    /// [`delete_returning`](Self::delete_returning) and
+    /// [`delete_expired_limited`](Self::delete_expired_limited); bulk helpers
+    /// don't report events. The listener runs on tokio's blocking thread pool
+    /// while the connection is still checked out, so it should be quick, e.g.
+    /// handing the event to a channel.
+    pub fn with_change_listener(
+        mut self,
+        listener: Arc<dyn Fn(SessionEvent) + Send + Sync>,
+    ) -> Self {
+        self.change_listener = Some(ChangeListener(listener));
+        self
+    }
+
+    /// Caps how many records [`save_many`](Self::save_many) writes, and how
+    /// many expired sessions
+    /// [`continuously_delete_expired`](ExpiredDeletion::continuously_delete_expired)
+    /// deletes, per transaction; defaults to 500.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `batch_size` is zero.
+    pub fn with_batch_size(mut self, batch_size: usize) -> Self {
+        assert!(batch_size > 0, "batch size must be at least 1");
+
+        self.batch_size = batch_size;
+        self
+    }
+
+    /// The pool sessions are stored through, for running other queries
+    /// against the same database.
+    ///
+    /// ```rust
+    /// use tower_sessions_r2d2_sqlite_store::SqliteStore;
+    ///
+    /// let store = SqliteStore::in_memory()?;
+    ///
+    /// let conn = store.pool().get()?;
+    /// let healthy: i64 = conn.query_row("select 1", [], |row| row.get(0))?;
+    /// assert_eq!(healthy, 1);
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    pub fn pool(&self) -> &r2d2::Pool<M> {
+        &self.pool
+    }
+
+    /// Reports how many of the pool's connections are open and idle, e.g. to
+    /// alert before checkouts start timing out.
+    ///
+    /// ```rust
+    /// use tower_sessions_r2d2_sqlite_store::SqliteStore;
+    ///
+    /// let store = SqliteStore::in_memory()?;
+    /// let idle = store.pool_state().idle_connections;
+    ///
+    /// let conn = store.pool().get()?;
+    /// assert_eq!(store.pool_state().idle_connections, idle - 1);
+    /// assert_eq!(store.pool_state().in_use(), 1);
+    /// # drop(conn);
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    pub fn pool_state(&self) -> PoolState {
+        let state = self.pool.state();
+
+        PoolState {
+            connections: state.connections,
+            idle_connections: state.idle_connections,
         }
     }
 
+    /// The version of the SQLite library rusqlite is linked against, both as
+    /// SQLite's encoded number (e.g. `3040001`) and split into its parts.
+    ///
+    /// This is the library in use at runtime, which for a system SQLite may
+    /// differ from the one the crate was built against.
+    ///
+    /// ```rust
+    /// use tower_sessions_r2d2_sqlite_store::SqliteStore;
+    ///
+    /// let store = SqliteStore::in_memory()?;
+    /// let (number, version) = store.sqlite_version();
+    ///
+    /// assert_eq!(version.major, 3);
+    /// assert_eq!(store.supports_strict(), number >= 3_037_000);
+    /// println!("running on SQLite {version}");
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    pub fn sqlite_version(&self) -> (u32, Version) {
+        let number = r2d2_sqlite::rusqlite::version_number() as u32;
+        #[cfg(test)]
+        let number = self.sqlite_version_override.unwrap_or(number);
+
+        (number, Version::from_number(number))
+    }
+
+    /// Whether the linked SQLite supports `RETURNING` clauses, added in
+    /// 3.35.
+    ///
+    /// Without them, methods such as [`delete_returning`](Self::delete_returning)
+    /// and [`load_and_touch`](Self::load_and_touch) read the row first and
+    /// then write it in one transaction, with the same results.
+    pub fn supports_returning(&self) -> bool {
+        self.sqlite_version().0 >= 3_035_000
+    }
+
+    /// Whether the linked SQLite supports `STRICT` tables, added in 3.37 and
+    /// needed by [`with_strict_tables`](Self::with_strict_tables).
+    pub fn supports_strict(&self) -> bool {
+        self.sqlite_version().0 >= 3_037_000
+    }
+
+    /// Brings the session table up to date with the schema this version of
+    /// the crate expects, creating it if needed.
+    ///
+    /// The schema version of each table is kept in a
+    /// `tower_sessions_schema_versions` table next to it; only the steps past
+    /// it are applied, in one transaction. Tables are versioned separately,
+    /// so several stores with different [table names](Self::with_table_name)
+    /// can share a database and each picks up new steps when it migrates. A
+    /// table created before versions were recorded gets every step, the first
+    /// of which leaves an existing table alone.
+    ///
+    /// The transaction takes the write lock before reading the version, so
+    /// stores starting up at the same time against one database file migrate
+    /// one after the other, waiting on the busy timeout and the
+    /// [retry policy](Self::with_retry): the first applies the steps and the
+    /// rest find nothing left to do.
+    ///
+    /// With the `json` feature, a table whose `data` column is still declared
+    /// `blob` is rebuilt with a `text` one, converting JSON records to TEXT.
+    /// SQLite can't change a column's type in place, so the rebuild drops the
+    /// table and creates it again with the store's own columns and indexes.
+    /// Since anything else would be lost, a table with extra columns, foreign
+    /// keys, triggers or indexes fails with
+    /// [`SqliteStoreError::InvalidConfig`] instead and is left as it is. Rows
+    /// in other tables referencing the session table through foreign keys
+    /// are subject to the drop, so disable `foreign_keys` while migrating
+    /// such a database.
     pub fn migrate(&self) -> session_store::Result<()> {
-        let query = r#"
-            create table if not exists tower_sessions (
-                id text primary key not null,
-                data blob not null,
-                expiry_date integer not null
-            )"#;
+        let mut conn = self.conn()?;
+        self.migrate_with_conn(&mut conn)?;
+        Ok(())
+    }
+
+    fn migrate_with_conn(&self, conn: &mut Connection) -> Result<(), SqliteStoreError> {
+        self.retrying(|| self.migrate_once(conn))
+    }
+
+    fn migrate_once(&self, conn: &mut Connection) -> Result<(), SqliteStoreError> {
+        self.ensure_writable()?;
+        self.ensure_creatable()?;
+
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+
+        let schema = self.schema_prefix();
+
+        tx.execute_batch(&format!(
+            r#"
+            create table if not exists {schema}{VERSIONS_TABLE} (
+                table_name text primary key not null,
+                version integer not null
+            );
+            "#
+        ))?;
 
-        let conn = self.pool.get().map_err(SqliteStoreError::R2d2)?;
+        let recorded: Option<usize> = tx
+            .query_row(
+                &format!("select version from {schema}{VERSIONS_TABLE} where table_name = ?1"),
+                [&*self.table_name],
+                |row| row.get(0),
+            )
+            .optional()?;
+        let table_exists: bool = tx.query_row(
+            &format!(
+                "select exists(select 1 from {schema}sqlite_master where type = 'table' and name = ?1)"
+            ),
+            [&*self.table_name],
+            |row| row.get(0),
+        )?;
+
+        let applied = match recorded {
+            Some(version) if table_exists => version,
+            _ => 0,
+        };
+
+        self.apply_migrations(&tx, applied)?;
+        self.convert_data_column(&tx)?;
+
+        if recorded != Some(MIGRATIONS.len()) {
+            tx.execute(
+                &format!(
+                    r#"
+                    insert into {schema}{VERSIONS_TABLE} (table_name, version) values (?1, ?2)
+                    on conflict(table_name) do update set version = excluded.version
+                    "#
+                ),
+                params![&*self.table_name, MIGRATIONS.len()],
+            )?;
+        }
+
+        if self.verify_schema_on_migrate {
+            self.verify_schema_with_conn(&tx)?;
+        }
 
-        conn.execute(query, [])
-            .map_err(SqliteStoreError::Rusqlite)?;
+        tx.commit()?;
 
         Ok(())
     }
 
-    fn try_create_with_conn(&self, record: &Record) -> session_store::Result<bool> {
-        let query = r#"select exists(select 1 from tower_sessions where id = ?1)"#;
+    /// Runs every migration step after the first `applied` ones.
+    fn apply_migrations(&self, conn: &Connection, applied: usize) -> Result<(), SqlError> {
+        let strict = if self.strict_tables { " strict" } else { "" };
 
-        let conn = self.pool.get().map_err(SqliteStoreError::R2d2)?;
+        for migration in MIGRATIONS.iter().skip(applied) {
+            conn.execute_batch(
+                &migration
+                    .replace("{table}", &self.table)
+                    .replace("{schema}", &self.schema_prefix())
+                    .replace("{name}", &self.table_name)
+                    .replace("{strict}", strict),
+            )?;
+        }
 
-        let res = conn
-            .query_row(query, [record.id.to_string()], |row| row.get(0))
-            .map_err(SqliteStoreError::Rusqlite)?;
+        Ok(())
+    }
 
-        Ok(res)
+    /// The declared type of the `data` column: `text` with the `json` feature,
+    /// so JSON records are stored as TEXT, and `blob` otherwise.
+    ///
+    /// [Strict tables](Self::with_strict_tables) keep a `blob` column, since a
+    /// strict `text` column would reject compressed and encrypted records.
+    fn data_column_type(&self) -> &'static str {
+        if cfg!(feature = "json") && !self.strict_tables {
+            "text"
+        } else {
+            "blob"
+        }
     }
 
-    fn save_with_conn(&self, record: &Record) -> session_store::Result<()> {
-        let query = r#"
-            insert into tower_sessions
-                (id, data, expiry_date)
-                values (?1, ?2, ?3)
-            on conflict(id) do update set
-            data = excluded.data,
-            expiry_date = excluded.expiry_date
-        "#;
-
-        let conn = self.pool.get().map_err(SqliteStoreError::R2d2)?;
-
-        conn.execute(
-            query,
-            params![
-                record.id.to_string(),
-                rmp_serde::to_vec(record).map_err(SqliteStoreError::Encode)?,
-                record.expiry_date.unix_timestamp(),
-            ],
-        )
-        .map_err(SqliteStoreError::Rusqlite)?;
+    /// Rebuilds the session table if its `data` column isn't declared with
+    /// the [expected type](Self::data_column_type), which only happens when
+    /// the `json` feature is enabled for a table created without it.
+    fn convert_data_column(&self, conn: &Connection) -> Result<(), SqliteStoreError> {
+        #[cfg(feature = "json")]
+        if self.data_column_type() == "text" {
+            let declared: String = conn.query_row(
+                &format!(
+                    "select type from {schema}pragma_table_info(?1) where name = 'data'",
+                    schema = self.schema_prefix()
+                ),
+                [&*self.table_name],
+                |row| row.get(0),
+            )?;
+
+            if !declared.eq_ignore_ascii_case("text") {
+                self.ensure_rebuildable(conn)?;
+                conn.execute_batch(
+                    &JSON_DATA_REBUILD
+                        .replace("{table}", &self.table)
+                        .replace("{schema}", &self.schema_prefix())
+                        .replace("{name}", &self.table_name),
+                )?;
+            }
+        }
+        #[cfg(not(feature = "json"))]
+        let _ = conn;
 
         Ok(())
     }
-}
 
-#[async_trait]
-impl SessionStore for SqliteStore {
-    async fn create(&self, record: &mut Record) -> session_store::Result<()> {
-        while self.try_create_with_conn(record)? {
-            record.id = Id::default();
+    /// Checks that [`JSON_DATA_REBUILD`] would keep everything the session
+    /// table has: no columns besides [`EXPECTED_COLUMNS`], no foreign keys,
+    /// and no indexes or triggers besides the store's own.
+    #[cfg(feature = "json")]
+    fn ensure_rebuildable(&self, conn: &Connection) -> Result<(), SqliteStoreError> {
+        let schema = self.schema_prefix();
+        let name = &*self.table_name;
+
+        let extra_columns: bool = conn.query_row(
+            &format!(
+                "select exists(select 1 from {schema}pragma_table_info(?1) where name not in ({}))",
+                EXPECTED_COLUMNS
+                    .iter()
+                    .map(|(column, _)| format!("'{column}'"))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            [name],
+            |row| row.get(0),
+        )?;
+        if extra_columns {
+            return Err(SqliteStoreError::InvalidConfig(
+                "the json feature can't convert a session table with columns of its own",
+            ));
         }
 
-        self.save_with_conn(&record)?;
+        let foreign_keys: bool = conn.query_row(
+            &format!("select exists(select 1 from {schema}pragma_foreign_key_list(?1))"),
+            [name],
+            |row| row.get(0),
+        )?;
+        let extra_objects: bool = conn.query_row(
+            &format!(
+                r#"
+                select exists(
+                    select 1 from {schema}sqlite_master
+                    where tbl_name = ?1 and type in ('index', 'trigger')
+                        and name not like 'sqlite_autoindex_%'
+                        and name not in (
+                            'idx_' || ?1 || '_expiry',
+                            'idx_' || ?1 || '_user_id',
+                            'idx_' || ?1 || '_created_at',
+                            'idx_' || ?1 || '_tenant',
+                            'idx_' || ?1 || '_last_accessed'
+                        )
+                )
+                "#
+            ),
+            [name],
+            |row| row.get(0),
+        )?;
+        if foreign_keys || extra_objects {
+            return Err(SqliteStoreError::InvalidConfig(
+                "the json feature can't convert a session table with foreign keys, triggers or indexes of its own",
+            ));
+        }
 
         Ok(())
     }
 
-    async fn save(&self, record: &Record) -> session_store::Result<()> {
-        self.save_with_conn(record)?;
+    /// Returns the `create table` and `create index` statements describing the
+    /// session table once [`migrate`](Self::migrate) has run, using the
+    /// configured [table name](Self::with_table_name).
+    ///
+    /// The statements are read back from a scratch in-memory database the
+    /// migrations are applied to, so they match what SQLite records in
+    /// `sqlite_master` for a migrated table.
+    ///
+    /// Fails like [`migrate`](Self::migrate) would on a configuration it
+    /// can't create the table for, e.g. [strict
+    /// tables](Self::with_strict_tables) on an SQLite that predates them.
+    ///
+    /// ```rust
+    /// use tower_sessions_r2d2_sqlite_store::SqliteStore;
+    ///
+    /// let ddl = SqliteStore::in_memory()?
+    ///     .with_table_name("my_sessions")
+    ///     .schema_ddl()?;
+    ///
+    /// assert!(ddl.contains("CREATE TABLE my_sessions"));
+    /// assert!(ddl.contains("idx_my_sessions_expiry on my_sessions(expiry_date)"));
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    pub fn schema_ddl(&self) -> Result<String, SqliteStoreError> {
+        self.ensure_creatable()?;
+
+        let conn = Connection::open_in_memory()?;
+        if let Some(schema) = &self.schema {
+            conn.execute_batch(&format!("attach database ':memory:' as {schema}"))?;
+        }
+        self.apply_migrations(&conn, 0)?;
+        self.convert_data_column(&conn)?;
+
+        let mut stmt = conn.prepare(&format!(
+            r#"
+            select sql || ';' from {schema}sqlite_master
+            where tbl_name = ?1 and sql is not null
+            order by type = 'table' desc, name
+            "#,
+            schema = self.schema_prefix()
+        ))?;
+        let statements = stmt
+            .query_map([&*self.table_name], |row| row.get::<_, String>(0))?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(statements.join("\n"))
+    }
+
+    /// Checks that the session table has every column the store queries, with
+    /// the declared types it expects, failing with
+    /// [`SqliteStoreError::SchemaMismatch`] on the first one that doesn't.
+    ///
+    /// Extra columns are fine. Useful when pointing the store at a table it
+    /// didn't create, where a mismatch would otherwise surface as confusing
+    /// query errors later.
+    pub fn verify_schema(&self) -> session_store::Result<()> {
+        let conn = self.conn()?;
+        self.verify_schema_with_conn(&conn)?;
         Ok(())
     }
 
-    async fn load(&self, session_id: &Id) -> session_store::Result<Option<Record>> {
-        let query = r#"
-            select data from tower_sessions
-            where id = ? and expiry_date > ?
-        "#;
+    fn verify_schema_with_conn(&self, conn: &Connection) -> Result<(), SqliteStoreError> {
+        let mut stmt = conn.prepare(&format!(
+            "select name, type from {schema}pragma_table_info(?1)",
+            schema = self.schema_prefix()
+        ))?;
+        let columns = stmt
+            .query_map([&*self.table_name], |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
 
-        let conn = self.pool.get().map_err(SqliteStoreError::R2d2)?;
+        if columns.is_empty() {
+            return Err(SqliteStoreError::SchemaMismatch {
+                expected: format!("a table named {}", self.table),
+                found: "no such table".to_owned(),
+            });
+        }
 
-        let data: Option<Vec<u8>> = conn
-            .query_row(
-                query,
-                params![
-                    session_id.to_string(),
-                    OffsetDateTime::now_utc().unix_timestamp()
-                ],
-                |row| {
-                    let data: Vec<u8> = row.get(0)?;
-                    Ok(data)
-                },
-            )
-            .optional()
-            .map_err(SqliteStoreError::Rusqlite)?;
+        for &(name, ty) in EXPECTED_COLUMNS {
+            let ty = if name == "data" {
+                self.data_column_type()
+            } else {
+                ty
+            };
 
-        match data {
-            Some(data) => {
-                let record: Record =
-                    rmp_serde::from_slice(&data).map_err(SqliteStoreError::Decode)?;
-                Ok(Some(record))
+            match columns.iter().find(|(found, _)| found == name) {
+                Some((_, found_ty)) if found_ty.eq_ignore_ascii_case(ty) => {}
+                Some((_, found_ty)) => {
+                    return Err(SqliteStoreError::SchemaMismatch {
+                        expected: format!("column {name} {ty}"),
+                        found: format!("column {name} {}", found_ty.to_lowercase()),
+                    });
+                }
+                None => {
+                    return Err(SqliteStoreError::SchemaMismatch {
+                        expected: format!("column {name} {ty}"),
+                        found: format!("no column {name}"),
+                    });
+                }
             }
-            None => Ok(None),
         }
+
+        Ok(())
     }
 
-    async fn delete(&self, session_id: &Id) -> session_store::Result<()> {
-        let query = "delete from tower_sessions where id = ?";
-        let conn = self.pool.get().map_err(SqliteStoreError::R2d2)?;
+    /// Checks out a connection and runs `select 1`, so it fails if the pool is
+    /// exhausted or the database can't be queried.
+    ///
+    /// Cheap enough to back a Kubernetes readiness probe:
+    ///
+    /// ```rust,ignore
+    /// async fn ready(State(store): State<SqliteStore>) -> StatusCode {
+    ///     match store.health_check().await {
+    ///         Ok(()) => StatusCode::OK,
+    ///         Err(_) => StatusCode::SERVICE_UNAVAILABLE,
+    ///     }
+    /// }
+    /// ```
+    pub async fn health_check(&self) -> session_store::Result<()> {
+        self.run_blocking(|store| store.health_check_with_conn())
+            .await?;
+
+        Ok(())
+    }
 
-        conn.execute(query, params![session_id.to_string()])
-            .map_err(SqliteStoreError::Rusqlite)?;
+    /// Opens as many connections as the pool holds, so the first requests
+    /// after startup don't pay for connecting and applying pragmas.
+    ///
+    /// Only useful when the pool's `min_idle` is below its `max_size`, since
+    /// r2d2 otherwise opens every connection when the pool is built. The pool
+    /// may still close the connections again once its `idle_timeout` passes.
+    pub async fn warmup(&self) -> session_store::Result<()> {
+        self.warmup_connections(self.pool.max_size()).await
+    }
+
+    /// Like [`warmup`](Self::warmup), but opens up to `count` connections,
+    /// capped at the pool's `max_size`.
+    pub async fn warmup_connections(&self, count: u32) -> session_store::Result<()> {
+        self.run_blocking(move |store| store.warmup_with_conn(count))
+            .await?;
 
         Ok(())
     }
+
+    /// Returns the number of stored sessions, including expired ones that
+    /// haven't been deleted yet.
+    pub async fn count(&self) -> session_store::Result<i64> {
+        let count = self.run_blocking(|store| store.count_with_conn()).await?;
+        Ok(count)
+    }
+
+    /// Returns the number of sessions that haven't expired.
+    pub async fn count_active(&self) -> session_store::Result<i64> {
+        let count = self
+            .run_blocking(|store| store.count_active_with_conn())
+            .await?;
+
+        Ok(count)
+    }
+
+    /// Returns the number of sessions the next
+    /// [`delete_expired`](ExpiredDeletion::delete_expired) would remove, e.g.
+    /// to log or sanity-check a cleanup before running it.
+    pub async fn count_expired(&self) -> session_store::Result<i64> {
+        let count = self
+            .run_blocking(|store| store.count_expired_with_conn())
+            .await?;
+
+        Ok(count)
+    }
+
+    /// Like [`load`](SessionStore::load), but tells an expired session apart
+    /// from one that doesn't exist.
+    pub async fn load_detailed(&self, id: &Id) -> session_store::Result<LoadOutcome> {
+        let id = *id;
+
+        let outcome = self
+            .run_blocking(move |store| store.load_detailed_with_conn(&id))
+            .await?;
+
+        Ok(outcome)
+    }
+
+    /// Returns whether session `id` exists and hasn't expired, without
+    /// fetching or decoding its data.
+    pub async fn exists(&self, id: &Id) -> session_store::Result<bool> {
+        let id = *id;
+
+        let exists = self
+            .run_blocking(move |store| store.exists_with_conn(&id))
+            .await?;
+
+        Ok(exists)
+    }
+
+    /// Returns when session `id` expires, or `None` if it doesn't exist,
+    /// without fetching or decoding its data.
+    ///
+    /// Sessions that have already expired but not yet been deleted still
+    /// report their expiry.
+    pub async fn expiry_of(&self, id: &Id) -> session_store::Result<Option<OffsetDateTime>> {
+        let id = *id;
+
+        let expiry_date = self
+            .run_blocking(move |store| store.expiry_of_with_conn(&id))
+            .await?;
+
+        Ok(expiry_date)
+    }
+
+    /// Waits until session `id` has expired, returning straight away if it
+    /// already has or doesn't exist.
+    ///
+    /// The wait is worked out from the store's [clock](Self::with_clock) and
+    /// slept on tokio's timer, after which the expiry is read again, so a
+    /// session extended meanwhile is waited on until its new expiry, while
+    /// one deleted meanwhile ends the wait once the sleep is over. A manually
+    /// advanced clock is likewise only looked at again after each sleep.
+    pub async fn await_expiry(&self, id: &Id) -> session_store::Result<()> {
+        let id = *id;
+
+        loop {
+            let expiry_date = self
+                .run_blocking(move |store| store.expiry_of_with_conn(&id))
+                .await?;
+
+            let Some(expiry_date) = expiry_date.filter(|at| self.is_live(unix_nanos(*at))) else {
+                return Ok(());
+            };
+
+            // An expiry the clock has only just reached may still count as
+            // live, so the sleep never drops to zero and spins.
+            let remaining = (expiry_date - self.clock.now()).unsigned_abs();
+            tokio::time::sleep(remaining.max(std::time::Duration::from_millis(1))).await;
+        }
+    }
+
+    /// Returns the `data` column of session `id` exactly as stored, without
+    /// decrypting, decompressing or deserializing it, or `None` if the session
+    /// is missing or expired.
+    pub async fn load_raw(&self, id: &Id) -> session_store::Result<Option<Vec<u8>>> {
+        let id = *id;
+
+        let data = self
+            .run_blocking(move |store| store.load_raw_with_conn(&id))
+            .await?;
+
+        Ok(data)
+    }
+
+    /// Stores `bytes` verbatim as the `data` column of session `id`, expiring
+    /// at `expiry_date`, e.g. to copy a blob read with
+    /// [`load_raw`](Self::load_raw) from another store.
+    ///
+    /// Nothing is encoded or checked, so loading the session only works if
+    /// `bytes` is what this store would have written. The user id and tenant
+    /// can't be derived from raw bytes: a new row has neither, and an existing
+    /// row keeps its own.
+    pub async fn save_raw(
+        &self,
+        id: &Id,
+        bytes: &[u8],
+        expiry_date: OffsetDateTime,
+    ) -> session_store::Result<()> {
+        let id = *id;
+        let bytes = bytes.to_vec();
+
+        self.run_blocking(move |store| {
+            store.retrying(|| store.save_raw_with_conn(&id, &bytes, expiry_date))
+        })
+        .await?;
+
+        Ok(())
+    }
+
+    /// Loads every session in `ids` that exists and hasn't expired, in one
+    /// query per chunk of ids rather than one per id.
+    ///
+    /// Ids that are missing or expired are left out of the result, as are rows
+    /// whose data fails to decode or holds the record of another session.
+    /// Results are keyed by the id of the row they were read from.
+    pub async fn load_many(&self, ids: &[Id]) -> session_store::Result<Vec<(Id, Record)>> {
+        let ids = ids.to_vec();
+
+        let records = self
+            .run_blocking(move |store| store.load_many_with_conn(&ids))
+            .await?;
+
+        Ok(records)
+    }
+
+    /// Like [`load_many`](Self::load_many), but keyed by id for direct
+    /// lookups. Ids passed more than once appear once.
+    pub async fn load_map(&self, ids: &[Id]) -> session_store::Result<HashMap<Id, Record>> {
+        let ids = ids.to_vec();
+
+        let records = self
+            .run_blocking(move |store| store.load_many_with_conn(&ids))
+            .await?;
+
+        Ok(records.into_iter().collect())
+    }
+
+    /// Upserts all of `records` over one connection, one transaction per
+    /// [batch](Self::with_batch_size).
+    ///
+    /// Batches commit independently: if one fails, the batches before it stay
+    /// written.
+    pub async fn save_many(&self, records: &[Record]) -> session_store::Result<()> {
+        let records = records.to_vec();
+
+        self.run_blocking(move |store| store.save_many_with_conn(&records))
+            .await?;
+
+        Ok(())
+    }
+
+    /// Upserts all of `records` as fast as SQLite allows, for preseeding load
+    /// tests with large numbers of sessions.
+    ///
+    /// Everything goes into one transaction over a single connection, with
+    /// `synchronous = OFF` and, unless the database is in WAL mode,
+    /// `journal_mode = MEMORY` for its duration; both are restored afterwards.
+    /// This gives up durability: a crash or power loss mid-seed can lose the
+    /// whole batch or, outside WAL mode, corrupt the database. Use it for
+    /// seeding only, never for live traffic.
+    pub async fn bulk_seed(&self, records: &[Record]) -> session_store::Result<()> {
+        let records = records.to_vec();
+
+        self.run_blocking(move |store| store.bulk_seed_with_conn(&records))
+            .await?;
+
+        Ok(())
+    }
+
+    /// Like [`save`](SessionStore::save), but returns the number of rows
+    /// written.
+    ///
+    /// The upsert counts as one change whether it inserted or updated, so
+    /// this is 1 for every successful save, unless it was
+    /// [skipped](Self::with_skip_unchanged_saves).
+    pub async fn save_returning_changes(&self, record: &Record) -> session_store::Result<u64> {
+        let record = record.clone();
+
+        let changes = self
+            .run_blocking(move |store| store.observed_save(&record))
+            .await?;
+
+        Ok(changes)
+    }
+
+    /// Like [`delete`](SessionStore::delete), but returns the number of rows
+    /// removed: 0 if session `id` didn't exist, 1 otherwise.
+    pub async fn delete_returning_changes(&self, id: &Id) -> session_store::Result<u64> {
+        let id = *id;
+
+        let changes = self
+            .run_blocking(move |store| store.observed_delete(&id))
+            .await?;
+
+        Ok(changes)
+    }
+
+    /// Like [`delete`](SessionStore::delete), but returns the expiry the
+    /// removed session had, or `None` if session `id` didn't exist, e.g. for
+    /// audit logs.
+    pub async fn delete_returning(&self, id: &Id) -> session_store::Result<Option<OffsetDateTime>> {
+        let id = *id;
+
+        let expiry_date = self
+            .run_blocking(move |store| {
+                store.observed(
+                    Operation::Delete,
+                    || store.retrying(|| store.delete_returning_with_conn(&id)),
+                    |observer, _, elapsed| observer.on_delete(elapsed),
+                )
+            })
+            .await?;
+
+        Ok(expiry_date)
+    }
+
+    /// Iterates over every stored session, expired or not, e.g. for backups.
+    ///
+    /// Rows are read in id order, [`batch_size`](Self::with_batch_size) at a
+    /// time, and decoded as they are yielded, so memory use stays bounded on
+    /// tables of any size. Each page is a separate query, so sessions written
+    /// or deleted during the walk may or may not show up; this is not a
+    /// consistent snapshot. An error reading a page ends the iteration.
+    ///
+    /// Sessions are yielded under the id of their row; a row holding the
+    /// record of another session yields a decode error instead.
+    ///
+    /// Iterating blocks on the database and must not happen on an async
+    /// worker thread; run it inside `tokio::task::spawn_blocking`.
+    pub fn iter_records(
+        &self,
+    ) -> impl Iterator<Item = session_store::Result<(Id, Record)>> + use<M> {
+        iter::RecordIter::new(self.clone())
+    }
+
+    /// Writes every stored session, expired or not, to `w` as a stream of
+    /// length-prefixed MessagePack `(id, expiry, data)` entries, returning how
+    /// many were written.
+    ///
+    /// The `data` column is copied as stored, so the archive can be loaded
+    /// into any store with the same serializer, compression and encryption
+    /// settings through [`import_from_reader`](Self::import_from_reader),
+    /// regardless of SQLite version or file format. Rows are read in one
+    /// query and then written to `w` on the calling task, so a slow writer
+    /// should be buffered.
+    ///
+    /// ```rust
+    /// use time::{Duration, OffsetDateTime};
+    /// use tower_sessions_core::{
+    ///     SessionStore,
+    ///     session::{Id, Record},
+    /// };
+    /// use tower_sessions_r2d2_sqlite_store::SqliteStore;
+    ///
+    /// # tokio::runtime::Builder::new_current_thread().build()?.block_on(async {
+    /// let source = SqliteStore::in_memory()?;
+    /// source.migrate()?;
+    ///
+    /// let mut record = Record {
+    ///     id: Id::default(),
+    ///     data: Default::default(),
+    ///     expiry_date: OffsetDateTime::now_utc() + Duration::hours(1),
+    /// };
+    /// source.create(&mut record).await?;
+    ///
+    /// let mut archive = Vec::new();
+    /// source.export_to_writer(&mut archive).await?;
+    ///
+    /// let target = SqliteStore::in_memory()?;
+    /// target.migrate()?;
+    /// assert_eq!(target.import_from_reader(&archive[..], false).await?, 1);
+    /// assert!(target.load(&record.id).await?.is_some());
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// # })?;
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    pub async fn export_to_writer(&self, mut w: impl Write) -> session_store::Result<u64> {
+        let entries = self
+            .run_blocking(|store| store.export_entries_with_conn())
+            .await?;
+
+        for entry in &entries {
+            archive::write_entry(&mut w, entry)?;
+        }
+        w.flush().map_err(SqliteStoreError::from)?;
+
+        Ok(entries.len() as u64)
+    }
+
+    /// Loads an archive written by [`export_to_writer`](Self::export_to_writer)
+    /// from `r` in a single transaction, returning how many sessions were
+    /// written.
+    ///
+    /// Sessions whose id is already stored are overwritten if `overwrite` is
+    /// set, and skipped otherwise. The whole archive is read before anything
+    /// is written, so a truncated or malformed one changes nothing.
+    pub async fn import_from_reader(
+        &self,
+        mut r: impl Read,
+        overwrite: bool,
+    ) -> session_store::Result<u64> {
+        let mut entries = Vec::new();
+        while let Some(entry) = archive::read_entry(&mut r)? {
+            entries.push(entry);
+        }
+
+        let imported = self
+            .run_blocking(move |store| store.import_entries_with_conn(&entries, overwrite))
+            .await?;
+
+        Ok(imported)
+    }
+
+    /// Overwrites the stored session only if it is still at `expected_version`,
+    /// returning whether it was written.
+    ///
+    /// Every save bumps a session's version, so reading it with
+    /// [`version_of`](Self::version_of) before a read-modify-write and passing
+    /// it here rejects the write if another one got in first. Sessions that
+    /// don't exist are never written.
+    ///
+    /// Like [`save`](SessionStore::save), it follows the [retry
+    /// policy](Self::with_retry) and reports a write to [change
+    /// listeners](Self::with_change_listener).
+    pub async fn save_if_unchanged(
+        &self,
+        record: &Record,
+        expected_version: u64,
+    ) -> session_store::Result<bool> {
+        let record = record.clone();
+
+        let saved = self
+            .run_blocking(move |store| {
+                store.retrying(|| store.save_if_unchanged_with_conn(&record, expected_version))
+            })
+            .await?;
+
+        Ok(saved)
+    }
+
+    /// Loads session `id`, lets `f` modify it and saves it back, holding a
+    /// lock on the id throughout so concurrent calls for the same session
+    /// take turns instead of overwriting each other's changes.
+    ///
+    /// Returns what `f` returned, or `None` without calling it if the session
+    /// doesn't exist or has expired. The lock lives in this store and its
+    /// clones, so it only serializes calls through this method in this
+    /// process; plain [`save`](SessionStore::save)s and other processes can
+    /// still interleave, which [`save_if_unchanged`](Self::save_if_unchanged)
+    /// guards against instead.
+    pub async fn with_session_lock<R>(
+        &self,
+        id: &Id,
+        f: impl FnOnce(&mut Record) -> R,
+    ) -> session_store::Result<Option<R>> {
+        let id = *id;
+        let lock = self.session_locks.entry(id).or_default().clone();
+
+        let res = {
+            let _guard = lock.lock().await;
+            self.load_modify_save(id, f).await
+        };
+
+        // Drop the entry once nobody else holds or waits on the lock.
+        drop(lock);
+        self.session_locks
+            .remove_if(&id, |_, lock| Arc::strong_count(lock) == 1);
+
+        res
+    }
+
+    /// The body of `with_session_lock`, run while the lock is held.
+    async fn load_modify_save<R>(
+        &self,
+        id: Id,
+        f: impl FnOnce(&mut Record) -> R,
+    ) -> session_store::Result<Option<R>> {
+        let Some(mut record) = self
+            .run_blocking(move |store| store.load_with_conn(&id))
+            .await?
+        else {
+            return Ok(None);
+        };
+
+        let out = f(&mut record);
+
+        self.run_blocking(move |store| store.observed_save(&record))
+            .await?;
+
+        Ok(Some(out))
+    }
+
+    /// Returns the current version of session `id`, or `None` if it doesn't
+    /// exist; see [`save_if_unchanged`](Self::save_if_unchanged).
+    pub async fn version_of(&self, id: &Id) -> session_store::Result<Option<u64>> {
+        let id = *id;
+
+        let version = self
+            .run_blocking(move |store| store.version_of_with_conn(&id))
+            .await?;
+
+        Ok(version)
+    }
+
+    /// Copies every session from `source_table`, a table written by
+    /// `tower-sessions-sqlx-store`'s SQLite store in the same database, into
+    /// this store's table, returning how many were copied.
+    ///
+    /// The source rows hold MessagePack records and an expiry given either as
+    /// Unix seconds or as a date-time string, which is converted to this
+    /// store's representation. Sessions already stored under the same id are
+    /// overwritten. Everything happens in one transaction, so a row that
+    /// fails to decode leaves this store's table untouched; the source table
+    /// is never modified.
+    ///
+    /// Like [table names](Self::with_table_name), `source_table` has to be a
+    /// plain identifier; anything else fails with
+    /// [`SqliteStoreError::InvalidConfig`].
+    pub async fn migrate_from_sqlx_store(&self, source_table: &str) -> session_store::Result<u64> {
+        if !is_valid_identifier(source_table) {
+            return Err(SqliteStoreError::InvalidConfig(
+                "source table names have to match [A-Za-z_][A-Za-z0-9_]*",
+            )
+            .into());
+        }
+
+        let source_table = source_table.to_owned();
+
+        let migrated = self
+            .run_blocking(move |store| store.migrate_from_sqlx_store_with_conn(&source_table))
+            .await?;
+
+        Ok(migrated)
+    }
+
+    /// Upserts all of `records` in a single transaction: either every record
+    /// is written or, if any of them fails to encode or write, none are.
+    ///
+    /// The first error is returned. The write lock is held for the whole
+    /// batch, so very large imports may stall other writers; see
+    /// [`save_many`](Self::save_many) for a batched alternative.
+    pub async fn save_all_atomic(&self, records: &[Record]) -> session_store::Result<()> {
+        let records = records.to_vec();
+
+        self.run_blocking(move |store| store.save_all_atomic_with_conn(&records))
+            .await?;
+
+        Ok(())
+    }
+
+    /// Moves the expiry of session `id` to `expiry_date` without rewriting its
+    /// data, returning whether the session exists.
+    pub async fn touch(&self, id: &Id, expiry_date: OffsetDateTime) -> session_store::Result<bool> {
+        let id = *id;
+
+        let touched = self
+            .run_blocking(move |store| store.touch_with_conn(&id, expiry_date))
+            .await?;
+
+        Ok(touched)
+    }
+
+    /// Moves session `old` to the id `new`, keeping its data and expiry,
+    /// e.g. to rotate the id after a login. Returns whether `old` existed.
+    ///
+    /// The move happens in one transaction and fails with
+    /// [`SqliteStoreError::DuplicateId`] if a session, expired or not, already
+    /// uses `new`.
+    pub async fn rotate_id(&self, old: &Id, new: Id) -> session_store::Result<bool> {
+        let old = *old;
+
+        let rotated = self
+            .run_blocking(move |store| store.rotate_id_with_conn(&old, new))
+            .await?;
+
+        Ok(rotated)
+    }
+
+    /// Picks a fresh id that no stored session, expired or not, uses yet,
+    /// without inserting anything, e.g. to embed in a form before the session
+    /// has any data.
+    ///
+    /// This is best effort: the id isn't held, so another writer may take it
+    /// before it is stored. [`create`](SessionStore::create) still checks it
+    /// and picks another on a collision, while [`save`](SessionStore::save)
+    /// overwrites whatever is there.
+    pub async fn reserve_id(&self) -> session_store::Result<Id> {
+        let id = self
+            .run_blocking(|store| store.reserve_id_with_conn())
+            .await?;
+
+        Ok(id)
+    }
+
+    /// Moves every stored session to a fresh, unused id, keeping its data and
+    /// expiry, and returns how many were moved; e.g. to invalidate every
+    /// session id that may have leaked after a security incident.
+    ///
+    /// The old ids stop working straight away, and nothing hands the new ones
+    /// to clients: a client still presenting its old cookie no longer finds
+    /// its session and starts a new one, so its user has to log in again. The
+    /// data stays available under the new ids, e.g. through
+    /// [`sessions_for_user`](Self::sessions_for_user).
+    /// Everything happens in one transaction holding the write lock, and a
+    /// row that fails to decode aborts the rotation with nothing changed.
+    pub async fn rotate_all_ids(&self) -> session_store::Result<u64> {
+        let rotated = self
+            .run_blocking(|store| store.rotate_all_ids_with_conn())
+            .await?;
+
+        Ok(rotated)
+    }
+
+    /// Inserts `record` under its own id without checking for a collision
+    /// first, for callers that generate ids they know to be unique.
+    ///
+    /// Unlike [`create`](SessionStore::create), a taken id isn't replaced:
+    /// the insert fails with [`SqliteStoreError::DuplicateId`].
+    pub async fn create_unchecked(&self, record: &Record) -> session_store::Result<()> {
+        let record = record.clone();
+
+        self.run_blocking(move |store| {
+            store.observed(
+                Operation::Create,
+                || store.retrying(|| store.create_unchecked_with_conn(&record)),
+                |observer, _, elapsed| observer.on_create(elapsed),
+            )
+        })
+        .await?;
+
+        Ok(())
+    }
+
+    /// Returns the ids of all sessions that haven't expired.
+    ///
+    /// Fails with [`SqliteStoreError::InvalidId`] if a row's id doesn't parse
+    /// back into an [`Id`].
+    pub async fn active_ids(&self) -> session_store::Result<Vec<Id>> {
+        let ids = self
+            .run_blocking(|store| store.active_ids_with_conn())
+            .await?;
+
+        Ok(ids)
+    }
+
+    /// Returns the ids of sessions that are still live but expire within
+    /// `window` from now, e.g. to warn their users ahead of time.
+    ///
+    /// Fails with [`SqliteStoreError::InvalidId`] if a row's id doesn't parse
+    /// back into an [`Id`].
+    pub async fn expiring_within(&self, window: time::Duration) -> session_store::Result<Vec<Id>> {
+        let ids = self
+            .run_blocking(move |store| store.expiring_within_with_conn(window))
+            .await?;
+
+        Ok(ids)
+    }
+
+    /// Returns the ids of live sessions that haven't been loaded since
+    /// `cutoff`, e.g. to log out idle users ahead of their expiry.
+    ///
+    /// Needs [access tracking](Self::with_track_access); sessions that were
+    /// never loaded with it on count as last accessed when they were created.
+    /// Fails with [`SqliteStoreError::InvalidId`] if a row's id doesn't parse
+    /// back into an [`Id`].
+    pub async fn idle_since(&self, cutoff: OffsetDateTime) -> session_store::Result<Vec<Id>> {
+        let ids = self
+            .run_blocking(move |store| store.idle_since_with_conn(cutoff))
+            .await?;
+
+        Ok(ids)
+    }
+
+    /// Counts live sessions by when they expire, in buckets `bucket` wide
+    /// starting now and reaching up to `horizon` from now, e.g. to predict
+    /// cleanup load.
+    ///
+    /// Each entry is a bucket's start time and how many sessions expire
+    /// within it, in time order. Empty buckets are left out, and sessions
+    /// expiring after the horizon aren't counted.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `bucket` isn't positive.
+    pub async fn expiry_histogram(
+        &self,
+        bucket: time::Duration,
+        horizon: time::Duration,
+    ) -> session_store::Result<Vec<(OffsetDateTime, i64)>> {
+        assert!(bucket.is_positive(), "bucket width must be positive");
+
+        let histogram = self
+            .run_blocking(move |store| store.expiry_histogram_with_conn(bucket, horizon))
+            .await?;
+
+        Ok(histogram)
+    }
+
+    /// Moves the expiry of every session in `ids` to `expiry_date`, returning
+    /// how many existed.
+    ///
+    /// Like [`touch`](Self::touch), this only changes the `expiry_date`
+    /// column.
+    pub async fn touch_many(
+        &self,
+        ids: &[Id],
+        expiry_date: OffsetDateTime,
+    ) -> session_store::Result<u64> {
+        let ids = ids.to_vec();
+
+        let touched = self
+            .run_blocking(move |store| store.touch_many_with_conn(&ids, expiry_date))
+            .await?;
+
+        Ok(touched)
+    }
+
+    /// Loads session `id` and moves its expiry to `new_expiry` in a single
+    /// statement, so no other write can land between the two.
+    ///
+    /// Returns `None` without touching anything if the session doesn't exist
+    /// or has expired. The returned record carries `new_expiry`, although, as
+    /// with [`touch`](Self::touch), the stored data keeps the old one.
+    pub async fn load_and_touch(
+        &self,
+        id: &Id,
+        new_expiry: OffsetDateTime,
+    ) -> session_store::Result<Option<Record>> {
+        let id = *id;
+
+        let record = self
+            .run_blocking(move |store| store.load_and_touch_with_conn(&id, new_expiry))
+            .await?;
+
+        Ok(record)
+    }
+
+    /// Deletes every session in `ids`, returning how many existed.
+    pub async fn delete_many(&self, ids: &[Id]) -> session_store::Result<u64> {
+        let ids = ids.to_vec();
+
+        let deleted = self
+            .run_blocking(move |store| store.delete_many_with_conn(&ids))
+            .await?;
+
+        Ok(deleted)
+    }
+
+    /// Deletes every session belonging to `user_id`, e.g. after a password
+    /// change, returning how many were removed.
+    ///
+    /// Requires a [user id extractor](Self::with_user_id_extractor).
+    pub async fn delete_by_user(&self, user_id: &str) -> session_store::Result<u64> {
+        let user_id = user_id.to_owned();
+
+        let deleted = self
+            .run_blocking(move |store| store.delete_by_user_with_conn(&user_id))
+            .await?;
+
+        Ok(deleted)
+    }
+
+    /// Returns the ids of the live sessions belonging to `user_id`.
+    ///
+    /// Requires a [user id extractor](Self::with_user_id_extractor).
+    pub async fn sessions_for_user(&self, user_id: &str) -> session_store::Result<Vec<Id>> {
+        let user_id = user_id.to_owned();
+
+        let ids = self
+            .run_blocking(move |store| store.sessions_for_user_with_conn(&user_id))
+            .await?;
+
+        Ok(ids)
+    }
+
+    /// Returns the number of live sessions belonging to `tenant`.
+    ///
+    /// Requires a [tenant extractor](Self::with_tenant_extractor).
+    pub async fn count_for_tenant(&self, tenant: &str) -> session_store::Result<i64> {
+        let tenant = tenant.to_owned();
+
+        let count = self
+            .run_blocking(move |store| store.count_for_tenant_with_conn(&tenant))
+            .await?;
+
+        Ok(count)
+    }
+
+    /// Deletes every session, expired or not, belonging to `tenant`, returning
+    /// how many were removed.
+    ///
+    /// Requires a [tenant extractor](Self::with_tenant_extractor).
+    pub async fn delete_for_tenant(&self, tenant: &str) -> session_store::Result<u64> {
+        let tenant = tenant.to_owned();
+
+        let deleted = self
+            .run_blocking(move |store| store.delete_for_tenant_with_conn(&tenant))
+            .await?;
+
+        Ok(deleted)
+    }
+
+    /// Returns the ids of the live sessions belonging to `tenant`.
+    ///
+    /// Requires a [tenant extractor](Self::with_tenant_extractor).
+    pub async fn active_ids_for_tenant(&self, tenant: &str) -> session_store::Result<Vec<Id>> {
+        let tenant = tenant.to_owned();
+
+        let ids = self
+            .run_blocking(move |store| store.active_ids_for_tenant_with_conn(&tenant))
+            .await?;
+
+        Ok(ids)
+    }
+
+    /// Deletes every session, expired or not, logging everyone out.
+    pub async fn clear(&self) -> session_store::Result<()> {
+        self.run_blocking(|store| store.clear_with_conn()).await?;
+        Ok(())
+    }
+
+    /// Deletes only the expired sessions; an alias for
+    /// [`delete_expired`](ExpiredDeletion::delete_expired).
+    pub async fn clear_expired_only(&self) -> session_store::Result<()> {
+        self.run_blocking(|store| store.observed_delete_expired())
+            .await?;
+
+        Ok(())
+    }
+
+    /// Deletes at most `batch` expired sessions, returning how many were
+    /// removed.
+    ///
+    /// Unlike [`delete_expired`](ExpiredDeletion::delete_expired), this keeps
+    /// each write transaction short on a large backlog; call it until it
+    /// returns less than `batch` to clear everything.
+    pub async fn delete_expired_limited(&self, batch: usize) -> session_store::Result<u64> {
+        let deleted = self
+            .run_blocking(move |store| store.delete_expired_limited_with_conn(batch))
+            .await?;
+
+        Ok(deleted)
+    }
+
+    /// Deletes expired sessions `batch` at a time until none are left, calling
+    /// `on_batch` with the running total after each batch, and returns the
+    /// total, e.g. to show progress from a cleanup CLI.
+    ///
+    /// Each batch is its own short transaction, as with
+    /// [`delete_expired_limited`](Self::delete_expired_limited); sessions
+    /// expiring while this runs are picked up by later batches.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `batch` is zero.
+    pub async fn delete_expired_reporting(
+        &self,
+        batch: usize,
+        mut on_batch: impl FnMut(u64),
+    ) -> session_store::Result<u64> {
+        assert!(batch > 0, "batch size must be at least 1");
+
+        let mut total = 0;
+
+        loop {
+            let deleted = self
+                .run_blocking(move |store| store.delete_expired_limited_with_conn(batch))
+                .await?;
+
+            if deleted > 0 {
+                total += deleted;
+                on_batch(total);
+            }
+
+            if deleted < batch as u64 {
+                return Ok(total);
+            }
+        }
+    }
+
+    /// Rewrites every stored session with the current serializer,
+    /// [compression](Self::with_compression) and other blob settings,
+    /// returning how many rows changed, e.g. after turning compression on so
+    /// older rows stop taking the legacy path.
+    ///
+    /// Rows are decoded as [`load`](SessionStore::load) would, so any format
+    /// the store can read is accepted, and are rewritten one
+    /// [batch](Self::with_batch_size) per transaction. Batches commit
+    /// independently: if one fails, the rows before it stay rewritten, and
+    /// since nothing about the sessions changes, running it again is safe.
+    /// Versions aren't bumped.
+    pub async fn reencode_all(&self) -> session_store::Result<u64> {
+        let reencoded = self
+            .run_blocking(|store| store.reencode_all_with_conn())
+            .await?;
+
+        Ok(reencoded)
+    }
+
+    /// Deletes live sessions whose data is identical to another live
+    /// session's, keeping one per group as chosen by `keep`, and returns how
+    /// many were removed, e.g. to clean up after a client that kept starting
+    /// new sessions.
+    ///
+    /// Sessions are compared by their decoded data, since the stored blobs
+    /// also hold each session's own id and expiry. Every live row is read and
+    /// decoded inside one write transaction, so this is best run off-peak;
+    /// expired sessions are left to
+    /// [`delete_expired`](ExpiredDeletion::delete_expired).
+    pub async fn deduplicate(&self, keep: DedupKeep) -> session_store::Result<u64> {
+        let removed = self
+            .run_blocking(move |store| store.deduplicate_with_conn(keep))
+            .await?;
+
+        Ok(removed)
+    }
+
+    /// Deletes the sessions closest to expiry until at most `max_sessions`
+    /// remain, returning how many were evicted, e.g. to make room for new
+    /// logins on a capacity-limited deployment.
+    ///
+    /// Expired sessions that haven't been deleted yet count towards the cap
+    /// and go first. The count and the deletion happen in one transaction.
+    pub async fn evict_to_cap(&self, max_sessions: usize) -> session_store::Result<u64> {
+        let evicted = self
+            .run_blocking(move |store| store.evict_to_cap_with_conn(max_sessions))
+            .await?;
+
+        Ok(evicted)
+    }
+
+    /// Deletes every session first stored more than `age` ago, however far
+    /// its expiry has since been extended, returning how many were removed.
+    ///
+    /// This caps the total lifetime of sessions whose expiry slides forward on
+    /// every request. Sessions stored before the `created_at` column existed
+    /// count as created when [`migrate`](Self::migrate) added it.
+    pub async fn delete_older_than(&self, age: time::Duration) -> session_store::Result<u64> {
+        let deleted = self
+            .run_blocking(move |store| store.delete_older_than_with_conn(age))
+            .await?;
+
+        Ok(deleted)
+    }
+
+    /// Rebuilds the database file with `VACUUM`, returning the space freed by
+    /// deleted sessions to the filesystem.
+    ///
+    /// Vacuuming holds an exclusive lock for its whole run and rewrites the
+    /// entire file, which can take a while on large databases, so it's best
+    /// left to maintenance windows.
+    pub async fn vacuum(&self) -> session_store::Result<()> {
+        self.run_blocking(|store| store.vacuum_with_conn()).await?;
+        Ok(())
+    }
+
+    /// Frees up to `pages` unused pages with `pragma incremental_vacuum`, or
+    /// all of them if `pages` is 0.
+    ///
+    /// Only has an effect on databases created with (or vacuumed after
+    /// setting) `auto_vacuum = INCREMENTAL`.
+    pub async fn incremental_vacuum(&self, pages: u32) -> session_store::Result<()> {
+        self.run_blocking(move |store| store.incremental_vacuum_with_conn(pages))
+            .await?;
+
+        Ok(())
+    }
+
+    /// Checkpoints the write-ahead log into the database file and truncates
+    /// it, then drops this handle to the pool, for a clean shutdown.
+    ///
+    /// `Drop` can't run async work, so this has to be called explicitly. The
+    /// pool and its connections only close once every clone of the store is
+    /// gone. Read-only stores and databases not in WAL mode skip the
+    /// checkpoint. If another connection is still reading or writing, the
+    /// checkpoint can't complete and a `SQLITE_BUSY` error is returned.
+    pub async fn close(self) -> session_store::Result<()> {
+        self.run_blocking(|store| store.checkpoint_with_conn())
+            .await?;
+
+        Ok(())
+    }
+
+    /// Runs `f` on tokio's blocking thread pool.
+    ///
+    /// Checking a connection out of r2d2 and running a rusqlite statement both
+    /// block the calling thread, so none of that work may happen on an async
+    /// worker. The store is cloned into the task, which only bumps reference
+    /// counts.
+    async fn run_blocking<F, T>(&self, f: F) -> Result<T, SqliteStoreError>
+    where
+        F: FnOnce(&Self) -> Result<T, SqliteStoreError> + Send + 'static,
+        T: Send + 'static,
+    {
+        let store = self.clone();
+        let task = tokio::task::spawn_blocking(move || f(&store));
+
+        let res = match self.operation_timeout {
+            Some(timeout) => tokio::time::timeout(timeout, task)
+                .await
+                .map_err(|_| SqliteStoreError::Timeout(timeout))??,
+            None => task.await?,
+        };
+
+        match (res, self.query_interrupt) {
+            (Err(SqliteStoreError::Rusqlite(err)), Some(budget))
+                if err.sqlite_error_code() == Some(ErrorCode::OperationInterrupted) =>
+            {
+                Err(SqliteStoreError::Interrupted(budget))
+            }
+            (res, _) => res,
+        }
+    }
+
+    /// Checks a connection out of the pool.
+    ///
+    /// `Pool::get` only fails once `connection_timeout` has passed without a
+    /// connection to hand out, so its errors are reported as
+    /// [`SqliteStoreError::PoolTimeout`] rather than as query failures.
+    fn conn(&self) -> Result<interrupt::StoreConn<M>, SqliteStoreError> {
+        let conn = self.pool.get().map_err(SqliteStoreError::PoolTimeout)?;
+        Ok(interrupt::StoreConn::new(conn, self.query_interrupt))
+    }
+
+    /// The `schema.` qualifying names in the attached database the table
+    /// lives in, or nothing for `main`.
+    fn schema_prefix(&self) -> String {
+        self.schema
+            .as_ref()
+            .map_or_else(String::new, |schema| format!("{schema}."))
+    }
+
+    /// The current time according to the store's [`Clock`], in the unit of
+    /// the `expiry_date` column.
+    fn now_nanos(&self) -> i64 {
+        unix_nanos(self.clock.now())
+    }
+
+    /// The operator by which a live session's `expiry_date` compares to now,
+    /// per [`with_expiry_inclusive`](Self::with_expiry_inclusive).
+    fn live_op(&self) -> &'static str {
+        if self.expiry_inclusive { ">=" } else { ">" }
+    }
+
+    /// The operator by which an expired session's `expiry_date` compares to
+    /// now; the complement of [`live_op`](Self::live_op).
+    fn expired_op(&self) -> &'static str {
+        if self.expiry_inclusive { "<" } else { "<=" }
+    }
+
+    /// Whether a session expiring at `expiry_date` nanoseconds is live now.
+    fn is_live(&self, expiry_date: i64) -> bool {
+        if self.expiry_inclusive {
+            expiry_date >= self.now_nanos()
+        } else {
+            expiry_date > self.now_nanos()
+        }
+    }
+
+    /// How `id` is bound to the `id` column.
+    fn id_value(&self, id: &Id) -> Value {
+        if self.binary_ids {
+            Value::Blob(id.0.to_le_bytes().to_vec())
+        } else {
+            Value::Text(id.to_string())
+        }
+    }
+
+    fn ensure_writable(&self) -> Result<(), SqliteStoreError> {
+        if self.read_only {
+            return Err(SqliteStoreError::ReadOnly);
+        }
+
+        Ok(())
+    }
+
+    /// Fails if the session table can't be created as configured: with
+    /// [strict tables](Self::with_strict_tables) on an SQLite that predates
+    /// them, or combined with [binary ids](Self::with_binary_ids).
+    fn ensure_creatable(&self) -> Result<(), SqliteStoreError> {
+        self.ensure_strict_supported()?;
+        if self.strict_tables && self.binary_ids {
+            return Err(SqliteStoreError::InvalidConfig(
+                "strict tables can't hold binary ids in their text id column",
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Fails if [strict tables](Self::with_strict_tables) are on but the
+    /// linked SQLite predates them.
+    fn ensure_strict_supported(&self) -> Result<(), SqliteStoreError> {
+        if self.strict_tables && !self.supports_strict() {
+            return Err(SqliteStoreError::UnsupportedSqlite {
+                feature: "STRICT tables",
+                required: "3.37",
+                found: r2d2_sqlite::rusqlite::version(),
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Runs a write under the configured [`RetryPolicy`], if any.
+    fn retrying<T>(
+        &self,
+        mut op: impl FnMut() -> Result<T, SqliteStoreError>,
+    ) -> Result<T, SqliteStoreError> {
+        match self.retry {
+            Some(policy) => policy.run(op),
+            None => op(),
+        }
+    }
+
+    /// Runs `op`, then passes its duration to `report` on success or its error
+    /// to [`StoreObserver::on_error`], if an observer is attached.
+    fn observed<T>(
+        &self,
+        operation: Operation,
+        op: impl FnOnce() -> Result<T, SqliteStoreError>,
+        report: impl FnOnce(&dyn StoreObserver, &T, std::time::Duration),
+    ) -> Result<T, SqliteStoreError> {
+        let Some(observer) = &self.observer else {
+            return op();
+        };
+
+        let start = Instant::now();
+        let res = op();
+
+        match &res {
+            Ok(value) => report(&**observer, value, start.elapsed()),
+            Err(err) => observer.on_error(operation, err),
+        }
+
+        res
+    }
+
+    fn encode_record(&self, record: &Record) -> Result<Vec<u8>, SqliteStoreError> {
+        let always_tag = self.serializer.needs_format_tag();
+        self.seal(self.serializer.encode(record)?, always_tag)
+    }
+
+    /// Compresses, tags and encrypts serialized bytes as configured, giving
+    /// the blob stored in the `data` column. Uncompressed bytes are tagged if
+    /// [format tags](Self::with_format_tags) are on or `always_tag` is set.
+    fn seal(&self, mut blob: Vec<u8>, always_tag: bool) -> Result<Vec<u8>, SqliteStoreError> {
+        #[allow(unused_mut)]
+        let mut tagged = false;
+
+        #[cfg(feature = "compression")]
+        if let Some(level) = self.compression {
+            blob = compression::compress(&blob, level)?;
+            tagged = true;
+        }
+
+        if (self.format_tags || always_tag) && !tagged {
+            blob.insert(0, PLAIN_MARKER);
+        }
+
+        #[cfg(feature = "encryption")]
+        if let Some(encryption) = &self.encryption {
+            blob = encryption.encrypt(&blob)?;
+        }
+
+        Ok(blob)
+    }
+
+    fn decode_record(&self, blob: &[u8]) -> Result<Record, SqliteStoreError> {
+        self.serializer.decode(&self.unseal(blob)?)
+    }
+
+    /// Decodes a record read along with its row's `expiry_date`, which wins
+    /// over the one serialized in the blob: expiry-only writes such as
+    /// [`touch`](Self::touch) leave the blob as it was.
+    fn decode_stored(&self, blob: &[u8], expiry_date: i64) -> Result<Record, SqliteStoreError> {
+        let mut record = self.decode_record(blob)?;
+        record.expiry_date = from_unix_nanos(expiry_date);
+        Ok(record)
+    }
+
+    /// Like [`decode_stored`](Self::decode_stored), for a row read along with
+    /// its `id`, which has to match the one in the record.
+    fn decode_row(
+        &self,
+        id: ValueRef<'_>,
+        blob: &[u8],
+        expiry_date: i64,
+    ) -> Result<(Id, Record), SqliteStoreError> {
+        let id = parse_id(id)?;
+        let record = self.decode_stored(blob, expiry_date)?;
+
+        if record.id != id {
+            return Err(SqliteStoreError::IdMismatch {
+                row: id,
+                record: record.id,
+            });
+        }
+
+        Ok((id, record))
+    }
+
+    /// Undoes [`seal`](Self::seal), giving back the serialized bytes.
+    fn unseal<'a>(&self, blob: &'a [u8]) -> Result<Cow<'a, [u8]>, SqliteStoreError> {
+        // With a key configured every row has to be sealed with it, otherwise
+        // anyone able to write to the database could plant sessions.
+        #[cfg(feature = "encryption")]
+        if let Some(encryption) = &self.encryption {
+            return match blob.split_first() {
+                Some((&ENCRYPTED_MARKER, sealed)) => Ok(Cow::Owned(
+                    self.untag(&encryption.decrypt(sealed)?)?.into_owned(),
+                )),
+                _ => Err(SqliteStoreError::Encryption("blob is not encrypted".into())),
+            };
+        }
+
+        self.untag(blob)
+    }
+
+    /// Strips the format tag from an unencrypted blob, decompressing it if
+    /// need be.
+    fn untag<'a>(&self, blob: &'a [u8]) -> Result<Cow<'a, [u8]>, SqliteStoreError> {
+        match blob.split_first() {
+            Some((&ENCRYPTED_MARKER, _)) => Err(SqliteStoreError::Encryption(
+                "blob is encrypted but no encryption key is configured".into(),
+            )),
+            #[cfg(feature = "compression")]
+            Some((&COMPRESSED_MARKER, compressed)) => {
+                Ok(Cow::Owned(compression::decompress(compressed)?))
+            }
+            #[cfg(not(feature = "compression"))]
+            Some((&COMPRESSED_MARKER, _)) => {
+                Err(SqliteStoreError::Compression(std::io::Error::other(
+                    "blob is compressed but the `compression` feature is disabled",
+                )))
+            }
+            Some((&PLAIN_MARKER, plain)) => Ok(Cow::Borrowed(plain)),
+            Some((&tag, _)) if tag <= LAST_FORMAT_MARKER => {
+                Err(SqliteStoreError::UnknownFormat(tag))
+            }
+            // Rows written without format tags.
+            _ => Ok(Cow::Borrowed(blob)),
+        }
+    }
+
+    /// How an encoded record is bound to the `data` column.
+    ///
+    /// With the `json` feature, blobs that are valid UTF-8 are stored as TEXT so
+    /// `json_extract` and other string functions work on them, and
+    /// [`migrate`](Self::migrate) declares the column `text` to match; reads
+    /// accept either storage class. [Strict tables](Self::with_strict_tables)
+    /// keep a `blob` column, which only accepts blobs, so they always get one.
+    fn data_value(&self, blob: Vec<u8>) -> Value {
+        #[cfg(feature = "json")]
+        let blob = match String::from_utf8(blob) {
+            Ok(text) if !self.strict_tables => return Value::Text(text),
+            Ok(text) => text.into_bytes(),
+            Err(err) => err.into_bytes(),
+        };
+
+        Value::Blob(blob)
+    }
+
+    /// The `id, data, expiry_date, user_id, created_at, tenant` values
+    /// written for `record`.
+    fn row_values(&self, record: &Record) -> Result<[Value; 6], SqliteStoreError> {
+        let user_id = self.user_id_extractor.as_ref().and_then(|f| f.0(record));
+        let tenant = self.tenant_extractor.as_ref().and_then(|f| f.0(record));
+
+        Ok([
+            self.id_value(&record.id),
+            self.data_value(self.encode_record(record)?),
+            Value::Integer(unix_nanos(record.expiry_date)),
+            user_id.map_or(Value::Null, Value::Text),
+            Value::Integer(self.now_nanos()),
+            tenant.map_or(Value::Null, Value::Text),
+        ])
+    }
+
+    fn create_with_conn(&self, record: &mut Record) -> Result<(), SqliteStoreError> {
+        self.ensure_writable()?;
+
+        let strategy = self.collision_strategy;
+        let on_conflict = match strategy {
+            CollisionStrategy::AssumeUnique => "",
+            CollisionStrategy::PreCheck | CollisionStrategy::OnConflictRetry => {
+                "on conflict(id) do nothing"
+            }
+        };
+        let attempts = match strategy {
+            CollisionStrategy::AssumeUnique => 2,
+            CollisionStrategy::PreCheck | CollisionStrategy::OnConflictRetry => MAX_CREATE_ATTEMPTS,
+        };
+
+        let conn = self.conn()?;
+        let mut stmt = conn.prepare_cached(&self.insert_query(on_conflict))?;
+
+        // With `on conflict do nothing` the id check and the insert are the
+        // same statement, so a concurrent `create` can never claim the id in
+        // between; the pre-check is only an extra, earlier look.
+        for _ in 0..attempts {
+            if strategy == CollisionStrategy::PreCheck && self.id_taken(&conn, &record.id)? {
+                record.id = self.generate_id();
+                continue;
+            }
+
+            let inserted = match stmt.execute(params_from_iter(self.row_values(record)?)) {
+                Err(err) if is_primary_key_violation(&err) => 0,
+                res => res?,
+            };
+
+            if inserted == 1 {
+                self.notify(SessionEvent::Created(record.id));
+                return Ok(());
+            }
+
+            record.id = self.generate_id();
+        }
+
+        Err(SqliteStoreError::IdCollisionExhausted(attempts))
+    }
+
+    /// The insert used by `create`, followed by `on_conflict`.
+    fn insert_query(&self, on_conflict: &str) -> String {
+        format!(
+            r#"
+            insert into {table}
+                (id, data, expiry_date, user_id, created_at, tenant)
+                values (?1, ?2, ?3, ?4, ?5, ?6)
+            {on_conflict}
+            "#,
+            table = self.table
+        )
+    }
+
+    fn create_unchecked_with_conn(&self, record: &Record) -> Result<(), SqliteStoreError> {
+        self.ensure_writable()?;
+
+        let conn = self.conn()?;
+
+        match conn
+            .prepare_cached(&self.insert_query(""))?
+            .execute(params_from_iter(self.row_values(record)?))
+        {
+            Err(err) if is_primary_key_violation(&err) => {
+                Err(SqliteStoreError::DuplicateId(record.id))
+            }
+            Err(err) => Err(err.into()),
+            Ok(_) => {
+                self.notify(SessionEvent::Created(record.id));
+                Ok(())
+            }
+        }
+    }
+
+    /// Whether any session, expired or not, is stored under `id`.
+    fn id_taken(&self, conn: &Connection, id: &Id) -> Result<bool, SqliteStoreError> {
+        let query = format!(
+            "select exists(select 1 from {table} where id = ?1)",
+            table = self.table
+        );
+
+        let taken = conn
+            .prepare_cached(&query)?
+            .query_row(params![self.id_value(id)], |row| row.get(0))?;
+
+        Ok(taken)
+    }
+
+    /// A fresh random session id.
+    fn generate_id(&self) -> Id {
+        #[cfg(test)]
+        if let Some(id) = self.generated_id {
+            return id;
+        }
+
+        Id::default()
+    }
+
+    /// Generates ids until one isn't [taken](Self::id_taken).
+    fn unused_id(&self, conn: &Connection) -> Result<Id, SqliteStoreError> {
+        for _ in 0..MAX_CREATE_ATTEMPTS {
+            let id = self.generate_id();
+
+            if !self.id_taken(conn, &id)? {
+                return Ok(id);
+            }
+        }
+
+        Err(SqliteStoreError::IdCollisionExhausted(MAX_CREATE_ATTEMPTS))
+    }
+
+    fn reserve_id_with_conn(&self) -> Result<Id, SqliteStoreError> {
+        self.unused_id(&*self.conn()?)
+    }
+
+    /// The upsert used by `save`. `created_at` is only written by the insert,
+    /// so it keeps the time the session was first stored, and every update
+    /// bumps `version`.
+    fn save_query(&self) -> String {
+        format!(
+            r#"
+            insert into {table}
+                (id, data, expiry_date, user_id, created_at, tenant)
+                values (?1, ?2, ?3, ?4, ?5, ?6)
+            on conflict(id) do update set
+            data = excluded.data,
+            expiry_date = excluded.expiry_date,
+            user_id = excluded.user_id,
+            tenant = excluded.tenant,
+            version = version + 1
+            "#,
+            table = self.table
+        )
+    }
+
+    fn save_with_conn(&self, record: &Record) -> Result<u64, SqliteStoreError> {
+        self.ensure_writable()?;
+
+        let conn = self.conn()?;
+
+        if self.skip_unchanged_saves
+            && let Some(changes) = self.save_unchanged_with_conn(&conn, record)?
+        {
+            if changes > 0 {
+                self.notify(SessionEvent::Saved(record.id));
+            }
+            return Ok(changes);
+        }
+
+        let changes = conn
+            .prepare_cached(&self.save_query())?
+            .execute(params_from_iter(self.row_values(record)?))?;
+
+        self.notify(SessionEvent::Saved(record.id));
+
+        Ok(changes as u64)
+    }
+
+    /// Handles a save whose data matches the stored row by at most moving its
+    /// expiry, returning the number of rows changed, or `None` if the row is
+    /// missing, differs or doesn't decode and has to be written in full.
+    fn save_unchanged_with_conn(
+        &self,
+        conn: &Connection,
+        record: &Record,
+    ) -> Result<Option<u64>, SqliteStoreError> {
+        let query = format!(
+            "select data, expiry_date from {table} where id = ?1",
+            table = self.table
+        );
+
+        let stored: Option<(Vec<u8>, i64)> = conn
+            .prepare_cached(&query)?
+            .query_row(params![self.id_value(&record.id)], |row| {
+                Ok((row.get::<_, Data>(0)?.0, row.get(1)?))
+            })
+            .optional()?;
+
+        let Some((data, expiry_date)) = stored else {
+            return Ok(None);
+        };
+
+        match self.decode_record(&data) {
+            Ok(stored) if stored.data == record.data => {}
+            _ => return Ok(None),
+        }
+
+        let new_expiry = unix_nanos(record.expiry_date);
+        if expiry_date == new_expiry {
+            return Ok(Some(0));
+        }
+
+        let query = format!(
+            "update {table} set expiry_date = ?2 where id = ?1",
+            table = self.table
+        );
+        let changes = conn
+            .prepare_cached(&query)?
+            .execute(params![self.id_value(&record.id), new_expiry])?;
+
+        Ok(Some(changes as u64))
+    }
+
+    fn save_if_unchanged_with_conn(
+        &self,
+        record: &Record,
+        expected_version: u64,
+    ) -> Result<bool, SqliteStoreError> {
+        self.ensure_writable()?;
+
+        let query = format!(
+            r#"
+            update {table} set
+            data = ?2,
+            expiry_date = ?3,
+            user_id = ?4,
+            tenant = ?6,
+            version = version + 1
+            where id = ?1 and version = ?5
+            "#,
+            table = self.table
+        );
+        let conn = self.conn()?;
+
+        let [id, data, expiry_date, user_id, _, tenant] = self.row_values(record)?;
+        let updated = conn.prepare_cached(&query)?.execute(params![
+            id,
+            data,
+            expiry_date,
+            user_id,
+            expected_version as i64,
+            tenant
+        ])?;
+
+        if updated > 0 {
+            self.notify(SessionEvent::Saved(record.id));
+        }
+
+        Ok(updated > 0)
+    }
+
+    fn version_of_with_conn(&self, id: &Id) -> Result<Option<u64>, SqliteStoreError> {
+        let query = format!(
+            "select version from {table} where id = ?1",
+            table = self.table
+        );
+        let conn = self.conn()?;
+
+        let version: Option<i64> = conn
+            .prepare_cached(&query)?
+            .query_row(params![self.id_value(id)], |row| row.get(0))
+            .optional()?;
+
+        Ok(version.map(|version| version as u64))
+    }
+
+    fn export_entries_with_conn(&self) -> Result<Vec<archive::Entry>, SqliteStoreError> {
+        let conn = self.conn()?;
+        let mut stmt = conn.prepare(&format!(
+            "select id, expiry_date, data from {table} order by id",
+            table = self.table
+        ))?;
+
+        let mut rows = stmt.query([])?;
+        let mut entries = Vec::new();
+
+        while let Some(row) = rows.next()? {
+            let id = parse_id(row.get_ref(0)?)?;
+            let Data(data) = row.get(2)?;
+
+            entries.push((id.to_string(), row.get(1)?, data.into()));
+        }
+
+        Ok(entries)
+    }
+
+    fn import_entries_with_conn(
+        &self,
+        entries: &[archive::Entry],
+        overwrite: bool,
+    ) -> Result<u64, SqliteStoreError> {
+        self.ensure_writable()?;
+
+        let conflict = if overwrite {
+            r#"
+            do update set
+            data = excluded.data,
+            expiry_date = excluded.expiry_date,
+            user_id = excluded.user_id,
+            tenant = excluded.tenant,
+            version = version + 1
+            "#
+        } else {
+            "do nothing"
+        };
+        let query = format!(
+            r#"
+            insert into {table}
+                (id, data, expiry_date, user_id, created_at, tenant)
+                values (?1, ?2, ?3, ?4, ?5, ?6)
+            on conflict(id) {conflict}
+            "#,
+            table = self.table
+        );
+
+        let mut conn = self.conn()?;
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let mut imported = 0;
+
+        {
+            let mut stmt = tx.prepare(&query)?;
+
+            for (id, expiry_date, data) in entries {
+                let id = parse_id(ValueRef::Text(id.as_bytes()))?;
+                // Only decode when the owner or tenant has to be worked out
+                // again.
+                let record = if self.user_id_extractor.is_some() || self.tenant_extractor.is_some()
+                {
+                    Some(self.decode_record(data)?)
+                } else {
+                    None
+                };
+                let extract = |extractor: &Option<RecordExtractor>| {
+                    extractor
+                        .as_ref()
+                        .zip(record.as_ref())
+                        .and_then(|(f, record)| f.0(record))
+                };
+
+                imported += stmt.execute(params![
+                    self.id_value(&id),
+                    self.data_value(data.to_vec()),
+                    expiry_date,
+                    extract(&self.user_id_extractor),
+                    self.now_nanos(),
+                    extract(&self.tenant_extractor),
+                ])? as u64;
+            }
+        }
+
+        tx.commit()?;
+
+        Ok(imported)
+    }
+
+    fn migrate_from_sqlx_store_with_conn(
+        &self,
+        source_table: &str,
+    ) -> Result<u64, SqliteStoreError> {
+        self.ensure_writable()?;
+
+        // `strftime('%s', ...)` parses the date-time strings SQLite understands,
+        // including RFC 3339 with an offset.
+        let select = format!(
+            r#"
+            select data, case
+                when typeof(expiry_date) in ('integer', 'real') then cast(expiry_date as integer)
+                else cast(strftime('%s', expiry_date) as integer)
+            end
+            from {schema}{source_table}
+            "#,
+            schema = self.schema_prefix()
+        );
+        let query = self.save_query();
+
+        let mut conn = self.conn()?;
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+
+        let rows = tx
+            .prepare(&select)?
+            .query_map([], |row| {
+                Ok((row.get::<_, Data>(0)?.0, row.get::<_, Option<i64>>(1)?))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        {
+            let mut stmt = tx.prepare_cached(&query)?;
+
+            for (data, expiry_date) in &rows {
+                let mut record = MessagePackSerializer.decode(data)?;
+                let expiry_date = expiry_date
+                    .and_then(|secs| OffsetDateTime::from_unix_timestamp(secs).ok())
+                    .ok_or_else(|| {
+                        SqliteStoreError::Deserialize(format!(
+                            "unreadable expiry for session {}",
+                            record.id
+                        ))
+                    })?;
+                record.expiry_date = expiry_date;
+
+                stmt.execute(params_from_iter(self.row_values(&record)?))?;
+            }
+        }
+
+        tx.commit()?;
+
+        Ok(rows.len() as u64)
+    }
+
+    fn save_all_atomic_with_conn(&self, records: &[Record]) -> Result<(), SqliteStoreError> {
+        self.ensure_writable()?;
+
+        let query = self.save_query();
+        let mut conn = self.conn()?;
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+
+        {
+            let mut stmt = tx.prepare_cached(&query)?;
+
+            for record in records {
+                stmt.execute(params_from_iter(self.row_values(record)?))?;
+            }
+        }
+
+        // Returning early drops `tx`, which rolls back everything written.
+        tx.commit()?;
+
+        Ok(())
+    }
+
+    fn save_many_with_conn(&self, records: &[Record]) -> Result<(), SqliteStoreError> {
+        self.ensure_writable()?;
+
+        let query = self.save_query();
+        let mut conn = self.conn()?;
+
+        for chunk in records.chunks(self.batch_size) {
+            let tx = conn.transaction()?;
+
+            {
+                let mut stmt = tx.prepare_cached(&query)?;
+
+                for record in chunk {
+                    stmt.execute(params_from_iter(self.row_values(record)?))?;
+                }
+            }
+
+            tx.commit()?;
+        }
+
+        Ok(())
+    }
+
+    fn bulk_seed_with_conn(&self, records: &[Record]) -> Result<(), SqliteStoreError> {
+        self.ensure_writable()?;
+
+        let schema = self.schema_prefix();
+        let mut conn = self.conn()?;
+
+        let synchronous: i64 =
+            conn.query_row(&format!("pragma {schema}synchronous"), [], |row| row.get(0))?;
+        let journal_mode: String =
+            conn.query_row(&format!("pragma {schema}journal_mode"), [], |row| {
+                row.get(0)
+            })?;
+
+        // Leaving WAL needs every other connection closed, and WAL commits are
+        // cheap enough already.
+        let switch_journal = !journal_mode.eq_ignore_ascii_case("wal");
+
+        conn.execute_batch(&format!("pragma {schema}synchronous = off"))?;
+        if switch_journal {
+            conn.query_row(&format!("pragma {schema}journal_mode = memory"), [], |_| {
+                Ok(())
+            })?;
+        }
+
+        let res = (|| {
+            let query = self.save_query();
+            let tx = conn.transaction()?;
+
+            {
+                let mut stmt = tx.prepare_cached(&query)?;
+
+                for record in records {
+                    stmt.execute(params_from_iter(self.row_values(record)?))?;
+                }
+            }
+
+            tx.commit()?;
+            Ok(())
+        })();
+
+        // The connection goes back to the pool, so it must not keep the
+        // seeding settings even if seeding failed.
+        if switch_journal {
+            conn.query_row(
+                &format!("pragma {schema}journal_mode = {journal_mode}"),
+                [],
+                |_| Ok(()),
+            )?;
+        }
+        conn.execute_batch(&format!("pragma {schema}synchronous = {synchronous}"))?;
+
+        res
+    }
+
+    fn load_with_conn(&self, session_id: &Id) -> Result<Option<Record>, SqliteStoreError> {
+        // Lazy deletion needs to see expired rows to know there is something
+        // to delete; otherwise they are filtered out right away.
+        let query = format!(
+            r#"
+            select data, last_accessed, expiry_date, expiry_date {live} ?2 from {table}
+            where id = ?1 {filter}
+            "#,
+            table = self.table,
+            live = self.live_op(),
+            filter = if self.lazy_expiry_deletion {
+                String::new()
+            } else {
+                format!("and expiry_date {live} ?2", live = self.live_op())
+            },
+        );
+
+        let conn = self.conn()?;
+        let now = self.now_nanos();
+
+        // `load` runs on nearly every request, so it reuses the statement from
+        // rusqlite's per-connection cache instead of having SQLite parse and
+        // plan the same SQL each time; only the first load on a connection
+        // pays for preparing it.
+        let row: Option<(Vec<u8>, Option<i64>, i64, bool)> = conn
+            .prepare_cached(&query)?
+            .query_row(params![self.id_value(session_id), now], |row| {
+                let Data(data) = row.get(0)?;
+                Ok((data, row.get(1)?, row.get(2)?, row.get(3)?))
+            })
+            .optional()?;
+
+        let (data, last_accessed, expiry_date) = match row {
+            Some((data, last_accessed, expiry_date, true)) => (data, last_accessed, expiry_date),
+            Some((_, _, _, false)) => {
+                if self.lazy_expiry_deletion && !self.read_only {
+                    self.delete_expired_session(&conn, session_id, now);
+                }
+
+                return Ok(None);
+            }
+            None => return Ok(None),
+        };
+
+        if self.track_access
+            && !self.read_only
+            && last_accessed.is_none_or(|at| now.saturating_sub(at) > ACCESS_WRITE_INTERVAL)
+        {
+            self.record_access(&conn, session_id, now);
+        }
+
+        match self.decode_stored(&data, expiry_date) {
+            Ok(record) => Ok(Some(record)),
+            Err(err) => match self.corrupt_blob_policy {
+                CorruptBlobPolicy::Error => Err(err),
+                CorruptBlobPolicy::TreatAsMissing => Ok(None),
+                CorruptBlobPolicy::DeleteAndMiss => {
+                    if !self.read_only {
+                        self.delete_corrupt_session(&conn, session_id, &data);
+                    }
+
+                    Ok(None)
+                }
+            },
+        }
+    }
+
+    /// Writes `now` to the `last_accessed` column of session `id` for
+    /// [access tracking](Self::with_track_access).
+    ///
+    /// This is bookkeeping on top of a read, so a failure, e.g. because
+    /// another connection holds the write lock, doesn't fail the load; the
+    /// next load tries again.
+    fn record_access(&self, conn: &Connection, id: &Id, now: i64) {
+        let query = format!(
+            "update {table} set last_accessed = ?2 where id = ?1",
+            table = self.table
+        );
+
+        let updated = conn
+            .prepare_cached(&query)
+            .and_then(|mut stmt| stmt.execute(params![self.id_value(id), now]));
+
+        match updated {
+            Ok(_) => {}
+            #[cfg(feature = "tracing")]
+            Err(err) => tracing::debug!(error = %err, "recording session access failed"),
+            #[cfg(not(feature = "tracing"))]
+            Err(_) => {}
+        }
+    }
+
+    /// Deletes session `id`, whose stored `data` `load` just failed to
+    /// decode, for [`CorruptBlobPolicy::DeleteAndMiss`].
+    ///
+    /// Like [`delete_expired_session`](Self::delete_expired_session), a
+    /// failure here doesn't fail the load, which reports the session as
+    /// missing either way; the row is left for a later load to retry.
+    fn delete_corrupt_session(&self, conn: &Connection, id: &Id, data: &[u8]) {
+        // Matching on the data too leaves the row alone if a `save` replaced
+        // it since it was read.
+        let query = format!(
+            "delete from {table} where id = ?1 and cast(data as blob) = ?2",
+            table = self.table
+        );
+
+        let deleted = conn
+            .prepare_cached(&query)
+            .and_then(|mut stmt| stmt.execute(params![self.id_value(id), data]));
+
+        match deleted {
+            Ok(0) => {}
+            Ok(_) => self.notify(SessionEvent::Deleted(*id)),
+            #[cfg(feature = "tracing")]
+            Err(err) => tracing::debug!(error = %err, "deleting a corrupt session failed"),
+            #[cfg(not(feature = "tracing"))]
+            Err(_) => {}
+        }
+    }
+
+    /// Deletes session `id`, which `load` just found expired, for
+    /// [lazy expiry deletion](Self::with_lazy_expiry_deletion).
+    ///
+    /// The load has its answer already, so a failure here, e.g. because
+    /// another connection holds the write lock, doesn't fail it; the row is
+    /// left for a later load or sweep.
+    fn delete_expired_session(&self, conn: &Connection, id: &Id, now: i64) {
+        // The expiry is checked again by the delete itself, so a `save` that
+        // revived the session in the meantime wins.
+        let query = format!(
+            "delete from {table} where id = ?1 and expiry_date {expired} ?2",
+            table = self.table,
+            expired = self.expired_op(),
+        );
+
+        let deleted = conn
+            .prepare_cached(&query)
+            .and_then(|mut stmt| stmt.execute(params![self.id_value(id), now]));
+
+        match deleted {
+            Ok(0) => {}
+            Ok(_) => self.notify(SessionEvent::Expired(*id)),
+            #[cfg(feature = "tracing")]
+            Err(err) => tracing::debug!(error = %err, "lazy expiry deletion failed"),
+            #[cfg(not(feature = "tracing"))]
+            Err(_) => {}
+        }
+    }
+
+    fn load_raw_with_conn(&self, id: &Id) -> Result<Option<Vec<u8>>, SqliteStoreError> {
+        let query = format!(
+            "select data from {table} where id = ?1 and expiry_date {live} ?2",
+            table = self.table,
+            live = self.live_op(),
+        );
+        let conn = self.conn()?;
+
+        let data = conn
+            .prepare_cached(&query)?
+            .query_row(params![self.id_value(id), self.now_nanos()], |row| {
+                row.get::<_, Data>(0).map(|data| data.0)
+            })
+            .optional()?;
+
+        Ok(data)
+    }
+
+    fn save_raw_with_conn(
+        &self,
+        id: &Id,
+        bytes: &[u8],
+        expiry_date: OffsetDateTime,
+    ) -> Result<(), SqliteStoreError> {
+        self.ensure_writable()?;
+
+        let query = format!(
+            r#"
+            insert into {table}
+                (id, data, expiry_date, created_at)
+                values (?1, ?2, ?3, ?4)
+            on conflict(id) do update set
+            data = excluded.data,
+            expiry_date = excluded.expiry_date,
+            version = version + 1
+            "#,
+            table = self.table
+        );
+        let conn = self.conn()?;
+
+        conn.prepare_cached(&query)?.execute(params![
+            self.id_value(id),
+            bytes,
+            unix_nanos(expiry_date),
+            self.now_nanos(),
+        ])?;
+
+        Ok(())
+    }
+
+    fn load_detailed_with_conn(&self, id: &Id) -> Result<LoadOutcome, SqliteStoreError> {
+        let query = format!(
+            "select data, expiry_date from {table} where id = ?1",
+            table = self.table
+        );
+        let conn = self.conn()?;
+
+        let row: Option<(Vec<u8>, i64)> = conn
+            .prepare_cached(&query)?
+            .query_row(params![self.id_value(id)], |row| {
+                Ok((row.get::<_, Data>(0)?.0, row.get(1)?))
+            })
+            .optional()?;
+
+        match row {
+            Some((data, expiry_date)) if self.is_live(expiry_date) => {
+                Ok(LoadOutcome::Found(self.decode_stored(&data, expiry_date)?))
+            }
+            Some(_) => Ok(LoadOutcome::Expired),
+            None => Ok(LoadOutcome::NotFound),
+        }
+    }
+
+    fn exists_with_conn(&self, id: &Id) -> Result<bool, SqliteStoreError> {
+        let query = format!(
+            "select exists(select 1 from {table} where id = ?1 and expiry_date {live} ?2)",
+            table = self.table,
+            live = self.live_op(),
+        );
+        let conn = self.conn()?;
+
+        let exists = conn
+            .prepare_cached(&query)?
+            .query_row(params![self.id_value(id), self.now_nanos()], |row| {
+                row.get(0)
+            })?;
+
+        Ok(exists)
+    }
+
+    fn expiry_of_with_conn(&self, id: &Id) -> Result<Option<OffsetDateTime>, SqliteStoreError> {
+        let query = format!(
+            "select expiry_date from {table} where id = ?1",
+            table = self.table
+        );
+        let conn = self.conn()?;
+
+        let expiry_date: Option<i64> = conn
+            .prepare_cached(&query)?
+            .query_row(params![self.id_value(id)], |row| row.get(0))
+            .optional()?;
+
+        Ok(expiry_date.map(from_unix_nanos))
+    }
+
+    fn load_many_with_conn(&self, ids: &[Id]) -> Result<Vec<(Id, Record)>, SqliteStoreError> {
+        let conn = self.conn()?;
+        let now = self.now_nanos();
+
+        let mut records = Vec::new();
+
+        for chunk in ids.chunks(ID_CHUNK_SIZE) {
+            let query = format!(
+                r#"
+                select id, data, expiry_date from {table}
+                where id in ({ids}) and expiry_date {live} ?
+                "#,
+                table = self.table,
+                live = self.live_op(),
+                ids = placeholders(chunk.len())
+            );
+
+            let params = chunk
+                .iter()
+                .map(|id| self.id_value(id))
+                .chain([Value::Integer(now)]);
+
+            let mut stmt = conn.prepare(&query)?;
+            let mut rows = stmt.query(params_from_iter(params))?;
+
+            while let Some(row) = rows.next()? {
+                let Data(data) = row.get(1)?;
+
+                if let Ok(entry) = self.decode_row(row.get_ref(0)?, &data, row.get(2)?) {
+                    records.push(entry);
+                }
+            }
+        }
+
+        Ok(records)
+    }
+
+    fn touch_with_conn(
+        &self,
+        id: &Id,
+        expiry_date: OffsetDateTime,
+    ) -> Result<bool, SqliteStoreError> {
+        self.ensure_writable()?;
+
+        let query = format!(
+            "update {table} set expiry_date = ?2 where id = ?1",
+            table = self.table
+        );
+        let conn = self.conn()?;
+
+        let changes = conn.execute(&query, params![self.id_value(id), unix_nanos(expiry_date)])?;
+
+        Ok(changes > 0)
+    }
+
+    /// Rewrites the row stored under `old` with the record in `data` moved to
+    /// the id `new`.
+    fn move_record(
+        &self,
+        conn: &Connection,
+        old: Value,
+        data: &[u8],
+        new: Id,
+    ) -> Result<(), SqliteStoreError> {
+        // The serialized record carries its own id, so it is rewritten too.
+        let mut record = self.decode_record(data)?;
+        record.id = new;
+
+        conn.prepare_cached(&format!(
+            "update {table} set id = ?2, data = ?3 where id = ?1",
+            table = self.table
+        ))?
+        .execute(params![
+            old,
+            self.id_value(&new),
+            self.data_value(self.encode_record(&record)?)
+        ])?;
+
+        Ok(())
+    }
+
+    fn rotate_all_ids_with_conn(&self) -> Result<u64, SqliteStoreError> {
+        self.ensure_writable()?;
+
+        let mut conn = self.conn()?;
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+
+        let rows = tx
+            .prepare(&format!("select id, data from {table}", table = self.table))?
+            .query_map([], |row| {
+                Ok((row.get::<_, Value>(0)?, row.get::<_, Data>(1)?.0))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        for (old, data) in &rows {
+            let new = self.unused_id(&tx)?;
+            self.move_record(&tx, old.clone(), data, new)?;
+        }
+
+        tx.commit()?;
+
+        Ok(rows.len() as u64)
+    }
+
+    fn rotate_id_with_conn(&self, old: &Id, new: Id) -> Result<bool, SqliteStoreError> {
+        self.ensure_writable()?;
+
+        let mut conn = self.conn()?;
+        // Take the write lock up front so nothing claims `new` between the
+        // check and the update.
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+
+        let data: Option<Vec<u8>> = tx
+            .query_row(
+                &format!("select data from {table} where id = ?1", table = self.table),
+                params![self.id_value(old)],
+                |row| row.get::<_, Data>(0).map(|data| data.0),
+            )
+            .optional()?;
+
+        let Some(data) = data else {
+            return Ok(false);
+        };
+
+        if self.id_taken(&tx, &new)? {
+            return Err(SqliteStoreError::DuplicateId(new));
+        }
+
+        self.move_record(&tx, self.id_value(old), &data, new)?;
+
+        tx.commit()?;
+
+        Ok(true)
+    }
+
+    fn delete_with_conn(&self, session_id: &Id) -> Result<u64, SqliteStoreError> {
+        self.ensure_writable()?;
+
+        let query = format!("delete from {table} where id = ?", table = self.table);
+        let conn = self.conn()?;
+
+        let changes = conn
+            .prepare_cached(&query)?
+            .execute(params![self.id_value(session_id)])?;
+
+        if changes > 0 {
+            self.notify(SessionEvent::Deleted(*session_id));
+        }
+
+        Ok(changes as u64)
+    }
+
+    fn delete_returning_with_conn(
+        &self,
+        id: &Id,
+    ) -> Result<Option<OffsetDateTime>, SqliteStoreError> {
+        self.ensure_writable()?;
+
+        let mut conn = self.conn()?;
+
+        let expiry_date: Option<i64> = if self.supports_returning() {
+            let query = format!(
+                "delete from {table} where id = ?1 returning expiry_date",
+                table = self.table
+            );
+
+            conn.prepare_cached(&query)?
+                .query_row(params![self.id_value(id)], |row| row.get(0))
+                .optional()?
+        } else {
+            let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+
+            let expiry_date = tx
+                .prepare_cached(&format!(
+                    "select expiry_date from {table} where id = ?1",
+                    table = self.table
+                ))?
+                .query_row(params![self.id_value(id)], |row| row.get(0))
+                .optional()?;
+
+            tx.prepare_cached(&format!(
+                "delete from {table} where id = ?1",
+                table = self.table
+            ))?
+            .execute(params![self.id_value(id)])?;
+
+            tx.commit()?;
+            expiry_date
+        };
+
+        if expiry_date.is_some() {
+            self.notify(SessionEvent::Deleted(*id));
+        }
+
+        Ok(expiry_date.map(from_unix_nanos))
+    }
+
+    fn touch_many_with_conn(
+        &self,
+        ids: &[Id],
+        expiry_date: OffsetDateTime,
+    ) -> Result<u64, SqliteStoreError> {
+        self.ensure_writable()?;
+
+        let mut conn = self.conn()?;
+        let tx = conn.transaction()?;
+
+        let mut touched = 0;
+
+        for chunk in ids.chunks(ID_CHUNK_SIZE) {
+            let query = format!(
+                "update {table} set expiry_date = ? where id in ({ids})",
+                table = self.table,
+                ids = placeholders(chunk.len())
+            );
+
+            let params = [Value::Integer(unix_nanos(expiry_date))]
+                .into_iter()
+                .chain(chunk.iter().map(|id| self.id_value(id)));
+
+            touched += tx.execute(&query, params_from_iter(params))? as u64;
+        }
+
+        tx.commit()?;
+
+        Ok(touched)
+    }
+
+    fn load_and_touch_with_conn(
+        &self,
+        id: &Id,
+        new_expiry: OffsetDateTime,
+    ) -> Result<Option<Record>, SqliteStoreError> {
+        self.ensure_writable()?;
+
+        let update = format!(
+            r#"
+            update {table} set expiry_date = ?3{access}
+            where id = ?1 and expiry_date {live} ?2
+            "#,
+            table = self.table,
+            live = self.live_op(),
+            access = if self.track_access {
+                ", last_accessed = ?2"
+            } else {
+                ""
+            },
+        );
+        let params = params![self.id_value(id), self.now_nanos(), unix_nanos(new_expiry)];
+        let mut conn = self.conn()?;
+
+        let data: Option<Vec<u8>> = if self.supports_returning() {
+            conn.prepare_cached(&format!("{update} returning data"))?
+                .query_row(params, |row| row.get::<_, Data>(0).map(|data| data.0))
+                .optional()?
+        } else {
+            let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+
+            let data = tx
+                .prepare_cached(&format!(
+                    "select data from {table} where id = ?1 and expiry_date {live} ?2",
+                    table = self.table,
+                    live = self.live_op(),
+                ))?
+                .query_row(&params[..2], |row| row.get::<_, Data>(0).map(|data| data.0))
+                .optional()?;
+
+            if data.is_some() {
+                tx.prepare_cached(&update)?.execute(params)?;
+            }
+
+            tx.commit()?;
+            data
+        };
+
+        data.map(|data| {
+            let mut record = self.decode_record(&data)?;
+            record.expiry_date = new_expiry;
+            Ok(record)
+        })
+        .transpose()
+    }
+
+    fn delete_many_with_conn(&self, ids: &[Id]) -> Result<u64, SqliteStoreError> {
+        self.ensure_writable()?;
+
+        let mut conn = self.conn()?;
+        let tx = conn.transaction()?;
+
+        let mut deleted = 0;
+
+        for chunk in ids.chunks(ID_CHUNK_SIZE) {
+            let query = format!(
+                "delete from {table} where id in ({ids})",
+                table = self.table,
+                ids = placeholders(chunk.len())
+            );
+
+            let params = chunk.iter().map(|id| self.id_value(id));
+
+            deleted += tx.execute(&query, params_from_iter(params))? as u64;
+        }
+
+        tx.commit()?;
+
+        Ok(deleted)
+    }
+
+    fn health_check_with_conn(&self) -> Result<(), SqliteStoreError> {
+        let conn = self.conn()?;
+
+        conn.query_row("select 1", [], |row| row.get::<_, i64>(0))?;
+
+        Ok(())
+    }
+
+    fn warmup_with_conn(&self, count: u32) -> Result<(), SqliteStoreError> {
+        // Holding every connection at once makes the pool open new ones
+        // instead of handing the same idle one out again.
+        let conns = (0..count.min(self.pool.max_size()))
+            .map(|_| self.conn())
+            .collect::<Result<Vec<_>, _>>()?;
+
+        drop(conns);
+
+        Ok(())
+    }
+
+    fn count_with_conn(&self) -> Result<i64, SqliteStoreError> {
+        let query = format!("select count(*) from {table}", table = self.table);
+        let conn = self.conn()?;
+
+        let count = conn.query_row(&query, [], |row| row.get(0))?;
+
+        Ok(count)
+    }
+
+    fn count_active_with_conn(&self) -> Result<i64, SqliteStoreError> {
+        let query = format!(
+            "select count(*) from {table} where expiry_date {live} ?1",
+            table = self.table,
+            live = self.live_op(),
+        );
+        let conn = self.conn()?;
+
+        let count = conn.query_row(&query, params![self.now_nanos()], |row| row.get(0))?;
+
+        Ok(count)
+    }
+
+    fn count_expired_with_conn(&self) -> Result<i64, SqliteStoreError> {
+        let query = format!(
+            "select count(*) from {table} where expiry_date {expired} ?1",
+            table = self.table,
+            expired = self.expired_op(),
+        );
+        let conn = self.conn()?;
+
+        let count = conn.query_row(&query, params![self.now_nanos()], |row| row.get(0))?;
+
+        Ok(count)
+    }
+
+    fn active_ids_with_conn(&self) -> Result<Vec<Id>, SqliteStoreError> {
+        let query = format!(
+            "select id from {table} where expiry_date {live} ?1",
+            table = self.table,
+            live = self.live_op(),
+        );
+        let conn = self.conn()?;
+
+        let mut stmt = conn.prepare(&query)?;
+        let mut rows = stmt.query(params![self.now_nanos()])?;
+
+        let mut ids = Vec::new();
+
+        while let Some(row) = rows.next()? {
+            ids.push(parse_id(row.get_ref(0)?)?);
+        }
+
+        Ok(ids)
+    }
+
+    fn expiring_within_with_conn(
+        &self,
+        window: time::Duration,
+    ) -> Result<Vec<Id>, SqliteStoreError> {
+        let query = format!(
+            "select id from {table} where expiry_date {live} ?1 and expiry_date <= ?2",
+            table = self.table,
+            live = self.live_op(),
+        );
+        let conn = self.conn()?;
+
+        let now = self.clock.now();
+        let until = unix_nanos(now.saturating_add(window));
+
+        let mut stmt = conn.prepare(&query)?;
+        let mut rows = stmt.query(params![unix_nanos(now), until])?;
+
+        let mut ids = Vec::new();
+
+        while let Some(row) = rows.next()? {
+            ids.push(parse_id(row.get_ref(0)?)?);
+        }
+
+        Ok(ids)
+    }
+
+    fn idle_since_with_conn(&self, cutoff: OffsetDateTime) -> Result<Vec<Id>, SqliteStoreError> {
+        let query = format!(
+            r#"
+            select id from {table}
+            where expiry_date {live} ?1 and coalesce(last_accessed, created_at) < ?2
+            "#,
+            table = self.table,
+            live = self.live_op(),
+        );
+        let conn = self.conn()?;
+
+        let mut stmt = conn.prepare(&query)?;
+        let mut rows = stmt.query(params![self.now_nanos(), unix_nanos(cutoff)])?;
+
+        let mut ids = Vec::new();
+
+        while let Some(row) = rows.next()? {
+            ids.push(parse_id(row.get_ref(0)?)?);
+        }
+
+        Ok(ids)
+    }
+
+    fn expiry_histogram_with_conn(
+        &self,
+        bucket: time::Duration,
+        horizon: time::Duration,
+    ) -> Result<Vec<(OffsetDateTime, i64)>, SqliteStoreError> {
+        let query = format!(
+            r#"
+            select (expiry_date - ?1) / ?2 as bucket, count(*) from {table}
+            where expiry_date {live} ?1 and expiry_date <= ?3
+            group by bucket
+            order by bucket
+            "#,
+            table = self.table,
+            live = self.live_op(),
+        );
+        let conn = self.conn()?;
+
+        let now = self.clock.now();
+        let start = unix_nanos(now);
+        let width = bucket.whole_nanoseconds().clamp(1, i64::MAX.into()) as i64;
+        let until = unix_nanos(now.saturating_add(horizon));
+
+        let mut stmt = conn.prepare(&query)?;
+        let buckets = stmt
+            .query_map(params![start, width, until], |row| {
+                Ok((row.get::<_, i64>(0)?, row.get::<_, i64>(1)?))
+            })?
+            .map(|row| {
+                let (index, count) = row?;
+                let bucket_start = start.saturating_add(index.saturating_mul(width));
+                Ok((from_unix_nanos(bucket_start), count))
+            })
+            .collect::<Result<_, SqlError>>()?;
+
+        Ok(buckets)
+    }
+
+    fn delete_by_user_with_conn(&self, user_id: &str) -> Result<u64, SqliteStoreError> {
+        self.ensure_writable()?;
+
+        let query = format!("delete from {table} where user_id = ?1", table = self.table);
+        let conn = self.conn()?;
+
+        let deleted = conn.execute(&query, params![user_id])?;
+
+        Ok(deleted as u64)
+    }
+
+    fn sessions_for_user_with_conn(&self, user_id: &str) -> Result<Vec<Id>, SqliteStoreError> {
+        let query = format!(
+            "select id from {table} where user_id = ?1 and expiry_date {live} ?2",
+            table = self.table,
+            live = self.live_op(),
+        );
+        let conn = self.conn()?;
+
+        let mut stmt = conn.prepare(&query)?;
+        let mut rows = stmt.query(params![user_id, self.now_nanos()])?;
+
+        let mut ids = Vec::new();
+
+        while let Some(row) = rows.next()? {
+            ids.push(parse_id(row.get_ref(0)?)?);
+        }
+
+        Ok(ids)
+    }
+
+    fn count_for_tenant_with_conn(&self, tenant: &str) -> Result<i64, SqliteStoreError> {
+        let query = format!(
+            "select count(*) from {table} where tenant = ?1 and expiry_date {live} ?2",
+            table = self.table,
+            live = self.live_op(),
+        );
+        let conn = self.conn()?;
+
+        let count = conn.query_row(&query, params![tenant, self.now_nanos()], |row| row.get(0))?;
+
+        Ok(count)
+    }
+
+    fn delete_for_tenant_with_conn(&self, tenant: &str) -> Result<u64, SqliteStoreError> {
+        self.ensure_writable()?;
+
+        let query = format!("delete from {table} where tenant = ?1", table = self.table);
+        let conn = self.conn()?;
+
+        let deleted = conn.execute(&query, params![tenant])?;
+
+        Ok(deleted as u64)
+    }
+
+    fn active_ids_for_tenant_with_conn(&self, tenant: &str) -> Result<Vec<Id>, SqliteStoreError> {
+        let query = format!(
+            "select id from {table} where tenant = ?1 and expiry_date {live} ?2",
+            table = self.table,
+            live = self.live_op(),
+        );
+        let conn = self.conn()?;
+
+        let mut stmt = conn.prepare(&query)?;
+        let mut rows = stmt.query(params![tenant, self.now_nanos()])?;
+
+        let mut ids = Vec::new();
+
+        while let Some(row) = rows.next()? {
+            ids.push(parse_id(row.get_ref(0)?)?);
+        }
+
+        Ok(ids)
+    }
+
+    fn clear_with_conn(&self) -> Result<(), SqliteStoreError> {
+        self.ensure_writable()?;
+
+        let query = format!("delete from {table}", table = self.table);
+        let conn = self.conn()?;
+
+        conn.execute(&query, [])?;
+
+        Ok(())
+    }
+
+    fn delete_older_than_with_conn(&self, age: time::Duration) -> Result<u64, SqliteStoreError> {
+        self.ensure_writable()?;
+
+        let query = format!(
+            "delete from {table} where created_at < ?1",
+            table = self.table
+        );
+        let conn = self.conn()?;
+
+        let cutoff = unix_nanos(self.clock.now().saturating_sub(age));
+        let deleted = conn.execute(&query, params![cutoff])?;
+
+        Ok(deleted as u64)
+    }
+
+    fn checkpoint_with_conn(&self) -> Result<(), SqliteStoreError> {
+        if self.read_only {
+            return Ok(());
+        }
+
+        let conn = self.conn()?;
+
+        let busy: i64 = conn.query_row("pragma wal_checkpoint(TRUNCATE)", [], |row| row.get(0))?;
+
+        if busy != 0 {
+            return Err(SqlError::SqliteFailure(
+                ffi::Error::new(ffi::SQLITE_BUSY),
+                Some("WAL checkpoint blocked by another connection".to_owned()),
+            )
+            .into());
+        }
+
+        Ok(())
+    }
+
+    fn vacuum_with_conn(&self) -> Result<(), SqliteStoreError> {
+        self.ensure_writable()?;
+
+        let conn = self.conn()?;
+        let schema = self.schema.as_deref().unwrap_or("main");
+        conn.execute_batch(&format!("vacuum {schema}"))?;
+        Ok(())
+    }
+
+    fn incremental_vacuum_with_conn(&self, pages: u32) -> Result<(), SqliteStoreError> {
+        self.ensure_writable()?;
+
+        let conn = self.conn()?;
+        let mut stmt = conn.prepare(&format!(
+            "pragma {schema}incremental_vacuum({pages})",
+            schema = self.schema_prefix()
+        ))?;
+
+        // Each step frees a single page and returns a row for it, so the
+        // statement has to run to completion.
+        let mut rows = stmt.query([])?;
+        while rows.next()?.is_some() {}
+
+        Ok(())
+    }
+
+    fn observed_save(&self, record: &Record) -> Result<u64, SqliteStoreError> {
+        self.observed(
+            Operation::Save,
+            || self.retrying(|| self.save_with_conn(record)),
+            |observer, _, elapsed| observer.on_save(elapsed),
+        )
+    }
+
+    fn observed_delete(&self, session_id: &Id) -> Result<u64, SqliteStoreError> {
+        self.observed(
+            Operation::Delete,
+            || self.retrying(|| self.delete_with_conn(session_id)),
+            |observer, _, elapsed| observer.on_delete(elapsed),
+        )
+    }
+
+    fn observed_delete_expired(&self) -> Result<(), SqliteStoreError> {
+        self.observed(
+            Operation::DeleteExpired,
+            || self.delete_expired_with_conn(),
+            |observer, _, elapsed| observer.on_delete_expired(elapsed),
+        )
+    }
+
+    fn observed_delete_expired_in_batches(&self) -> Result<(), SqliteStoreError> {
+        self.observed(
+            Operation::DeleteExpired,
+            || loop {
+                let deleted = self.delete_expired_limited_with_conn(self.batch_size)?;
+
+                if deleted < self.batch_size as u64 {
+                    return Ok(());
+                }
+            },
+            |observer, _, elapsed| observer.on_delete_expired(elapsed),
+        )
+    }
+
+    fn delete_expired_limited_with_conn(&self, batch: usize) -> Result<u64, SqliteStoreError> {
+        self.ensure_writable()?;
+
+        // SQLite only supports `delete ... limit` when built with
+        // SQLITE_ENABLE_UPDATE_DELETE_LIMIT, so the limit goes on a subquery.
+        let condition = format!(
+            "id in (select id from {table} where expiry_date {expired} ?1 limit ?2)",
+            table = self.table,
+            expired = self.expired_op(),
+        );
+        let mut conn = self.conn()?;
+
+        self.delete_expired_rows(
+            &mut conn,
+            &condition,
+            params![self.now_nanos(), batch as i64],
+        )
+    }
+
+    fn reencode_all_with_conn(&self) -> Result<u64, SqliteStoreError> {
+        self.ensure_writable()?;
+
+        let mut conn = self.conn()?;
+        let limit = self.batch_size as i64;
+        let mut last_id: Option<Value> = None;
+        let mut reencoded = 0;
+
+        loop {
+            let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+
+            let rows = {
+                let after = if last_id.is_some() {
+                    "where id > ?2"
+                } else {
+                    ""
+                };
+                let mut stmt = tx.prepare_cached(&format!(
+                    "select id, data from {table} {after} order by id limit ?1",
+                    table = self.table
+                ))?;
+
+                let row = |row: &Row<'_>| Ok((row.get::<_, Value>(0)?, row.get::<_, Data>(1)?.0));
+
+                match &last_id {
+                    Some(last_id) => stmt.query_map(params![limit, last_id], row)?,
+                    None => stmt.query_map(params![limit], row)?,
+                }
+                .collect::<Result<Vec<_>, _>>()?
+            };
+
+            {
+                let mut stmt = tx.prepare_cached(&format!(
+                    "update {table} set data = ?2 where id = ?1",
+                    table = self.table
+                ))?;
+
+                for (id, data) in &rows {
+                    let blob = self.encode_record(&self.decode_record(data)?)?;
+
+                    if blob != *data {
+                        stmt.execute(params![id, self.data_value(blob)])?;
+                        reencoded += 1;
+                    }
+                }
+            }
+
+            tx.commit()?;
+
+            if rows.len() < self.batch_size {
+                return Ok(reencoded);
+            }
+            last_id = rows.into_iter().last().map(|(id, _)| id);
+        }
+    }
+
+    fn deduplicate_with_conn(&self, keep: DedupKeep) -> Result<u64, SqliteStoreError> {
+        self.ensure_writable()?;
+
+        let mut conn = self.conn()?;
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+
+        let rows = tx
+            .prepare(&format!(
+                "select id, data, expiry_date, created_at from {table} where expiry_date {live} ?1",
+                table = self.table,
+                live = self.live_op(),
+            ))?
+            .query_map(params![self.now_nanos()], |row| {
+                Ok((
+                    row.get::<_, Value>(0)?,
+                    row.get::<_, Data>(1)?.0,
+                    row.get::<_, i64>(2)?,
+                    row.get::<_, Option<i64>>(3)?,
+                ))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        type Rank = (i64, i64);
+
+        // The session that wins its group so far, by data, and the ids of the
+        // ones that lost.
+        let mut kept: HashMap<Vec<u8>, (Rank, Value)> = HashMap::new();
+        let mut losers = Vec::new();
+
+        for (id, data, expiry_date, created_at) in rows {
+            let record = self.decode_record(&data)?;
+            // Sorting the keys makes equal maps serialize the same way.
+            let key = rmp_serde::to_vec(&record.data.iter().collect::<BTreeMap<_, _>>())?;
+            let rank = match keep {
+                DedupKeep::Newest => (created_at.unwrap_or(i64::MIN), expiry_date),
+                DedupKeep::LatestExpiry => (expiry_date, created_at.unwrap_or(i64::MIN)),
+            };
+
+            match kept.get_mut(&key) {
+                Some((best, best_id)) if rank > *best => {
+                    *best = rank;
+                    losers.push(std::mem::replace(best_id, id));
+                }
+                Some(_) => losers.push(id),
+                None => {
+                    kept.insert(key, (rank, id));
+                }
+            }
+        }
+
+        {
+            let mut stmt = tx.prepare(&format!(
+                "delete from {table} where id = ?1",
+                table = self.table
+            ))?;
+
+            for id in &losers {
+                stmt.execute([id])?;
+            }
+        }
+
+        tx.commit()?;
+
+        for id in &losers {
+            if let Ok(id) = parse_id(id.into()) {
+                self.notify(SessionEvent::Deleted(id));
+            }
+        }
+
+        Ok(losers.len() as u64)
+    }
+
+    fn evict_to_cap_with_conn(&self, max_sessions: usize) -> Result<u64, SqliteStoreError> {
+        self.ensure_writable()?;
+
+        let mut conn = self.conn()?;
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+
+        let count: i64 = tx.query_row(
+            &format!("select count(*) from {table}", table = self.table),
+            [],
+            |row| row.get(0),
+        )?;
+
+        let excess = count.saturating_sub(i64::try_from(max_sessions).unwrap_or(i64::MAX));
+        if excess <= 0 {
+            return Ok(0);
+        }
+
+        let query = format!(
+            r#"
+            delete from {table} where id in (
+                select id from {table} order by expiry_date asc limit ?1
+            )
+            "#,
+            table = self.table
+        );
+        let evicted = tx.execute(&query, params![excess])?;
+
+        tx.commit()?;
+
+        Ok(evicted as u64)
+    }
+
+    fn delete_expired_with_conn(&self) -> Result<(), SqliteStoreError> {
+        self.ensure_writable()?;
+
+        let condition = format!("expiry_date {expired} ?1", expired = self.expired_op());
+        let mut conn = self.conn()?;
+
+        self.delete_expired_rows(&mut conn, &condition, params![self.now_nanos()])?;
+
+        Ok(())
+    }
+
+    /// Deletes the expired rows matching `condition`, returning how many it
+    /// removed and reporting each of them to the change listener, if any.
+    fn delete_expired_rows(
+        &self,
+        conn: &mut Connection,
+        condition: &str,
+        params: impl Params,
+    ) -> Result<u64, SqliteStoreError> {
+        let table = &self.table;
+
+        let Some(listener) = &self.change_listener else {
+            let query = format!("delete from {table} where {condition}");
+            return Ok(conn.execute(&query, params)? as u64);
+        };
+
+        // Collecting the rows first runs the statement to completion, so the
+        // deletion is committed before anyone hears about it.
+        let ids = if self.supports_returning() {
+            conn.prepare(&format!(
+                "delete from {table} where {condition} returning id"
+            ))?
+            .query_map(params, |row| row.get::<_, Value>(0))?
+            .collect::<Result<Vec<_>, _>>()?
+        } else {
+            let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+
+            let ids = tx
+                .prepare(&format!("select id from {table} where {condition}"))?
+                .query_map(params, |row| row.get::<_, Value>(0))?
+                .collect::<Result<Vec<_>, _>>()?;
+
+            {
+                let mut stmt = tx.prepare(&format!("delete from {table} where id = ?1"))?;
+
+                for id in &ids {
+                    stmt.execute([id])?;
+                }
+            }
+
+            tx.commit()?;
+            ids
+        };
+
+        for id in &ids {
+            if let Ok(id) = parse_id(id.into()) {
+                listener.0(SessionEvent::Expired(id));
+            }
+        }
+
+        Ok(ids.len() as u64)
+    }
+
+    /// Passes `event` to the change listener, if any.
+    fn notify(&self, event: SessionEvent) {
+        if let Some(listener) = &self.change_listener {
+            listener.0(event);
+        }
+    }
+}
+
+/// Converts `time` to the nanoseconds since the Unix epoch stored in
+/// timestamp columns, saturating past the year 2262.
+fn unix_nanos(time: OffsetDateTime) -> i64 {
+    let nanos = time.unix_timestamp_nanos();
+    nanos.clamp(i64::MIN.into(), i64::MAX.into()) as i64
+}
+
+/// The inverse of [`unix_nanos`].
+fn from_unix_nanos(nanos: i64) -> OffsetDateTime {
+    // Any `i64` of nanoseconds lies within the years `time` can represent.
+    OffsetDateTime::from_unix_timestamp_nanos(nanos.into())
+        .expect("i64 nanoseconds are always in range")
+}
+
+fn is_primary_key_violation(err: &SqlError) -> bool {
+    err.sqlite_error()
+        .is_some_and(|err| err.extended_code == ffi::SQLITE_CONSTRAINT_PRIMARYKEY)
+}
+
+/// Reads an id back from the `id` column, in either of the forms
+/// [`SqliteStore::id_value`] writes.
+fn parse_id(value: ValueRef<'_>) -> Result<Id, SqliteStoreError> {
+    let id = match value {
+        ValueRef::Text(text) => std::str::from_utf8(text)
+            .ok()
+            .and_then(|text| text.parse().ok()),
+        ValueRef::Blob(bytes) => bytes
+            .try_into()
+            .ok()
+            .map(|bytes| Id(i128::from_le_bytes(bytes))),
+        _ => None,
+    };
+
+    id.ok_or_else(|| {
+        let shown = match value {
+            ValueRef::Null => "null".to_owned(),
+            ValueRef::Integer(n) => n.to_string(),
+            ValueRef::Real(n) => n.to_string(),
+            ValueRef::Text(text) => format!("{:?}", String::from_utf8_lossy(text)),
+            ValueRef::Blob(bytes) => {
+                let hex: String = bytes.iter().map(|byte| format!("{byte:02x}")).collect();
+                format!("x'{hex}'")
+            }
+        };
+
+        SqliteStoreError::InvalidId(shown)
+    })
+}
+
+/// A digest of `id` for span fields, so traces can be correlated without
+/// leaking usable session ids into logs.
+///
+/// This is 64-bit FNV-1a over the id's bytes rather than std's hasher, whose
+/// algorithm may change between Rust releases, so the digest of a session
+/// stays the same across processes and builds.
+#[cfg(feature = "tracing")]
+fn id_hash(id: &Id) -> String {
+    let hash =
+        id.0.to_le_bytes()
+            .iter()
+            .fold(0xcbf2_9ce4_8422_2325_u64, |hash, &byte| {
+                (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+            });
+    format!("{hash:016x}")
+}
+
+/// Builds the `?, ?, ?` placeholder list for an `in (...)` clause.
+fn placeholders(n: usize) -> String {
+    vec!["?"; n].join(", ")
+}
+
+fn is_valid_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+#[async_trait]
+impl<M> SessionStore for SqliteStore<M>
+where
+    M: ManageConnection<Connection = Connection> + fmt::Debug,
+{
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            name = "session.create",
+            skip_all,
+            fields(id_hash = tracing::field::Empty),
+            err
+        )
+    )]
+    async fn create(&self, record: &mut Record) -> session_store::Result<()> {
+        let mut candidate = record.clone();
+
+        record.id = self
+            .run_blocking(move |store| {
+                store.observed(
+                    Operation::Create,
+                    || store.retrying(|| store.create_with_conn(&mut candidate)),
+                    |observer, _, elapsed| observer.on_create(elapsed),
+                )?;
+                Ok(candidate.id)
+            })
+            .await?;
+
+        #[cfg(feature = "tracing")]
+        tracing::Span::current().record("id_hash", id_hash(&record.id));
+
+        Ok(())
+    }
+
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            name = "session.save",
+            skip_all,
+            fields(id_hash = id_hash(&record.id)),
+            err
+        )
+    )]
+    async fn save(&self, record: &Record) -> session_store::Result<()> {
+        let record = record.clone();
+
+        self.run_blocking(move |store| store.observed_save(&record))
+            .await?;
+
+        Ok(())
+    }
+
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            name = "session.load",
+            skip_all,
+            fields(id_hash = id_hash(session_id), hit = tracing::field::Empty),
+            err
+        )
+    )]
+    async fn load(&self, session_id: &Id) -> session_store::Result<Option<Record>> {
+        let session_id = *session_id;
+
+        let record = self
+            .run_blocking(move |store| {
+                store.observed(
+                    Operation::Load,
+                    || store.load_with_conn(&session_id),
+                    |observer, record, elapsed| observer.on_load(record.is_some(), elapsed),
+                )
+            })
+            .await?;
+
+        #[cfg(feature = "tracing")]
+        tracing::Span::current().record("hit", record.is_some());
+
+        Ok(record)
+    }
+
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            name = "session.delete",
+            skip_all,
+            fields(id_hash = id_hash(session_id)),
+            err
+        )
+    )]
+    async fn delete(&self, session_id: &Id) -> session_store::Result<()> {
+        let session_id = *session_id;
+
+        self.run_blocking(move |store| store.observed_delete(&session_id))
+            .await?;
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl<M> ExpiredDeletion for SqliteStore<M>
+where
+    M: ManageConnection<Connection = Connection> + fmt::Debug,
+{
+    async fn delete_expired(&self) -> session_store::Result<()> {
+        self.run_blocking(|store| store.observed_delete_expired())
+            .await?;
+
+        Ok(())
+    }
+
+    /// Deletes expired sessions every `period` until a hard error occurs,
+    /// which is returned.
+    ///
+    /// Each sweep deletes in transactions of up to
+    /// [`batch_size`](SqliteStore::with_batch_size) rows, as
+    /// [`delete_expired_limited`](SqliteStore::delete_expired_limited) does,
+    /// until none are left, so a large backlog never holds the write lock for
+    /// long.
+    ///
+    /// A sweep that fails with `SQLITE_BUSY` or `SQLITE_LOCKED`, or runs past
+    /// the [operation timeout](SqliteStore::with_operation_timeout), is skipped
+    /// and retried on the next tick instead of ending the loop.
+    ///
+    /// The loop never completes on its own, so it is meant to be spawned:
+    ///
+    /// ```rust,ignore
+    /// let deletion_task = tokio::task::spawn(
+    ///     store
+    ///         .clone()
+    ///         .continuously_delete_expired(tokio::time::Duration::from_secs(60)),
+    /// );
+    /// ```
+    ///
+    /// Stopping it means dropping the future: call `deletion_task.abort()`.
+    /// Merely dropping the `JoinHandle` detaches the task and leaves it running.
+    async fn continuously_delete_expired(
+        self,
+        period: tokio::time::Duration,
+    ) -> session_store::Result<()> {
+        let mut interval = tokio::time::interval(period);
+        interval.tick().await; // The first tick completes immediately; skip.
+
+        loop {
+            interval.tick().await;
+
+            match self
+                .run_blocking(|store| store.observed_delete_expired_in_batches())
+                .await
+            {
+                Err(err)
+                    if err.is_retryable()
+                        || matches!(
+                            err,
+                            SqliteStoreError::Timeout(_) | SqliteStoreError::Interrupted(_)
+                        ) =>
+                {
+                    continue;
+                }
+                res => res?,
+            }
+        }
+    }
 }
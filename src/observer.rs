@@ -0,0 +1,92 @@
+use std::{fmt::Debug, time::Duration};
+
+use tower_sessions_core::session::Id;
+
+use crate::SqliteStoreError;
+
+/// A store operation, as reported to [`StoreObserver::on_error`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum Operation {
+    Create,
+    Save,
+    Load,
+    Delete,
+    DeleteExpired,
+}
+
+/// A change to a stored session, as passed to a
+/// [change listener](crate::SqliteStore::with_change_listener).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum SessionEvent {
+    Created(Id),
+    Saved(Id),
+    Deleted(Id),
+    /// The session was removed because it had expired.
+    Expired(Id),
+}
+
+/// Receives the outcome and duration of each session store operation, e.g. to
+/// feed metrics; attach one with
+/// [`SqliteStore::with_observer`](crate::SqliteStore::with_observer).
+///
+/// Every method defaults to doing nothing. Durations cover checking out a
+/// connection and any [retries](crate::SqliteStore::with_retry), and the
+/// methods are called from tokio's blocking thread pool.
+///
+/// ```rust
+/// use std::{
+///     sync::{
+///         Arc,
+///         atomic::{AtomicU64, Ordering},
+///     },
+///     time::Duration,
+/// };
+///
+/// use tower_sessions_core::{SessionStore, session::Id};
+/// use tower_sessions_r2d2_sqlite_store::{SqliteStore, StoreObserver};
+///
+/// #[derive(Debug, Default)]
+/// struct Counters {
+///     hits: AtomicU64,
+///     misses: AtomicU64,
+/// }
+///
+/// #[derive(Debug)]
+/// struct Counting(Arc<Counters>);
+///
+/// impl StoreObserver for Counting {
+///     fn on_load(&self, hit: bool, _elapsed: Duration) {
+///         let counter = if hit { &self.0.hits } else { &self.0.misses };
+///         counter.fetch_add(1, Ordering::Relaxed);
+///     }
+/// }
+///
+/// # tokio::runtime::Builder::new_current_thread().build()?.block_on(async {
+/// let counters = Arc::new(Counters::default());
+/// let store = SqliteStore::in_memory()?.with_observer(Counting(counters.clone()));
+/// store.migrate()?;
+///
+/// assert!(store.load(&Id::default()).await?.is_none());
+/// assert_eq!(counters.misses.load(Ordering::Relaxed), 1);
+/// assert_eq!(counters.hits.load(Ordering::Relaxed), 0);
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// # })?;
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+pub trait StoreObserver: Debug + Send + Sync + 'static {
+    fn on_create(&self, _elapsed: Duration) {}
+
+    fn on_save(&self, _elapsed: Duration) {}
+
+    /// `hit` is whether a live session was found.
+    fn on_load(&self, _hit: bool, _elapsed: Duration) {}
+
+    fn on_delete(&self, _elapsed: Duration) {}
+
+    fn on_delete_expired(&self, _elapsed: Duration) {}
+
+    /// Called instead of the operation's own method when it fails.
+    fn on_error(&self, _op: Operation, _err: &SqliteStoreError) {}
+}
@@ -0,0 +1,181 @@
+use std::time::Duration;
+
+use r2d2_sqlite::rusqlite::{Connection, DatabaseName, Error as SqlError};
+
+/// Connection settings applied to every pooled connection when it is opened.
+///
+/// The defaults suit a session store sharing its database with other
+/// writers: WAL journaling, `synchronous = NORMAL` and a five second busy
+/// timeout. Fields may be added in later versions, so start from the
+/// defaults and change what you need with the `with_*` methods:
+///
+/// ```rust
+/// use std::time::Duration;
+/// use tower_sessions_r2d2_sqlite_store::{PragmaConfig, Synchronous};
+///
+/// let pragmas = PragmaConfig::default()
+///     .with_busy_timeout(Duration::from_secs(1))
+///     .with_synchronous(Synchronous::Full)
+///     .with_cache_size(-16_384);
+///
+/// assert_eq!(pragmas.busy_timeout, Duration::from_secs(1));
+/// assert_eq!(pragmas.cache_size, Some(-16_384));
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct PragmaConfig {
+    /// How long a statement waits on a locked database before failing with
+    /// `SQLITE_BUSY`.
+    pub busy_timeout: Duration,
+    pub journal_mode: JournalMode,
+    pub synchronous: Synchronous,
+    /// Has SQLite overwrite deleted content with zeros, so expired and
+    /// deleted session data doesn't linger in free pages.
+    ///
+    /// Every delete and update then writes the freed content back out, adding
+    /// I/O roughly in proportion to the size of the removed rows.
+    pub secure_delete: bool,
+    /// Enforces foreign key constraints, e.g. for tables that reference the
+    /// session table. SQLite leaves them off by default.
+    pub foreign_keys: bool,
+    /// The database page size in bytes, a power of two from 512 to 65536.
+    ///
+    /// Only takes effect on a fresh database, before anything is written to
+    /// it; an existing database keeps the page size it was created with. It
+    /// is set ahead of the journal mode, since WAL fixes it as well.
+    pub page_size: Option<u32>,
+    /// How much of the database each connection caches: a number of pages
+    /// if positive, or a size in KiB if negative, as with
+    /// `pragma cache_size`. A cache large enough for the session table's
+    /// indexes keeps lookups off the disk.
+    pub cache_size: Option<i64>,
+}
+
+impl Default for PragmaConfig {
+    fn default() -> Self {
+        Self {
+            busy_timeout: Duration::from_secs(5),
+            journal_mode: JournalMode::Wal,
+            synchronous: Synchronous::Normal,
+            secure_delete: false,
+            foreign_keys: false,
+            page_size: None,
+            cache_size: None,
+        }
+    }
+}
+
+impl PragmaConfig {
+    /// Sets [`busy_timeout`](Self::busy_timeout).
+    pub fn with_busy_timeout(mut self, busy_timeout: Duration) -> Self {
+        self.busy_timeout = busy_timeout;
+        self
+    }
+
+    /// Sets [`journal_mode`](Self::journal_mode).
+    pub fn with_journal_mode(mut self, journal_mode: JournalMode) -> Self {
+        self.journal_mode = journal_mode;
+        self
+    }
+
+    /// Sets [`synchronous`](Self::synchronous).
+    pub fn with_synchronous(mut self, synchronous: Synchronous) -> Self {
+        self.synchronous = synchronous;
+        self
+    }
+
+    /// Sets [`secure_delete`](Self::secure_delete).
+    pub fn with_secure_delete(mut self, secure_delete: bool) -> Self {
+        self.secure_delete = secure_delete;
+        self
+    }
+
+    /// Sets [`foreign_keys`](Self::foreign_keys).
+    pub fn with_foreign_keys(mut self, foreign_keys: bool) -> Self {
+        self.foreign_keys = foreign_keys;
+        self
+    }
+
+    /// Sets [`page_size`](Self::page_size).
+    pub fn with_page_size(mut self, page_size: u32) -> Self {
+        self.page_size = Some(page_size);
+        self
+    }
+
+    /// Sets [`cache_size`](Self::cache_size).
+    pub fn with_cache_size(mut self, cache_size: i64) -> Self {
+        self.cache_size = Some(cache_size);
+        self
+    }
+
+    /// Applies the settings to `conn`, covering the database attached as
+    /// `attached` as well as `main`, since SQLite applies most of these
+    /// pragmas to a single schema.
+    pub(crate) fn apply(&self, conn: &Connection, attached: Option<&str>) -> Result<(), SqlError> {
+        conn.busy_timeout(self.busy_timeout)?;
+        conn.pragma_update(None, "foreign_keys", self.foreign_keys)?;
+
+        let schemas =
+            std::iter::once(DatabaseName::Main).chain(attached.map(DatabaseName::Attached));
+        for schema in schemas {
+            if let Some(page_size) = self.page_size {
+                conn.pragma_update(Some(schema), "page_size", page_size)?;
+            }
+            conn.pragma_update(Some(schema), "journal_mode", self.journal_mode.as_str())?;
+            conn.pragma_update(Some(schema), "synchronous", self.synchronous.as_str())?;
+            conn.pragma_update(Some(schema), "secure_delete", self.secure_delete)?;
+            if let Some(cache_size) = self.cache_size {
+                conn.pragma_update(Some(schema), "cache_size", cache_size)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Values of `pragma journal_mode`.
+///
+/// In-memory databases only support `MEMORY` and `OFF`, and silently keep
+/// `MEMORY` when asked for anything else.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum JournalMode {
+    Delete,
+    Truncate,
+    Persist,
+    Memory,
+    Wal,
+    Off,
+}
+
+impl JournalMode {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Delete => "DELETE",
+            Self::Truncate => "TRUNCATE",
+            Self::Persist => "PERSIST",
+            Self::Memory => "MEMORY",
+            Self::Wal => "WAL",
+            Self::Off => "OFF",
+        }
+    }
+}
+
+/// Values of `pragma synchronous`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Synchronous {
+    Off,
+    Normal,
+    Full,
+    Extra,
+}
+
+impl Synchronous {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Off => "OFF",
+            Self::Normal => "NORMAL",
+            Self::Full => "FULL",
+            Self::Extra => "EXTRA",
+        }
+    }
+}
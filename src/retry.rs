@@ -0,0 +1,107 @@
+use std::time::Duration;
+
+use crate::SqliteStoreError;
+
+/// How writes are retried when SQLite reports the database as busy or
+/// locked; see [`SqliteStore::with_retry`](crate::SqliteStore::with_retry).
+///
+/// Attempt `n` waits `base_delay * 2^(n - 1)` before running again. The
+/// default makes up to 3 attempts starting from a 10ms delay.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Total attempts, including the first one.
+    pub max_attempts: u32,
+    pub base_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            base_delay: Duration::from_millis(10),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Runs `op` until it succeeds, fails with an error that isn't
+    /// [retryable](SqliteStoreError::is_retryable), or runs out of attempts.
+    ///
+    /// Sleeps the current thread between attempts, so it must only be used
+    /// off the async runtime.
+    pub(crate) fn run<T>(
+        &self,
+        mut op: impl FnMut() -> Result<T, SqliteStoreError>,
+    ) -> Result<T, SqliteStoreError> {
+        let mut attempt = 1;
+
+        loop {
+            match op() {
+                Err(err) if err.is_retryable() && attempt < self.max_attempts => {
+                    std::thread::sleep(self.base_delay.saturating_mul(1 << (attempt - 1).min(16)));
+                    attempt += 1;
+                }
+                res => return res,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use r2d2_sqlite::rusqlite::{Error as SqlError, ffi};
+
+    use super::*;
+
+    fn busy() -> SqliteStoreError {
+        SqlError::SqliteFailure(ffi::Error::new(ffi::SQLITE_BUSY), None).into()
+    }
+
+    #[test]
+    fn retries_busy_errors_with_growing_delays() {
+        let policy = RetryPolicy {
+            max_attempts: 4,
+            base_delay: Duration::from_millis(5),
+        };
+
+        let mut attempts = 0;
+        let start = Instant::now();
+        let res = policy.run(|| {
+            attempts += 1;
+            if attempts < 3 {
+                Err(busy())
+            } else {
+                Ok(attempts)
+            }
+        });
+
+        assert_eq!(res.unwrap(), 3);
+        assert!(start.elapsed() >= Duration::from_millis(5 + 10));
+    }
+
+    #[test]
+    fn stops_after_the_last_attempt() {
+        let mut attempts = 0;
+        let res = RetryPolicy::default().run(|| -> Result<(), _> {
+            attempts += 1;
+            Err(busy())
+        });
+
+        assert!(res.unwrap_err().is_retryable());
+        assert_eq!(attempts, 3);
+    }
+
+    #[test]
+    fn never_retries_other_errors() {
+        let mut attempts = 0;
+        let res = RetryPolicy::default().run(|| -> Result<(), _> {
+            attempts += 1;
+            Err(SqliteStoreError::ReadOnly)
+        });
+
+        assert!(matches!(res, Err(SqliteStoreError::ReadOnly)));
+        assert_eq!(attempts, 1);
+    }
+}
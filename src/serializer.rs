@@ -0,0 +1,120 @@
+use std::fmt::Debug;
+
+use tower_sessions_core::session::Record;
+
+use crate::SqliteStoreError;
+
+/// Converts session records to and from the bytes kept in the `data` column.
+///
+/// Implementations that don't produce `rmp_serde` errors should report
+/// failures through [`SqliteStoreError::Serialize`] and
+/// [`SqliteStoreError::Deserialize`].
+///
+/// The store marks compressed and encrypted blobs with a leading byte from
+/// `0x00` to `0x1f`, so it has to tell those apart from plain serializer
+/// output. Unless [`needs_format_tag`](Self::needs_format_tag) says
+/// otherwise, every record is therefore stored behind a one-byte format tag,
+/// which [`decode`](Self::decode) never sees.
+pub trait SessionSerializer: Debug + Send + Sync + 'static {
+    fn encode(&self, record: &Record) -> Result<Vec<u8>, SqliteStoreError>;
+
+    fn decode(&self, bytes: &[u8]) -> Result<Record, SqliteStoreError>;
+
+    /// Whether encoded records have to be stored behind a format tag.
+    ///
+    /// Only return `false` if [`encode`](Self::encode) never produces output
+    /// starting with a byte from `0x00` to `0x1f`, as with MessagePack arrays
+    /// and JSON objects. Formats such as bincode, whose output starts with
+    /// whatever the record's first field holds, need the tag.
+    fn needs_format_tag(&self) -> bool {
+        true
+    }
+}
+
+/// The default serializer, writing records as MessagePack with `rmp_serde`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MessagePackSerializer;
+
+impl SessionSerializer for MessagePackSerializer {
+    fn encode(&self, record: &Record) -> Result<Vec<u8>, SqliteStoreError> {
+        Ok(rmp_serde::to_vec(record)?)
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<Record, SqliteStoreError> {
+        Ok(rmp_serde::from_slice(bytes)?)
+    }
+
+    // Records encode as arrays, which start at 0x90 or above.
+    fn needs_format_tag(&self) -> bool {
+        false
+    }
+}
+
+/// Writes records as JSON with `serde_json`, so stored sessions can be read
+/// with `json_extract` and other SQLite tooling. The default with the `json`
+/// feature.
+///
+/// Rows that don't start with `{` are decoded as MessagePack, so sessions
+/// written before switching keep loading.
+#[cfg(feature = "json")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct JsonSerializer;
+
+#[cfg(feature = "json")]
+impl SessionSerializer for JsonSerializer {
+    fn encode(&self, record: &Record) -> Result<Vec<u8>, SqliteStoreError> {
+        Ok(serde_json::to_vec(record)?)
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<Record, SqliteStoreError> {
+        match bytes.first() {
+            Some(b'{') => Ok(serde_json::from_slice(bytes)?),
+            _ => MessagePackSerializer.decode(bytes),
+        }
+    }
+
+    fn needs_format_tag(&self) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use time::{Duration, OffsetDateTime};
+    use tower_sessions_core::session::Id;
+
+    use super::*;
+
+    fn record() -> Record {
+        Record {
+            id: Id::default(),
+            data: [("user".to_owned(), serde_json::json!("alice"))].into(),
+            expiry_date: OffsetDateTime::now_utc() + Duration::hours(1),
+        }
+    }
+
+    #[test]
+    fn message_pack_round_trips_without_a_tag() {
+        let record = record();
+        let bytes = MessagePackSerializer.encode(&record).unwrap();
+
+        assert!(bytes[0] >= 0x90);
+        assert!(!MessagePackSerializer.needs_format_tag());
+        assert_eq!(MessagePackSerializer.decode(&bytes).unwrap(), record);
+        assert!(MessagePackSerializer.decode(b"\xc1").is_err());
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn json_round_trips_and_reads_message_pack() {
+        let record = record();
+        let bytes = JsonSerializer.encode(&record).unwrap();
+
+        assert_eq!(bytes[0], b'{');
+        assert!(!JsonSerializer.needs_format_tag());
+        assert_eq!(JsonSerializer.decode(&bytes).unwrap(), record);
+
+        let legacy = MessagePackSerializer.encode(&record).unwrap();
+        assert_eq!(JsonSerializer.decode(&legacy).unwrap(), record);
+    }
+}
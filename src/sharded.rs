@@ -0,0 +1,255 @@
+use std::{fmt, future::Future, sync::Arc};
+
+use async_trait::async_trait;
+use r2d2::ManageConnection;
+use r2d2_sqlite::{SqliteConnectionManager, rusqlite::Connection};
+use tokio::task::JoinSet;
+use tower_sessions_core::{
+    ExpiredDeletion, SessionStore,
+    session::{Id, Record},
+    session_store,
+};
+
+use crate::{MAX_CREATE_ATTEMPTS, Operation, SqliteStore, SqliteStoreError};
+
+/// Spreads sessions over several [`SqliteStore`]s, e.g. one per database
+/// file, so writes to different sessions don't all queue for one file's
+/// write lock.
+///
+/// Each session lives in the shard picked by its id modulo the number of
+/// shards. That mapping is fixed: changing the number or order of shards
+/// strands existing sessions in shards their ids no longer route to.
+/// Operations on a single session go to its shard only; [`count`](Self::count),
+/// [`clear`](Self::clear) and [`delete_expired`](ExpiredDeletion::delete_expired)
+/// run on every shard concurrently.
+pub struct ShardedSqliteStore<M = SqliteConnectionManager>
+where
+    M: ManageConnection<Connection = Connection>,
+{
+    shards: Arc<[SqliteStore<M>]>,
+}
+
+// Derived `Clone` would require `M: Clone`.
+impl<M> Clone for ShardedSqliteStore<M>
+where
+    M: ManageConnection<Connection = Connection>,
+{
+    fn clone(&self) -> Self {
+        Self {
+            shards: self.shards.clone(),
+        }
+    }
+}
+
+impl<M> fmt::Debug for ShardedSqliteStore<M>
+where
+    M: ManageConnection<Connection = Connection> + fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ShardedSqliteStore")
+            .field("shards", &self.shards)
+            .finish()
+    }
+}
+
+impl<M> ShardedSqliteStore<M>
+where
+    M: ManageConnection<Connection = Connection>,
+{
+    /// Routes sessions over `shards`, which should each be configured the
+    /// same way and point at a different database.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `shards` is empty.
+    pub fn new(shards: Vec<SqliteStore<M>>) -> Self {
+        assert!(
+            !shards.is_empty(),
+            "a sharded store needs at least one shard"
+        );
+
+        Self {
+            shards: shards.into(),
+        }
+    }
+
+    /// The shards, in routing order.
+    pub fn shards(&self) -> &[SqliteStore<M>] {
+        &self.shards
+    }
+
+    /// The shard session `id` is stored in.
+    pub fn shard_for(&self, id: &Id) -> &SqliteStore<M> {
+        // Ids are random, so their low bits spread sessions evenly, and unlike
+        // a `Hasher` the mapping can't change between Rust releases.
+        let index = (id.0 as u128 % self.shards.len() as u128) as usize;
+        &self.shards[index]
+    }
+
+    /// [Migrates](SqliteStore::migrate) every shard.
+    pub fn migrate(&self) -> session_store::Result<()> {
+        self.shards.iter().try_for_each(SqliteStore::migrate)
+    }
+
+    /// Returns the number of stored sessions across all shards, expired or
+    /// not.
+    pub async fn count(&self) -> session_store::Result<i64> {
+        let counts = self
+            .on_every_shard(|shard| async move { shard.count().await })
+            .await?;
+
+        Ok(counts.into_iter().sum())
+    }
+
+    /// Deletes every session in every shard.
+    pub async fn clear(&self) -> session_store::Result<()> {
+        self.on_every_shard(|shard| async move { shard.clear().await })
+            .await?;
+
+        Ok(())
+    }
+
+    /// Runs `op` on all shards at once, failing with the first error.
+    async fn on_every_shard<F, Fut, T>(&self, op: F) -> session_store::Result<Vec<T>>
+    where
+        F: Fn(SqliteStore<M>) -> Fut,
+        Fut: Future<Output = session_store::Result<T>> + Send + 'static,
+        T: Send + 'static,
+    {
+        let mut tasks = JoinSet::new();
+
+        for shard in self.shards.iter() {
+            tasks.spawn(op(shard.clone()));
+        }
+
+        let mut results = Vec::with_capacity(self.shards.len());
+
+        while let Some(res) = tasks.join_next().await {
+            results.push(res.map_err(SqliteStoreError::from)??);
+        }
+
+        Ok(results)
+    }
+}
+
+#[async_trait]
+impl<M> SessionStore for ShardedSqliteStore<M>
+where
+    M: ManageConnection<Connection = Connection> + fmt::Debug,
+{
+    async fn create(&self, record: &mut Record) -> session_store::Result<()> {
+        // A shard picking a fresh id on a collision could move the session
+        // out of the shard that id routes to, so collisions are resolved
+        // here instead.
+        for _ in 0..MAX_CREATE_ATTEMPTS {
+            let candidate = record.clone();
+
+            let res = self
+                .shard_for(&record.id)
+                .run_blocking(move |store| {
+                    store.observed(
+                        Operation::Create,
+                        || store.retrying(|| store.create_unchecked_with_conn(&candidate)),
+                        |observer, _, elapsed| observer.on_create(elapsed),
+                    )
+                })
+                .await;
+
+            match res {
+                Err(SqliteStoreError::DuplicateId(_)) => record.id = Id::default(),
+                res => return Ok(res?),
+            }
+        }
+
+        Err(SqliteStoreError::IdCollisionExhausted(MAX_CREATE_ATTEMPTS).into())
+    }
+
+    async fn save(&self, record: &Record) -> session_store::Result<()> {
+        self.shard_for(&record.id).save(record).await
+    }
+
+    async fn load(&self, session_id: &Id) -> session_store::Result<Option<Record>> {
+        self.shard_for(session_id).load(session_id).await
+    }
+
+    async fn delete(&self, session_id: &Id) -> session_store::Result<()> {
+        self.shard_for(session_id).delete(session_id).await
+    }
+}
+
+#[async_trait]
+impl<M> ExpiredDeletion for ShardedSqliteStore<M>
+where
+    M: ManageConnection<Connection = Connection> + fmt::Debug,
+{
+    async fn delete_expired(&self) -> session_store::Result<()> {
+        self.on_every_shard(|shard| async move { shard.delete_expired().await })
+            .await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use time::{Duration, OffsetDateTime};
+
+    use super::*;
+
+    fn record(expires_in: Duration) -> Record {
+        Record {
+            id: Id::default(),
+            data: HashMap::from([("user".to_owned(), "alice".into())]),
+            expiry_date: OffsetDateTime::now_utc() + expires_in,
+        }
+    }
+
+    fn sharded(shards: usize) -> ShardedSqliteStore {
+        let store = ShardedSqliteStore::new(
+            (0..shards)
+                .map(|_| SqliteStore::in_memory().unwrap())
+                .collect(),
+        );
+        store.migrate().unwrap();
+        store
+    }
+
+    #[tokio::test]
+    async fn routes_sessions_to_their_shard_and_fans_out() {
+        let store = sharded(4);
+
+        let mut records = Vec::new();
+        for _ in 0..40 {
+            let mut record = record(Duration::hours(1));
+            store.create(&mut record).await.unwrap();
+            records.push(record);
+        }
+        let expired = record(-Duration::hours(1));
+        store.save(&expired).await.unwrap();
+
+        for record in &records {
+            assert_eq!(store.load(&record.id).await.unwrap().as_ref(), Some(record));
+            let shard = store.shard_for(&record.id);
+            assert_eq!(shard.load(&record.id).await.unwrap().as_ref(), Some(record));
+        }
+
+        let mut per_shard = Vec::new();
+        for shard in store.shards() {
+            per_shard.push(shard.count().await.unwrap());
+        }
+        assert!(per_shard.iter().all(|&count| count > 0), "{per_shard:?}");
+        assert_eq!(per_shard.iter().sum::<i64>(), 41);
+        assert_eq!(store.count().await.unwrap(), 41);
+
+        store.delete_expired().await.unwrap();
+        assert_eq!(store.count().await.unwrap(), 40);
+        store.delete(&records[0].id).await.unwrap();
+        assert_eq!(store.load(&records[0].id).await.unwrap(), None);
+        assert_eq!(store.count().await.unwrap(), 39);
+
+        store.clear().await.unwrap();
+        assert_eq!(store.count().await.unwrap(), 0);
+    }
+}
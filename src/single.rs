@@ -0,0 +1,127 @@
+use std::{fmt, sync::Mutex};
+
+use r2d2::ManageConnection;
+use r2d2_sqlite::rusqlite::{Connection, Error as SqlError, ffi};
+
+use crate::{SqliteStore, SqliteStoreError};
+
+/// A store running every operation over one existing connection; see
+/// [`SqliteStore::from_connection`].
+pub type SingleConnStore = SqliteStore<SingleConnectionManager>;
+
+/// Hands out a single connection the caller opened, and never opens another.
+///
+/// Once that connection is gone, e.g. because it was found broken, every
+/// checkout fails.
+pub struct SingleConnectionManager {
+    conn: Mutex<Option<Connection>>,
+}
+
+impl SingleConnectionManager {
+    pub fn new(conn: Connection) -> Self {
+        Self {
+            conn: Mutex::new(Some(conn)),
+        }
+    }
+}
+
+impl fmt::Debug for SingleConnectionManager {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SingleConnectionManager")
+    }
+}
+
+impl ManageConnection for SingleConnectionManager {
+    type Connection = Connection;
+    type Error = SqlError;
+
+    fn connect(&self) -> Result<Connection, SqlError> {
+        let conn = self
+            .conn
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .take();
+
+        conn.ok_or_else(|| {
+            SqlError::SqliteFailure(
+                ffi::Error::new(ffi::SQLITE_CANTOPEN),
+                Some("the store's single connection is gone".to_owned()),
+            )
+        })
+    }
+
+    fn is_valid(&self, conn: &mut Connection) -> Result<(), SqlError> {
+        conn.execute_batch("")
+    }
+
+    fn has_broken(&self, _: &mut Connection) -> bool {
+        false
+    }
+}
+
+impl SqliteStore<SingleConnectionManager> {
+    /// Creates a store that runs every operation over `conn`, for apps that
+    /// already hold a connection and have no use for a pool.
+    ///
+    /// Operations take turns on the connection, so only one runs at a time;
+    /// a second one waits for the first, up to r2d2's default 30 second
+    /// connection timeout.
+    pub fn from_connection(conn: Connection) -> Result<Self, SqliteStoreError> {
+        // The connection can't be replaced, so it must never be retired for
+        // age or idleness.
+        let pool = r2d2::Pool::builder()
+            .max_size(1)
+            .max_lifetime(None)
+            .idle_timeout(None)
+            .test_on_check_out(false)
+            .build(SingleConnectionManager::new(conn))?;
+
+        Ok(Self::new(pool))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use time::{Duration, OffsetDateTime};
+    use tower_sessions_core::{
+        SessionStore,
+        session::{Id, Record},
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn runs_a_full_session_cycle_on_the_given_connection() {
+        let conn = Connection::open_in_memory().unwrap();
+        // Temporary tables are only visible to the connection that made them.
+        conn.execute_batch("create temp table marker (x)").unwrap();
+
+        let store = SqliteStore::from_connection(conn).unwrap();
+        store.migrate().unwrap();
+        store
+            .pool()
+            .get()
+            .unwrap()
+            .execute_batch("select * from temp.marker")
+            .unwrap();
+
+        let mut record = Record {
+            id: Id::default(),
+            data: Default::default(),
+            expiry_date: OffsetDateTime::now_utc() + Duration::hours(1),
+        };
+        store.create(&mut record).await.unwrap();
+
+        // Concurrent operations wait their turn on the one connection.
+        let loads = (0..8).map(|_| {
+            let store = store.clone();
+            tokio::spawn(async move { store.load(&record.id).await })
+        });
+        for load in loads {
+            assert_eq!(load.await.unwrap().unwrap(), Some(record.clone()));
+        }
+
+        store.delete(&record.id).await.unwrap();
+        assert_eq!(store.load(&record.id).await.unwrap(), None);
+    }
+}
@@ -0,0 +1,3886 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicUsize, Ordering},
+    },
+};
+
+use r2d2_sqlite::{
+    SqliteConnectionManager,
+    rusqlite::{Connection, OptionalExtension},
+};
+use time::{Duration, OffsetDateTime};
+use tower_sessions_core::{
+    ExpiredDeletion, SessionStore,
+    session::{Id, Record},
+};
+
+use crate::{Clock, DedupKeep, SqliteStore, unix_nanos};
+
+/// A database file in the temp directory, removed along with its journals
+/// when dropped.
+struct TempDb(PathBuf);
+
+impl TempDb {
+    fn new() -> Self {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+
+        let name = format!(
+            "tower-sessions-r2d2-sqlite-store-{}-{}.db",
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        );
+        let db = Self(std::env::temp_dir().join(name));
+        db.remove();
+        db
+    }
+
+    fn path(&self) -> &Path {
+        &self.0
+    }
+
+    fn remove(&self) {
+        for suffix in ["", "-wal", "-shm", "-journal"] {
+            let mut path = self.0.clone().into_os_string();
+            path.push(suffix);
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+impl Drop for TempDb {
+    fn drop(&mut self) {
+        self.remove();
+    }
+}
+
+/// A clock that only moves when told to.
+#[derive(Clone, Debug)]
+struct ManualClock(Arc<Mutex<OffsetDateTime>>);
+
+impl ManualClock {
+    fn new() -> Self {
+        Self(Arc::new(Mutex::new(OffsetDateTime::now_utc())))
+    }
+
+    fn advance(&self, by: Duration) {
+        *self.0.lock().unwrap() += by;
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> OffsetDateTime {
+        *self.0.lock().unwrap()
+    }
+}
+
+fn record(expires_in: Duration) -> Record {
+    let mut data = std::collections::HashMap::new();
+    data.insert("user".to_owned(), serde_json::json!("alice"));
+
+    Record {
+        id: Id::default(),
+        data,
+        expiry_date: OffsetDateTime::now_utc() + expires_in,
+    }
+}
+
+fn store() -> SqliteStore {
+    // A single connection, since each in-memory connection is a database of
+    // its own.
+    let pool = r2d2::Pool::builder()
+        .max_size(1)
+        .build(SqliteConnectionManager::memory())
+        .unwrap();
+    let store = SqliteStore::new(pool);
+    store.migrate().unwrap();
+    store
+}
+
+fn columns(conn: &Connection, table: &str) -> Vec<String> {
+    conn.prepare("select name from pragma_table_info(?1)")
+        .unwrap()
+        .query_map([table], |row| row.get(0))
+        .unwrap()
+        .collect::<Result<_, _>>()
+        .unwrap()
+}
+
+fn row_count(store: &SqliteStore) -> i64 {
+    store
+        .pool
+        .get()
+        .unwrap()
+        .query_row("select count(*) from tower_sessions", [], |row| row.get(0))
+        .unwrap()
+}
+
+fn stored_data(store: &SqliteStore, id: &Id) -> Vec<u8> {
+    store
+        .pool
+        .get()
+        .unwrap()
+        .query_row(
+            "select cast(data as blob) from tower_sessions where id = ?1",
+            [id.to_string()],
+            |row| row.get(0),
+        )
+        .unwrap()
+}
+
+fn stored_expiry(store: &SqliteStore, id: &Id) -> Option<i64> {
+    store
+        .pool
+        .get()
+        .unwrap()
+        .query_row(
+            "select expiry_date from tower_sessions where id = ?1",
+            [id.to_string()],
+            |row| row.get(0),
+        )
+        .optional()
+        .unwrap()
+}
+
+#[tokio::test]
+async fn delete_expired_removes_only_expired_sessions() {
+    let store = store();
+    let live = record(Duration::hours(1));
+    let expired = [record(Duration::seconds(-1)), record(Duration::days(-30))];
+    store.save(&live).await.unwrap();
+    for record in &expired {
+        store.save(record).await.unwrap();
+    }
+
+    store.delete_expired().await.unwrap();
+
+    assert_eq!(row_count(&store), 1);
+    assert_eq!(store.load(&live.id).await.unwrap(), Some(live));
+    for record in &expired {
+        assert_eq!(stored_expiry(&store, &record.id), None);
+    }
+
+    // Nothing left to delete is fine too.
+    store.delete_expired().await.unwrap();
+    assert_eq!(row_count(&store), 1);
+}
+
+/// A store over a file in WAL mode that fails with `SQLITE_BUSY` right away
+/// instead of waiting for locks.
+fn impatient_store(db: &TempDb) -> SqliteStore {
+    SqliteStore::builder()
+        .path(db.path())
+        .with_pragmas(crate::PragmaConfig {
+            busy_timeout: std::time::Duration::ZERO,
+            ..Default::default()
+        })
+        .auto_migrate()
+        .build()
+        .unwrap()
+}
+
+/// Opens a second connection to `db` holding its write lock.
+fn lock_for_writing(db: &TempDb) -> Connection {
+    let conn = Connection::open(db.path()).unwrap();
+    conn.execute_batch("begin immediate").unwrap();
+    conn
+}
+
+#[tokio::test(start_paused = true)]
+async fn continuously_delete_expired_sweeps_once_per_period() {
+    let store = store();
+    store.save(&record(Duration::seconds(-1))).await.unwrap();
+    store.save(&record(Duration::hours(1))).await.unwrap();
+
+    let period = std::time::Duration::from_secs(60);
+    let task = tokio::spawn(store.clone().continuously_delete_expired(period));
+
+    // The first sweep waits a full period.
+    tokio::time::sleep(period - std::time::Duration::from_secs(1)).await;
+    assert_eq!(row_count(&store), 2);
+
+    tokio::time::sleep(std::time::Duration::from_secs(2)).await;
+    assert_eq!(row_count(&store), 1);
+
+    // And it keeps going.
+    store.save(&record(Duration::seconds(-1))).await.unwrap();
+    assert_eq!(row_count(&store), 2);
+    tokio::time::sleep(period).await;
+    assert_eq!(row_count(&store), 1);
+
+    task.abort();
+    assert!(task.await.unwrap_err().is_cancelled());
+}
+
+#[tokio::test(start_paused = true)]
+async fn continuously_delete_expired_skips_sweeps_while_locked() {
+    let db = TempDb::new();
+    let store = impatient_store(&db);
+    store.save(&record(Duration::seconds(-1))).await.unwrap();
+
+    let period = std::time::Duration::from_secs(60);
+    let task = tokio::spawn(store.clone().continuously_delete_expired(period));
+
+    let lock = lock_for_writing(&db);
+    tokio::time::sleep(period + std::time::Duration::from_secs(1)).await;
+    assert!(!task.is_finished());
+    assert_eq!(row_count(&store), 1);
+
+    drop(lock);
+    tokio::time::sleep(period).await;
+    assert!(!task.is_finished());
+    assert_eq!(row_count(&store), 0);
+
+    task.abort();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn concurrent_creates_claim_distinct_ids() {
+    let db = TempDb::new();
+    let pool = r2d2::Pool::builder()
+        .max_size(8)
+        .build(SqliteConnectionManager::file(db.path()))
+        .unwrap();
+    let store = SqliteStore::new(pool);
+    store.migrate().unwrap();
+
+    // Every create starts from the same id, which one of them may claim.
+    let template = record(Duration::hours(1));
+    let tasks: Vec<_> = (0..32)
+        .map(|_| {
+            let store = store.clone();
+            let mut record = template.clone();
+            tokio::spawn(async move {
+                store.create(&mut record).await.unwrap();
+                record
+            })
+        })
+        .collect();
+
+    let mut ids = std::collections::HashSet::new();
+    for task in tasks {
+        let record = task.await.unwrap();
+        assert!(ids.insert(record.id));
+        assert_eq!(store.load(&record.id).await.unwrap(), Some(record));
+    }
+    assert_eq!(row_count(&store), 32);
+
+    // An id that is already taken is replaced rather than overwritten.
+    let mut taken = template.clone();
+    taken
+        .data
+        .insert("user".to_owned(), serde_json::json!("bob"));
+    store.create(&mut taken).await.unwrap();
+    assert_ne!(taken.id, template.id);
+    assert_eq!(row_count(&store), 33);
+}
+
+#[tokio::test]
+async fn blocking_calls_leave_the_runtime_free() {
+    let db = TempDb::new();
+    let manager = SqliteConnectionManager::file(db.path())
+        .with_init(|conn| conn.busy_timeout(std::time::Duration::from_secs(2)));
+    let store = SqliteStore::new(r2d2::Pool::new(manager).unwrap());
+    store.migrate().unwrap();
+    let record = record(Duration::hours(1));
+
+    // On this single-threaded runtime the lock is only released if the save
+    // waiting on it doesn't hold up the thread.
+    let lock = lock_for_writing(&db);
+    let release = async {
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        drop(lock);
+    };
+    let started = std::time::Instant::now();
+    let (saved, ()) = tokio::join!(store.save(&record), release);
+
+    saved.unwrap();
+    assert!(started.elapsed() < std::time::Duration::from_secs(2));
+    assert_eq!(store.load(&record.id).await.unwrap(), Some(record));
+}
+
+#[tokio::test]
+async fn table_names_keep_stores_apart() {
+    let db = TempDb::new();
+    let pool = r2d2::Pool::new(SqliteConnectionManager::file(db.path())).unwrap();
+    let a = SqliteStore::new(pool).with_table_name("sessions_a");
+    let b = a.clone().with_table_name("sessions_b");
+    a.migrate().unwrap();
+    b.migrate().unwrap();
+
+    let record = record(Duration::hours(1));
+    a.save(&record).await.unwrap();
+
+    assert_eq!(a.load(&record.id).await.unwrap(), Some(record.clone()));
+    assert_eq!(b.load(&record.id).await.unwrap(), None);
+
+    b.delete(&record.id).await.unwrap();
+    assert_eq!(a.load(&record.id).await.unwrap(), Some(record));
+
+    let conn = Connection::open(db.path()).unwrap();
+    assert!(!columns(&conn, "sessions_a").is_empty());
+    assert!(columns(&conn, "tower_sessions").is_empty());
+}
+
+#[test]
+#[should_panic(expected = "invalid session table name")]
+fn table_names_must_be_identifiers() {
+    let pool = r2d2::Pool::new(SqliteConnectionManager::memory()).unwrap();
+    let _ = SqliteStore::new(pool).with_table_name("sessions; drop table users");
+}
+
+#[tokio::test]
+async fn count_includes_expired_sessions_until_deleted() {
+    let store = store();
+    assert_eq!(store.count().await.unwrap(), 0);
+
+    let mut live = record(Duration::hours(1));
+    store.create(&mut live).await.unwrap();
+    store.save(&record(Duration::seconds(-1))).await.unwrap();
+    assert_eq!(store.count().await.unwrap(), 2);
+
+    // Saving an existing session again doesn't add a row.
+    store.save(&live).await.unwrap();
+    assert_eq!(store.count().await.unwrap(), 2);
+
+    store.delete_expired().await.unwrap();
+    assert_eq!(store.count().await.unwrap(), 1);
+    store.delete(&live.id).await.unwrap();
+    assert_eq!(store.count().await.unwrap(), 0);
+}
+
+#[tokio::test]
+async fn clear_deletes_live_and_expired_sessions() {
+    let store = store();
+    let live = record(Duration::hours(1));
+    store.save(&live).await.unwrap();
+    store.save(&record(Duration::seconds(-1))).await.unwrap();
+
+    store.clear().await.unwrap();
+
+    assert_eq!(store.count().await.unwrap(), 0);
+    assert_eq!(store.load(&live.id).await.unwrap(), None);
+
+    // The table is still there to take new sessions.
+    store.save(&live).await.unwrap();
+    assert_eq!(store.load(&live.id).await.unwrap(), Some(live));
+}
+
+#[tokio::test]
+async fn load_many_skips_missing_expired_and_undecodable_sessions() {
+    let store = store();
+    // More ids than fit in one `in (...)` chunk.
+    let live: Vec<_> = (0..1000).map(|_| record(Duration::hours(1))).collect();
+    let expired = record(Duration::seconds(-1));
+    let corrupt = record(Duration::hours(1));
+    for record in live.iter().chain([&expired, &corrupt]) {
+        store.save(record).await.unwrap();
+    }
+    store
+        .pool
+        .get()
+        .unwrap()
+        .execute(
+            "update tower_sessions set data = x'ff00' where id = ?1",
+            [corrupt.id.to_string()],
+        )
+        .unwrap();
+
+    let mut ids: Vec<_> = live.iter().map(|record| record.id).collect();
+    ids.extend([expired.id, corrupt.id, Id::default()]);
+
+    let loaded = store.load_many(&ids).await.unwrap();
+    assert_eq!(loaded.len(), live.len());
+    let loaded: std::collections::HashMap<_, _> = loaded.into_iter().collect();
+    for record in &live {
+        assert_eq!(loaded.get(&record.id), Some(record));
+    }
+
+    assert!(store.load_many(&[]).await.unwrap().is_empty());
+}
+
+/// Fails every encode or decode, to see how the store reports it.
+#[derive(Debug)]
+struct FailingSerializer;
+
+impl crate::SessionSerializer for FailingSerializer {
+    fn encode(&self, _: &Record) -> Result<Vec<u8>, crate::SqliteStoreError> {
+        Err(crate::SqliteStoreError::Serialize("no encoding".into()))
+    }
+
+    fn decode(&self, _: &[u8]) -> Result<Record, crate::SqliteStoreError> {
+        Err(crate::SqliteStoreError::Deserialize("no decoding".into()))
+    }
+}
+
+#[tokio::test]
+async fn serializer_failures_surface_as_encode_and_decode_errors() {
+    let store = store();
+    let record = record(Duration::hours(1));
+    store.save(&record).await.unwrap();
+
+    let failing = store.clone().with_serializer(FailingSerializer);
+    assert!(matches!(
+        failing.save(&record).await,
+        Err(tower_sessions_core::session_store::Error::Encode(message)) if message == "no encoding"
+    ));
+    assert!(matches!(
+        failing.load(&record.id).await,
+        Err(tower_sessions_core::session_store::Error::Decode(message)) if message == "no decoding"
+    ));
+}
+
+#[cfg(feature = "compression")]
+#[tokio::test]
+async fn compression_shrinks_blobs_and_reads_uncompressed_rows() {
+    let plain = store();
+    let mut large = record(Duration::hours(1));
+    large.data.insert(
+        "notes".to_owned(),
+        serde_json::json!("lorem ipsum ".repeat(500)),
+    );
+    let mut uncompressed = record(Duration::hours(1));
+    plain.create(&mut uncompressed).await.unwrap();
+
+    let store = plain.with_compression(crate::CompressionLevel::default());
+    store.create(&mut large).await.unwrap();
+
+    let raw = store.load_raw(&large.id).await.unwrap().unwrap();
+    assert_eq!(raw[0], crate::COMPRESSED_MARKER);
+    assert!(raw.len() < 1000);
+    assert_eq!(store.load(&large.id).await.unwrap(), Some(large));
+
+    // Rows written before compression was turned on still load.
+    assert_eq!(
+        store.load(&uncompressed.id).await.unwrap(),
+        Some(uncompressed)
+    );
+}
+
+#[cfg(not(feature = "compression"))]
+#[tokio::test]
+async fn compressed_rows_fail_to_load_without_the_feature() {
+    let store = store();
+    let record = record(Duration::hours(1));
+    store.save(&record).await.unwrap();
+    store
+        .pool
+        .get()
+        .unwrap()
+        .execute(
+            "update tower_sessions set data = ?2 where id = ?1",
+            (
+                record.id.to_string(),
+                [crate::COMPRESSED_MARKER, 0x28, 0xb5],
+            ),
+        )
+        .unwrap();
+
+    assert!(matches!(
+        store.load(&record.id).await,
+        Err(tower_sessions_core::session_store::Error::Decode(_))
+    ));
+}
+
+#[tokio::test]
+async fn from_path_creates_and_reopens_the_file() {
+    let db = TempDb::new();
+    assert!(!db.path().exists());
+
+    let store = SqliteStore::from_path(db.path()).unwrap();
+    store.migrate().unwrap();
+    assert!(db.path().exists());
+
+    let record = record(Duration::hours(1));
+    store.save(&record).await.unwrap();
+    drop(store);
+
+    let reopened = SqliteStore::from_path(db.path()).unwrap();
+    assert_eq!(reopened.load(&record.id).await.unwrap(), Some(record));
+}
+
+#[tokio::test]
+async fn pool_is_shared_with_clones_and_sees_the_sessions() {
+    let store = store();
+    let record = record(Duration::hours(1));
+    store.clone().save(&record).await.unwrap();
+
+    let conn = store.pool().get().unwrap();
+    let stored: String = conn
+        .query_row("select id from tower_sessions", [], |row| row.get(0))
+        .unwrap();
+    assert_eq!(stored, record.id.to_string());
+
+    // The single in-memory connection is checked out, for the clone too.
+    assert_eq!(store.clone().pool().state().idle_connections, 0);
+}
+
+/// Reads `pragma name` on `conn` as text.
+fn pragma(conn: &Connection, name: &str) -> String {
+    conn.query_row(&format!("pragma {name}"), [], |row| {
+        Ok(match row.get_ref(0)? {
+            r2d2_sqlite::rusqlite::types::ValueRef::Integer(n) => n.to_string(),
+            value => value.as_str().unwrap().to_lowercase(),
+        })
+    })
+    .unwrap()
+}
+
+#[test]
+fn pragmas_apply_to_every_pooled_connection() {
+    let db = TempDb::new();
+    let store = SqliteStore::builder()
+        .path(db.path())
+        .with_pragmas(crate::PragmaConfig::default())
+        .build()
+        .unwrap();
+
+    let conns: Vec<_> = (0..3).map(|_| store.pool().get().unwrap()).collect();
+    for conn in &conns {
+        assert_eq!(pragma(conn, "journal_mode"), "wal");
+        assert_eq!(pragma(conn, "synchronous"), "1");
+        assert_eq!(pragma(conn, "busy_timeout"), "5000");
+    }
+    drop((conns, store));
+
+    let store = SqliteStore::builder()
+        .path(db.path())
+        .with_pragmas(
+            crate::PragmaConfig::default()
+                .with_busy_timeout(std::time::Duration::from_millis(250))
+                .with_journal_mode(crate::JournalMode::Delete)
+                .with_synchronous(crate::Synchronous::Extra),
+        )
+        .build()
+        .unwrap();
+    let conn = store.pool().get().unwrap();
+    assert_eq!(pragma(&conn, "journal_mode"), "delete");
+    assert_eq!(pragma(&conn, "synchronous"), "3");
+    assert_eq!(pragma(&conn, "busy_timeout"), "250");
+}
+
+#[tokio::test]
+async fn touch_moves_the_expiry_without_rewriting_data() {
+    let store = store();
+    let record = record(Duration::seconds(-1));
+    store.save(&record).await.unwrap();
+    let raw = stored_data(&store, &record.id);
+
+    // Touching revives an expired session.
+    let expiry = OffsetDateTime::now_utc() + Duration::hours(1);
+    assert!(store.touch(&record.id, expiry).await.unwrap());
+    assert_eq!(stored_expiry(&store, &record.id), Some(unix_nanos(expiry)));
+    assert_eq!(stored_data(&store, &record.id), raw);
+    assert_eq!(
+        store.load(&record.id).await.unwrap().unwrap().expiry_date,
+        expiry
+    );
+
+    assert!(!store.touch(&Id::default(), expiry).await.unwrap());
+    assert_eq!(store.count().await.unwrap(), 1);
+}
+
+#[test]
+fn expiry_queries_use_the_expiry_index() {
+    let store = store();
+    let conn = store.pool().get().unwrap();
+
+    let plan: Vec<String> = conn
+        .prepare("explain query plan delete from tower_sessions where expiry_date < ?1")
+        .unwrap()
+        .query_map([0], |row| row.get(3))
+        .unwrap()
+        .collect::<Result<_, _>>()
+        .unwrap();
+    assert!(
+        plan.iter()
+            .any(|step| step.contains("USING INDEX idx_tower_sessions_expiry")),
+        "{plan:?}"
+    );
+}
+
+#[tokio::test]
+async fn exhausted_pool_reports_a_pool_timeout() {
+    let pool = r2d2::Pool::builder()
+        .max_size(1)
+        .connection_timeout(std::time::Duration::from_millis(50))
+        .build(SqliteConnectionManager::memory())
+        .unwrap();
+    let store = SqliteStore::new(pool);
+    store.migrate().unwrap();
+
+    let held = store.pool().get().unwrap();
+    assert!(matches!(
+        store.conn(),
+        Err(crate::SqliteStoreError::PoolTimeout(_))
+    ));
+    assert!(matches!(
+        store.load(&Id::default()).await,
+        Err(tower_sessions_core::session_store::Error::Backend(message))
+            if message.starts_with("Connection pool exhausted")
+    ));
+
+    drop(held);
+    assert_eq!(store.load(&Id::default()).await.unwrap(), None);
+}
+
+#[tokio::test]
+async fn save_many_upserts_in_independent_batches() {
+    let store = store().with_batch_size(2);
+    let mut records: Vec<_> = (0..5).map(|_| record(Duration::hours(1))).collect();
+    store.save(&records[0]).await.unwrap();
+    records[0]
+        .data
+        .insert("user".to_owned(), serde_json::json!("bob"));
+
+    store.save_many(&records).await.unwrap();
+    assert_eq!(store.count().await.unwrap(), 5);
+    for record in &records {
+        assert_eq!(store.load(&record.id).await.unwrap().as_ref(), Some(record));
+    }
+
+    // A failure in the second batch keeps the first and skips the rest.
+    store.clear().await.unwrap();
+    store
+        .pool()
+        .get()
+        .unwrap()
+        .execute_batch(&format!(
+            r#"
+            create trigger reject before insert on tower_sessions
+            when new.id = '{}' begin select raise(abort, 'rejected'); end
+            "#,
+            records[3].id
+        ))
+        .unwrap();
+    assert!(store.save_many(&records).await.is_err());
+    assert_eq!(
+        store.count().await.unwrap(),
+        2,
+        "only the first batch is kept"
+    );
+    assert!(store.load(&records[1].id).await.unwrap().is_some());
+    assert_eq!(store.load(&records[2].id).await.unwrap(), None);
+}
+
+#[tokio::test]
+async fn hot_paths_prepare_their_statements_once_per_connection() {
+    use r2d2_sqlite::rusqlite::hooks::{AuthContext, Authorization};
+
+    let store = store();
+    let mut record = record(Duration::hours(1));
+    store.create(&mut record).await.unwrap();
+
+    // SQLite consults the authorizer while compiling a statement, never when
+    // running one it already prepared.
+    let prepared = Arc::new(AtomicUsize::new(0));
+    {
+        let prepared = prepared.clone();
+        store
+            .pool()
+            .get()
+            .unwrap()
+            .authorizer(Some(move |_: AuthContext<'_>| {
+                prepared.fetch_add(1, Ordering::Relaxed);
+                Authorization::Allow
+            }));
+    }
+
+    store.load(&record.id).await.unwrap();
+    store.save(&record).await.unwrap();
+    assert!(prepared.swap(0, Ordering::Relaxed) > 0);
+
+    for _ in 0..10 {
+        assert!(store.load(&record.id).await.unwrap().is_some());
+        store.save(&record).await.unwrap();
+    }
+    assert_eq!(prepared.load(Ordering::Relaxed), 0);
+}
+
+#[tokio::test]
+async fn active_ids_lists_live_sessions_and_rejects_bad_ids() {
+    let store = store();
+    let live = [record(Duration::hours(1)), record(Duration::minutes(1))];
+    for record in &live {
+        store.save(record).await.unwrap();
+    }
+    store.save(&record(Duration::seconds(-1))).await.unwrap();
+
+    let mut ids = store.active_ids().await.unwrap();
+    ids.sort_by_key(|id| id.0);
+    let mut expected: Vec<_> = live.iter().map(|record| record.id).collect();
+    expected.sort_by_key(|id| id.0);
+    assert_eq!(ids, expected);
+
+    store
+        .pool()
+        .get()
+        .unwrap()
+        .execute(
+            "update tower_sessions set id = 'not an id' where id = ?1",
+            [live[0].id.to_string()],
+        )
+        .unwrap();
+    assert!(matches!(
+        store.active_ids().await,
+        Err(tower_sessions_core::session_store::Error::Decode(message))
+            if message.contains("not an id")
+    ));
+}
+
+#[tokio::test]
+async fn exists_checks_liveness_without_decoding() {
+    let store = store();
+    let live = record(Duration::hours(1));
+    let expired = record(Duration::seconds(-1));
+    store.save(&live).await.unwrap();
+    store.save(&expired).await.unwrap();
+
+    assert!(store.exists(&live.id).await.unwrap());
+    assert!(!store.exists(&expired.id).await.unwrap());
+    assert!(!store.exists(&Id::default()).await.unwrap());
+
+    // Data that wouldn't decode doesn't matter.
+    store
+        .pool()
+        .get()
+        .unwrap()
+        .execute("update tower_sessions set data = x'c1'", [])
+        .unwrap();
+    assert!(store.exists(&live.id).await.unwrap());
+    assert!(store.load(&live.id).await.is_err());
+}
+
+#[tokio::test]
+async fn create_gives_up_after_too_many_collisions() {
+    let taken = record(Duration::hours(1));
+    let mut store = store();
+    store.save(&taken).await.unwrap();
+    store.generated_id = Some(taken.id);
+
+    for (strategy, attempts) in [
+        (
+            crate::CollisionStrategy::OnConflictRetry,
+            crate::MAX_CREATE_ATTEMPTS,
+        ),
+        (
+            crate::CollisionStrategy::PreCheck,
+            crate::MAX_CREATE_ATTEMPTS,
+        ),
+        (crate::CollisionStrategy::AssumeUnique, 2),
+    ] {
+        let store = store.clone().with_collision_strategy(strategy);
+        let mut record = record(Duration::hours(1));
+        record.id = taken.id;
+
+        assert!(matches!(
+            store.create_with_conn(&mut record),
+            Err(crate::SqliteStoreError::IdCollisionExhausted(n)) if n == attempts
+        ));
+        assert!(matches!(
+            store.create(&mut record).await,
+            Err(tower_sessions_core::session_store::Error::Backend(message))
+                if message == format!("No unused session id found after {attempts} attempts")
+        ));
+        assert!(matches!(
+            store.reserve_id().await,
+            Err(tower_sessions_core::session_store::Error::Backend(_))
+        ));
+    }
+
+    // The session holding the id is never overwritten.
+    assert_eq!(store.load(&taken.id).await.unwrap(), Some(taken));
+    assert_eq!(store.count().await.unwrap(), 1);
+}
+
+fn has_index(conn: &Connection, name: &str) -> bool {
+    conn.query_row(
+        "select exists(select 1 from sqlite_master where type = 'index' and name = ?1)",
+        [name],
+        |row| row.get(0),
+    )
+    .unwrap()
+}
+
+fn recorded_version(conn: &Connection, table: &str) -> Option<usize> {
+    conn.query_row(
+        "select version from tower_sessions_schema_versions where table_name = ?1",
+        [table],
+        |row| row.get(0),
+    )
+    .optional()
+    .unwrap()
+}
+
+#[tokio::test]
+async fn migrate_tracks_versions_per_table() {
+    let db = TempDb::new();
+
+    // `a` was created before migrations were versioned, without the index
+    // the first step adds.
+    let conn = Connection::open(db.path()).unwrap();
+    conn.execute_batch(
+        "create table a (id text primary key not null, data blob not null, expiry_date integer not null)",
+    )
+    .unwrap();
+
+    let a = SqliteStore::from_path(db.path())
+        .unwrap()
+        .with_table_name("a");
+    a.migrate().unwrap();
+    assert!(has_index(&conn, "idx_a_expiry"));
+    assert_eq!(recorded_version(&conn, "a"), Some(crate::MIGRATIONS.len()));
+    assert_eq!(recorded_version(&conn, "b"), None);
+
+    let b = SqliteStore::from_path(db.path())
+        .unwrap()
+        .with_table_name("b");
+    b.migrate().unwrap();
+    assert_eq!(recorded_version(&conn, "b"), Some(crate::MIGRATIONS.len()));
+    assert_eq!(columns(&conn, "a"), columns(&conn, "b"));
+
+    let mut record = record(Duration::hours(1));
+    b.create(&mut record).await.unwrap();
+    assert_eq!(b.load(&record.id).await.unwrap(), Some(record));
+}
+
+#[test]
+fn migrate_only_applies_missing_steps() {
+    let store = store();
+    let conn = store.pool().get().unwrap();
+    assert!(has_index(&conn, "idx_tower_sessions_expiry"));
+
+    // An up-to-date table is left alone, even if it has drifted.
+    conn.execute_batch("drop index idx_tower_sessions_expiry")
+        .unwrap();
+    drop(conn);
+    store.migrate().unwrap();
+    let conn = store.pool().get().unwrap();
+    assert!(!has_index(&conn, "idx_tower_sessions_expiry"));
+
+    // A table dropped behind the store's back is created from scratch.
+    conn.execute_batch("drop table tower_sessions").unwrap();
+    drop(conn);
+    store.migrate().unwrap();
+    let conn = store.pool().get().unwrap();
+    assert!(has_index(&conn, "idx_tower_sessions_expiry"));
+    assert_eq!(
+        recorded_version(&conn, "tower_sessions"),
+        Some(crate::MIGRATIONS.len())
+    );
+}
+
+#[tokio::test]
+async fn load_detailed_tells_expired_from_missing() {
+    use crate::LoadOutcome;
+
+    let store = store();
+    let live = record(Duration::hours(1));
+    let expired = record(Duration::seconds(-1));
+    store.save(&live).await.unwrap();
+    store.save(&expired).await.unwrap();
+
+    assert_eq!(
+        store.load_detailed(&live.id).await.unwrap(),
+        LoadOutcome::Found(live)
+    );
+    assert_eq!(
+        store.load_detailed(&expired.id).await.unwrap(),
+        LoadOutcome::Expired
+    );
+    assert_eq!(
+        store.load_detailed(&Id::default()).await.unwrap(),
+        LoadOutcome::NotFound
+    );
+
+    store.delete_expired().await.unwrap();
+    assert_eq!(
+        store.load_detailed(&expired.id).await.unwrap(),
+        LoadOutcome::NotFound
+    );
+}
+
+#[tokio::test]
+async fn expiry_has_sub_second_precision() {
+    let store = store();
+
+    let mut live = record(Duration::ZERO);
+    live.expiry_date += Duration::milliseconds(500);
+    let mut expired = record(Duration::ZERO);
+    expired.expiry_date -= Duration::milliseconds(200);
+    store.save(&live).await.unwrap();
+    store.save(&expired).await.unwrap();
+
+    assert_eq!(
+        stored_expiry(&store, &live.id),
+        Some(unix_nanos(live.expiry_date))
+    );
+    assert_eq!(store.load(&live.id).await.unwrap(), Some(live));
+    assert_eq!(store.load(&expired.id).await.unwrap(), None);
+}
+
+#[tokio::test]
+async fn migrate_converts_expiry_seconds_to_nanoseconds() {
+    let db = TempDb::new();
+    let expiry = (OffsetDateTime::now_utc() + Duration::hours(1))
+        .replace_nanosecond(0)
+        .unwrap();
+    let record = Record {
+        expiry_date: expiry,
+        ..record(Duration::ZERO)
+    };
+
+    // A row written by the first release, which stored whole seconds.
+    let conn = Connection::open(db.path()).unwrap();
+    conn.execute_batch(
+        "create table tower_sessions (id text primary key not null, data blob not null, expiry_date integer not null)",
+    )
+    .unwrap();
+    conn.execute(
+        "insert into tower_sessions (id, data, expiry_date) values (?1, ?2, ?3)",
+        r2d2_sqlite::rusqlite::params![
+            record.id.to_string(),
+            rmp_serde::to_vec(&record).unwrap(),
+            expiry.unix_timestamp()
+        ],
+    )
+    .unwrap();
+
+    let store = SqliteStore::from_path(db.path())
+        .unwrap()
+        .with_serializer(crate::MessagePackSerializer);
+    store.migrate().unwrap();
+
+    assert_eq!(stored_expiry(&store, &record.id), Some(unix_nanos(expiry)));
+    assert_eq!(store.load(&record.id).await.unwrap(), Some(record));
+}
+
+#[tokio::test]
+async fn delete_many_deletes_in_chunks_and_counts_what_existed() {
+    let store = store();
+    let records: Vec<_> = (0..1000).map(|_| record(Duration::hours(1))).collect();
+    let kept = record(Duration::hours(1));
+    store.save_many(&records).await.unwrap();
+    store.save(&kept).await.unwrap();
+
+    let mut ids: Vec<_> = records.iter().map(|record| record.id).collect();
+    ids.push(Id::default());
+
+    assert_eq!(store.delete_many(&ids).await.unwrap(), 1000);
+    assert_eq!(store.active_ids().await.unwrap(), [kept.id]);
+
+    assert_eq!(store.delete_many(&ids).await.unwrap(), 0);
+    assert_eq!(store.delete_many(&[]).await.unwrap(), 0);
+}
+
+#[cfg(feature = "encryption")]
+#[tokio::test]
+async fn encrypted_sessions_need_the_right_key() {
+    use tower_sessions_core::session_store::Error;
+
+    let plain = store();
+    let store = plain.clone().with_encryption_key([1; 32]);
+    let mut record = record(Duration::hours(1));
+    store.create(&mut record).await.unwrap();
+
+    let raw = store.load_raw(&record.id).await.unwrap().unwrap();
+    assert_eq!(raw[0], crate::ENCRYPTED_MARKER);
+    assert!(!raw.windows(5).any(|window| window == b"alice"));
+    assert_eq!(store.load(&record.id).await.unwrap(), Some(record.clone()));
+
+    let wrong_key = plain.clone().with_encryption_key([2; 32]);
+    assert!(matches!(
+        wrong_key.load(&record.id).await,
+        Err(Error::Decode(message)) if message.contains("wrong key")
+    ));
+    assert!(matches!(
+        plain.load(&record.id).await,
+        Err(Error::Decode(_))
+    ));
+
+    // Rows that aren't encrypted can't be planted in an encrypted store.
+    let mut planted = self::record(Duration::hours(1));
+    plain.create(&mut planted).await.unwrap();
+    assert!(matches!(
+        store.load(&planted.id).await,
+        Err(Error::Decode(message)) if message.contains("not encrypted")
+    ));
+}
+
+#[tokio::test]
+async fn health_check_fails_when_no_connection_is_free() {
+    let store = SqliteStore::builder()
+        .in_memory()
+        .connection_timeout(std::time::Duration::from_millis(50))
+        .build()
+        .unwrap();
+    store.health_check().await.unwrap();
+
+    let held = store.pool().get().unwrap();
+    assert!(store.health_check().await.is_err());
+
+    drop(held);
+    store.health_check().await.unwrap();
+}
+
+/// A record whose `user` field is `user`.
+fn record_of(user: &str, expires_in: Duration) -> Record {
+    let mut record = record(expires_in);
+    record
+        .data
+        .insert("user".to_owned(), serde_json::json!(user));
+    record
+}
+
+fn user_field(record: &Record) -> Option<String> {
+    record.data.get("user")?.as_str().map(str::to_owned)
+}
+
+fn sorted(mut ids: Vec<Id>) -> Vec<Id> {
+    ids.sort_by_key(|id| id.0);
+    ids
+}
+
+#[tokio::test]
+async fn sessions_are_found_and_revoked_per_user() {
+    let store = store().with_user_id_extractor(user_field);
+    let alice = [
+        record_of("alice", Duration::hours(1)),
+        record_of("alice", Duration::hours(2)),
+    ];
+    let alice_expired = record_of("alice", Duration::seconds(-1));
+    let bob = record_of("bob", Duration::hours(1));
+    for record in alice.iter().chain([&alice_expired, &bob]) {
+        store.save(record).await.unwrap();
+    }
+
+    assert_eq!(
+        sorted(store.sessions_for_user("alice").await.unwrap()),
+        sorted(alice.iter().map(|record| record.id).collect())
+    );
+    assert!(store.sessions_for_user("carol").await.unwrap().is_empty());
+
+    // Saving under another user moves the session.
+    let moved = Record {
+        data: record_of("bob", Duration::ZERO).data,
+        ..alice[1].clone()
+    };
+    store.save(&moved).await.unwrap();
+    assert_eq!(
+        sorted(store.sessions_for_user("bob").await.unwrap()),
+        sorted(vec![bob.id, moved.id])
+    );
+
+    // Expired sessions are revoked as well.
+    assert_eq!(store.delete_by_user("alice").await.unwrap(), 2);
+    assert_eq!(
+        sorted(store.active_ids().await.unwrap()),
+        sorted(vec![bob.id, moved.id])
+    );
+}
+
+#[tokio::test]
+async fn lazy_expiry_deletion_deletes_expired_rows_on_load() {
+    let store = store().with_lazy_expiry_deletion(true);
+
+    let expired = record(Duration::seconds(-10));
+    let live = record(Duration::hours(1));
+    store.save(&expired).await.unwrap();
+    store.save(&live).await.unwrap();
+
+    assert_eq!(store.load(&expired.id).await.unwrap(), None);
+    assert_eq!(store.load(&live.id).await.unwrap(), Some(live));
+    assert_eq!(store.count().await.unwrap(), 1);
+    assert_eq!(stored_expiry(&store, &expired.id), None);
+}
+
+#[tokio::test]
+async fn lazy_expiry_deletion_leaves_unknown_ids_read_only() {
+    let db = TempDb::new();
+    let store = impatient_store(&db).with_lazy_expiry_deletion(true);
+
+    let expired = record(Duration::seconds(-10));
+    store.save(&expired).await.unwrap();
+
+    let _lock = lock_for_writing(&db);
+
+    // A miss on an id that was never stored doesn't need the write lock, and
+    // a delete that can't get it doesn't fail the load.
+    assert_eq!(store.load(&Id::default()).await.unwrap(), None);
+    assert_eq!(store.load(&expired.id).await.unwrap(), None);
+    assert_eq!(store.count().await.unwrap(), 1);
+}
+
+#[tokio::test]
+async fn sqlite_errors_keep_their_extended_code() {
+    use tower_sessions_core::session_store::Error;
+
+    let store = store();
+    let failed = store
+        .pool()
+        .get()
+        .unwrap()
+        .execute("insert into tower_sessions (id) values ('a')", [])
+        .unwrap_err();
+    let err = crate::SqliteStoreError::from(failed);
+    assert!(!err.is_retryable());
+    assert!(matches!(
+        Error::from(err),
+        Error::Backend(message)
+            if message.starts_with("NOT NULL constraint failed")
+                && message.ends_with("(sqlite code 1299)")
+    ));
+
+    // Busy errors stay recognisable through the session store error too.
+    let db = TempDb::new();
+    let store = impatient_store(&db);
+    let _lock = lock_for_writing(&db);
+    let busy = store
+        .save_with_conn(&record(Duration::hours(1)))
+        .unwrap_err();
+    assert!(busy.is_retryable());
+    assert!(matches!(
+        Error::from(busy),
+        Error::Backend(message) if message.ends_with("(sqlite code 5)")
+    ));
+}
+
+#[tokio::test]
+async fn retry_waits_out_a_briefly_held_lock() {
+    let db = TempDb::new();
+    let record = record(Duration::hours(1));
+
+    let impatient = impatient_store(&db);
+    let lock = lock_for_writing(&db);
+    assert!(impatient.save(&record).await.is_err());
+    drop(lock);
+
+    let patient = impatient.with_retry(crate::RetryPolicy {
+        max_attempts: 6,
+        base_delay: std::time::Duration::from_millis(20),
+    });
+    let lock = lock_for_writing(&db);
+    let release = std::thread::spawn(move || {
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        drop(lock);
+    });
+
+    patient.save(&record).await.unwrap();
+    release.join().unwrap();
+    assert_eq!(
+        patient.load(&record.id).await.unwrap(),
+        Some(record.clone())
+    );
+
+    // Conditional saves wait it out too.
+    let version = patient.version_of(&record.id).await.unwrap().unwrap();
+    let lock = lock_for_writing(&db);
+    let release = std::thread::spawn(move || {
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        drop(lock);
+    });
+
+    assert!(patient.save_if_unchanged(&record, version).await.unwrap());
+    release.join().unwrap();
+    assert_eq!(
+        patient.version_of(&record.id).await.unwrap(),
+        Some(version + 1)
+    );
+}
+
+/// Records every observer call, in order.
+#[derive(Clone, Debug, Default)]
+struct RecordingObserver(Arc<Mutex<Vec<String>>>);
+
+impl RecordingObserver {
+    fn take(&self) -> Vec<String> {
+        std::mem::take(&mut self.0.lock().unwrap())
+    }
+
+    fn push(&self, call: impl Into<String>) {
+        self.0.lock().unwrap().push(call.into());
+    }
+}
+
+impl crate::StoreObserver for RecordingObserver {
+    fn on_create(&self, _: std::time::Duration) {
+        self.push("create");
+    }
+
+    fn on_save(&self, _: std::time::Duration) {
+        self.push("save");
+    }
+
+    fn on_load(&self, hit: bool, _: std::time::Duration) {
+        self.push(format!("load hit={hit}"));
+    }
+
+    fn on_delete(&self, _: std::time::Duration) {
+        self.push("delete");
+    }
+
+    fn on_delete_expired(&self, _: std::time::Duration) {
+        self.push("delete_expired");
+    }
+
+    fn on_error(&self, op: crate::Operation, _: &crate::SqliteStoreError) {
+        self.push(format!("error {op:?}"));
+    }
+}
+
+#[tokio::test]
+async fn observer_sees_every_operation_and_failure() {
+    let observer = RecordingObserver::default();
+    let store = store().with_observer(observer.clone());
+
+    let mut record = record(Duration::hours(1));
+    store.create(&mut record).await.unwrap();
+    store.save(&record).await.unwrap();
+    store.load(&record.id).await.unwrap();
+    store.load(&Id::default()).await.unwrap();
+    store.delete(&record.id).await.unwrap();
+    store.delete_expired().await.unwrap();
+    assert_eq!(
+        observer.take(),
+        [
+            "create",
+            "save",
+            "load hit=true",
+            "load hit=false",
+            "delete",
+            "delete_expired"
+        ]
+    );
+
+    store.save(&record).await.unwrap();
+    store
+        .pool()
+        .get()
+        .unwrap()
+        .execute("update tower_sessions set data = x'c1'", [])
+        .unwrap();
+    observer.take();
+    assert!(store.load(&record.id).await.is_err());
+    assert_eq!(observer.take(), ["error Load"]);
+}
+
+/// `pragma page_count` and `pragma freelist_count` of `store`'s database.
+fn page_counts(store: &SqliteStore) -> (i64, i64) {
+    let conn = store.pool().get().unwrap();
+    let count = |name: &str| conn.query_row(&format!("pragma {name}"), [], |row| row.get(0));
+    (
+        count("page_count").unwrap(),
+        count("freelist_count").unwrap(),
+    )
+}
+
+/// Stores `n` sessions carrying about 2 KiB of data each, then deletes them.
+async fn fill_and_empty(store: &SqliteStore, n: usize) {
+    let records: Vec<_> = (0..n)
+        .map(|_| {
+            let mut record = record(Duration::hours(1));
+            record
+                .data
+                .insert("padding".to_owned(), serde_json::json!("x".repeat(2048)));
+            record
+        })
+        .collect();
+    store.save_many(&records).await.unwrap();
+    store.clear().await.unwrap();
+}
+
+#[tokio::test]
+async fn vacuum_returns_freed_pages() {
+    let db = TempDb::new();
+    let store = SqliteStore::from_path(db.path()).unwrap();
+    store.migrate().unwrap();
+    fill_and_empty(&store, 500).await;
+
+    let (pages, free) = page_counts(&store);
+    assert!(free > 0);
+
+    store.vacuum().await.unwrap();
+    let (vacuumed, free) = page_counts(&store);
+    assert_eq!(free, 0);
+    assert!(vacuumed < pages);
+}
+
+#[tokio::test]
+async fn incremental_vacuum_frees_the_pages_asked_for() {
+    let db = TempDb::new();
+    let manager = SqliteConnectionManager::file(db.path())
+        .with_init(|conn| conn.execute_batch("pragma auto_vacuum = incremental"));
+    let store = SqliteStore::new(r2d2::Pool::new(manager).unwrap());
+    store.migrate().unwrap();
+    fill_and_empty(&store, 500).await;
+
+    let (_, free) = page_counts(&store);
+    assert!(free > 5);
+
+    store.incremental_vacuum(5).await.unwrap();
+    assert_eq!(page_counts(&store).1, free - 5);
+
+    store.incremental_vacuum(0).await.unwrap();
+    assert_eq!(page_counts(&store).1, 0);
+}
+
+#[tokio::test]
+async fn count_expired_predicts_what_delete_expired_removes() {
+    let store = store();
+    assert_eq!(store.count_expired().await.unwrap(), 0);
+
+    for expires_in in [
+        Duration::hours(1),
+        Duration::seconds(-1),
+        Duration::days(-1),
+        Duration::days(-30),
+    ] {
+        store.save(&record(expires_in)).await.unwrap();
+    }
+
+    let expired = store.count_expired().await.unwrap();
+    assert_eq!(expired, 3);
+    assert_eq!(store.count().await.unwrap(), 4);
+
+    store.delete_expired().await.unwrap();
+    assert_eq!(store.count().await.unwrap(), 4 - expired);
+    assert_eq!(store.count_expired().await.unwrap(), 0);
+}
+
+#[tokio::test]
+async fn read_only_stores_reject_writes_and_serve_reads() {
+    use tower_sessions_core::session_store::Error;
+
+    let db = TempDb::new();
+    let writer = SqliteStore::from_path(db.path()).unwrap();
+    writer.migrate().unwrap();
+    let record = record(Duration::hours(1));
+    writer.save(&record).await.unwrap();
+
+    let reader = SqliteStore::builder()
+        .path(db.path())
+        .read_only()
+        .build()
+        .unwrap();
+    assert_eq!(reader.load(&record.id).await.unwrap(), Some(record.clone()));
+    assert!(reader.exists(&record.id).await.unwrap());
+    assert_eq!(reader.count().await.unwrap(), 1);
+
+    let is_read_only = |result: Result<(), Error>| matches!(result, Err(Error::Backend(message)) if message == "Store is read-only");
+    let mut new = self::record(Duration::hours(1));
+    assert!(is_read_only(reader.create(&mut new).await));
+    assert!(is_read_only(reader.save(&new).await));
+    assert!(is_read_only(reader.delete(&record.id).await));
+    assert!(is_read_only(reader.delete_expired().await));
+    assert!(is_read_only(reader.migrate()));
+    assert_eq!(writer.count().await.unwrap(), 1);
+
+    // A pool that could write is still kept from doing so.
+    let reader = SqliteStore::new_read_only(writer.pool().clone());
+    assert!(is_read_only(reader.delete(&record.id).await));
+    assert_eq!(reader.load(&record.id).await.unwrap(), Some(record));
+}
+
+/// Overwrites the data of session `id` with bytes no serializer accepts.
+fn corrupt(store: &SqliteStore, id: &Id) {
+    store
+        .pool()
+        .get()
+        .unwrap()
+        .execute(
+            "update tower_sessions set data = x'ff00' where id = ?1",
+            [id.to_string()],
+        )
+        .unwrap();
+}
+
+#[tokio::test]
+async fn corrupt_blob_policies() {
+    use crate::CorruptBlobPolicy;
+    use tower_sessions_core::session_store::Error;
+
+    let record = record(Duration::hours(1));
+
+    let store = store();
+    store.save(&record).await.unwrap();
+    corrupt(&store, &record.id);
+    assert!(matches!(
+        store.load(&record.id).await,
+        Err(Error::Decode(_))
+    ));
+
+    let store = store.with_corrupt_blob_policy(CorruptBlobPolicy::TreatAsMissing);
+    assert_eq!(store.load(&record.id).await.unwrap(), None);
+    assert!(store.load_raw(&record.id).await.unwrap().is_some());
+
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let listener = {
+        let events = events.clone();
+        Arc::new(move |event| events.lock().unwrap().push(event))
+    };
+    let store = store
+        .with_corrupt_blob_policy(CorruptBlobPolicy::DeleteAndMiss)
+        .with_change_listener(listener);
+    assert_eq!(store.load(&record.id).await.unwrap(), None);
+    assert!(store.load_raw(&record.id).await.unwrap().is_none());
+    assert_eq!(
+        *events.lock().unwrap(),
+        [crate::SessionEvent::Deleted(record.id)]
+    );
+
+    // Rows that decode are left alone.
+    store.save(&record).await.unwrap();
+    assert_eq!(store.load(&record.id).await.unwrap(), Some(record));
+}
+
+#[tokio::test]
+async fn corrupt_blob_deletion_never_fails_a_load() {
+    let db = TempDb::new();
+    let store =
+        impatient_store(&db).with_corrupt_blob_policy(crate::CorruptBlobPolicy::DeleteAndMiss);
+    let record = record(Duration::hours(1));
+    store.save(&record).await.unwrap();
+    corrupt(&store, &record.id);
+
+    let lock = lock_for_writing(&db);
+    assert_eq!(store.load(&record.id).await.unwrap(), None);
+    assert!(store.load_raw(&record.id).await.unwrap().is_some());
+
+    drop(lock);
+    assert_eq!(store.load(&record.id).await.unwrap(), None);
+    assert!(store.load_raw(&record.id).await.unwrap().is_none());
+}
+
+#[tokio::test]
+async fn rotate_id_moves_the_session_to_the_new_id() {
+    use tower_sessions_core::session_store::Error;
+
+    let store = store();
+    let record = record(Duration::hours(1));
+    store.save(&record).await.unwrap();
+
+    let new = Id::default();
+    assert!(store.rotate_id(&record.id, new).await.unwrap());
+    assert_eq!(store.load(&record.id).await.unwrap(), None);
+    assert_eq!(
+        store.load(&new).await.unwrap(),
+        Some(Record {
+            id: new,
+            ..record.clone()
+        })
+    );
+    assert_eq!(store.count().await.unwrap(), 1);
+
+    // The old id is gone, so there is nothing left to move.
+    assert!(!store.rotate_id(&record.id, Id::default()).await.unwrap());
+
+    // A taken id, even by an expired session, is refused and nothing moves.
+    let expired = self::record(Duration::seconds(-1));
+    store.save(&expired).await.unwrap();
+    assert!(matches!(
+        store.rotate_id(&new, expired.id).await,
+        Err(Error::Backend(_))
+    ));
+    assert!(store.load(&new).await.unwrap().is_some());
+    assert_eq!(store.count().await.unwrap(), 2);
+}
+
+#[tokio::test]
+async fn manual_clock_expires_sessions_when_advanced() {
+    let clock = ManualClock::new();
+    let store = store().with_clock(clock.clone());
+
+    let mut record = record(Duration::ZERO);
+    record.expiry_date = clock.now() + Duration::minutes(30);
+    store.save(&record).await.unwrap();
+
+    clock.advance(Duration::minutes(29));
+    assert_eq!(store.load(&record.id).await.unwrap(), Some(record.clone()));
+    assert!(store.exists(&record.id).await.unwrap());
+    assert_eq!(store.count_expired().await.unwrap(), 0);
+
+    clock.advance(Duration::minutes(2));
+    assert_eq!(store.load(&record.id).await.unwrap(), None);
+    assert!(!store.exists(&record.id).await.unwrap());
+    assert_eq!(store.count_expired().await.unwrap(), 1);
+
+    store.delete_expired().await.unwrap();
+    assert_eq!(store.count().await.unwrap(), 0);
+}
+
+#[tokio::test]
+async fn returning_changes_counts_rows_written_and_removed() {
+    let store = store();
+    let mut record = record(Duration::hours(1));
+
+    assert_eq!(store.save_returning_changes(&record).await.unwrap(), 1);
+    record.expiry_date += Duration::hours(1);
+    assert_eq!(store.save_returning_changes(&record).await.unwrap(), 1);
+    assert_eq!(store.count().await.unwrap(), 1);
+
+    assert_eq!(store.delete_returning_changes(&record.id).await.unwrap(), 1);
+    assert_eq!(store.delete_returning_changes(&record.id).await.unwrap(), 0);
+    assert_eq!(
+        store
+            .delete_returning_changes(&Id::default())
+            .await
+            .unwrap(),
+        0
+    );
+}
+
+#[cfg(feature = "json")]
+fn data_column_type(store: &SqliteStore) -> String {
+    store
+        .pool()
+        .get()
+        .unwrap()
+        .query_row(
+            "select lower(type) from pragma_table_info('tower_sessions') where name = 'data'",
+            [],
+            |row| row.get(0),
+        )
+        .unwrap()
+}
+
+/// The storage class of a session's `data` value.
+#[cfg(feature = "json")]
+fn data_storage(store: &SqliteStore, id: &Id) -> String {
+    store
+        .pool()
+        .get()
+        .unwrap()
+        .query_row(
+            "select typeof(data) from tower_sessions where id = ?1",
+            [id.to_string()],
+            |row| row.get(0),
+        )
+        .unwrap()
+}
+
+#[cfg(feature = "json")]
+#[tokio::test]
+async fn json_records_are_stored_as_queryable_text() {
+    let store = store();
+    let mut record = record(Duration::hours(1));
+    store.create(&mut record).await.unwrap();
+
+    assert_eq!(data_column_type(&store), "text");
+    assert_eq!(data_storage(&store, &record.id), "text");
+
+    let (valid, user): (bool, String) = store
+        .pool()
+        .get()
+        .unwrap()
+        .query_row(
+            "select json_valid(data), data ->> '$.data.user' from tower_sessions",
+            [],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .unwrap();
+    assert!(valid);
+    assert_eq!(user, "alice");
+
+    assert!(store.schema_ddl().unwrap().contains("data text not null"));
+    store.verify_schema().unwrap();
+}
+
+#[cfg(feature = "json")]
+#[tokio::test]
+async fn json_migration_converts_blob_data_columns() {
+    let store = store();
+    let json = record(Duration::hours(1));
+    let opaque = record(Duration::hours(1));
+    let opaque_blob = store
+        .seal(serde_json::to_vec(&opaque).unwrap(), true)
+        .unwrap();
+
+    // A table migrated without the `json` feature, holding a JSON record
+    // written as a blob and a record that isn't JSON at all.
+    {
+        let conn = store.pool().get().unwrap();
+        conn.execute_batch(
+            r#"
+            drop table tower_sessions;
+            create table tower_sessions (
+                id text primary key not null,
+                data blob not null,
+                expiry_date integer not null
+            );
+            "#,
+        )
+        .unwrap();
+        store.apply_migrations(&conn, 1).unwrap();
+
+        for (record, blob) in [
+            (&json, serde_json::to_vec(&json).unwrap()),
+            (&opaque, opaque_blob.clone()),
+        ] {
+            conn.execute(
+                "insert into tower_sessions (id, data, expiry_date) values (?1, ?2, ?3)",
+                r2d2_sqlite::rusqlite::params![
+                    record.id.to_string(),
+                    blob,
+                    crate::unix_nanos(record.expiry_date)
+                ],
+            )
+            .unwrap();
+        }
+    }
+    assert_eq!(data_column_type(&store), "blob");
+    assert!(store.verify_schema().is_err());
+
+    store.migrate().unwrap();
+    store.verify_schema().unwrap();
+
+    assert_eq!(data_column_type(&store), "text");
+    assert_eq!(data_storage(&store, &json.id), "text");
+    assert_eq!(data_storage(&store, &opaque.id), "blob");
+    assert_eq!(store.load_raw(&opaque.id).await.unwrap(), Some(opaque_blob));
+    assert_eq!(store.load(&json.id).await.unwrap(), Some(json));
+    assert_eq!(store.load(&opaque.id).await.unwrap(), Some(opaque));
+
+    // The indexes come back with the table.
+    let indexes: usize = store
+        .pool()
+        .get()
+        .unwrap()
+        .query_row(
+            "select count(*) from sqlite_master where type = 'index' and tbl_name = 'tower_sessions' and sql is not null",
+            [],
+            |row| row.get(0),
+        )
+        .unwrap();
+    assert_eq!(indexes, 5);
+}
+
+#[cfg(feature = "json")]
+#[test]
+fn json_migration_refuses_to_drop_what_it_cant_rebuild() {
+    for extra in [
+        "alter table tower_sessions add column device text",
+        "create index idx_device on tower_sessions(user_id, tenant)",
+        "create trigger audit after delete on tower_sessions begin select 1; end",
+    ] {
+        let store = store();
+        {
+            let conn = store.pool().get().unwrap();
+            conn.execute_batch(
+                r#"
+                drop table tower_sessions;
+                create table tower_sessions (
+                    id text primary key not null,
+                    data blob not null,
+                    expiry_date integer not null
+                );
+                "#,
+            )
+            .unwrap();
+            store.apply_migrations(&conn, 1).unwrap();
+            conn.execute_batch(extra).unwrap();
+        }
+
+        assert!(
+            matches!(
+                store.migrate(),
+                Err(tower_sessions_core::session_store::Error::Backend(message))
+                    if message.starts_with("Invalid store configuration")
+            ),
+            "{extra}"
+        );
+        // Nothing was dropped.
+        assert_eq!(data_column_type(&store), "blob");
+        assert_eq!(
+            store
+                .pool()
+                .get()
+                .unwrap()
+                .query_row(
+                    "select count(*) from sqlite_master where tbl_name = 'tower_sessions' and sql like '%device%' or name = 'audit'",
+                    [],
+                    |row| row.get::<_, usize>(0),
+                )
+                .unwrap(),
+            1,
+            "{extra}"
+        );
+    }
+}
+
+#[tokio::test]
+async fn delete_expired_limited_deletes_one_batch_at_a_time() {
+    let store = store();
+    let live = record(Duration::hours(1));
+    store.save(&live).await.unwrap();
+    let expired: Vec<_> = (0..25).map(|_| record(Duration::seconds(-1))).collect();
+    store.save_many(&expired).await.unwrap();
+
+    assert_eq!(store.delete_expired_limited(10).await.unwrap(), 10);
+    assert_eq!(store.count_expired().await.unwrap(), 15);
+
+    assert_eq!(store.delete_expired_limited(10).await.unwrap(), 10);
+    assert_eq!(store.delete_expired_limited(10).await.unwrap(), 5);
+    assert_eq!(store.delete_expired_limited(10).await.unwrap(), 0);
+    assert_eq!(store.count().await.unwrap(), 1);
+    assert!(store.exists(&live.id).await.unwrap());
+}
+
+#[tokio::test]
+async fn delete_older_than_ignores_extended_expiries() {
+    let clock = ManualClock::new();
+    let store = store().with_clock(clock.clone());
+
+    let mut old = record(Duration::hours(1));
+    store.save(&old).await.unwrap();
+
+    clock.advance(Duration::days(31));
+    // Saving again slides the expiry forward but keeps the creation time.
+    old.expiry_date = clock.now() + Duration::hours(1);
+    store.save(&old).await.unwrap();
+    let mut fresh = record(Duration::ZERO);
+    fresh.expiry_date = clock.now() + Duration::hours(1);
+    store.save(&fresh).await.unwrap();
+
+    assert_eq!(
+        store.delete_older_than(Duration::days(30)).await.unwrap(),
+        1
+    );
+    assert!(!store.exists(&old.id).await.unwrap());
+    assert!(store.exists(&fresh.id).await.unwrap());
+}
+
+/// Opens in-memory connections and counts how many it has opened.
+#[derive(Debug)]
+struct CountingManager {
+    inner: r2d2_sqlite::SqliteConnectionManager,
+    opened: Arc<AtomicUsize>,
+}
+
+impl r2d2::ManageConnection for CountingManager {
+    type Connection = Connection;
+    type Error = r2d2_sqlite::rusqlite::Error;
+
+    fn connect(&self) -> Result<Connection, Self::Error> {
+        self.opened.fetch_add(1, Ordering::Relaxed);
+        self.inner.connect()
+    }
+
+    fn is_valid(&self, conn: &mut Connection) -> Result<(), Self::Error> {
+        self.inner.is_valid(conn)
+    }
+
+    fn has_broken(&self, conn: &mut Connection) -> bool {
+        self.inner.has_broken(conn)
+    }
+}
+
+#[tokio::test]
+async fn stores_work_over_a_custom_connection_manager() {
+    let opened = Arc::new(AtomicUsize::new(0));
+    let manager = CountingManager {
+        inner: r2d2_sqlite::SqliteConnectionManager::memory(),
+        opened: opened.clone(),
+    };
+    let pool = r2d2::Pool::builder().max_size(1).build(manager).unwrap();
+
+    let store: SqliteStore<CountingManager> = SqliteStore::new(pool);
+    store.migrate().unwrap();
+    let mut record = record(Duration::hours(1));
+    store.create(&mut record).await.unwrap();
+    assert_eq!(store.load(&record.id).await.unwrap(), Some(record.clone()));
+    store.delete(&record.id).await.unwrap();
+    store.delete_expired().await.unwrap();
+
+    assert_eq!(opened.load(Ordering::Relaxed), 1);
+}
+
+#[tokio::test]
+async fn save_all_atomic_rolls_back_on_any_failure() {
+    let store = store();
+    let existing = record(Duration::hours(1));
+    store.save(&existing).await.unwrap();
+
+    let mut updated = existing.clone();
+    updated.expiry_date += Duration::hours(1);
+    let poisoned = record(Duration::hours(1));
+    let records = [
+        record(Duration::hours(1)),
+        updated,
+        poisoned.clone(),
+        record(Duration::hours(1)),
+    ];
+
+    store
+        .pool()
+        .get()
+        .unwrap()
+        .execute_batch(&format!(
+            r#"
+            create trigger reject_poisoned before insert on tower_sessions
+            when new.id = '{}'
+            begin select raise(abort, 'poisoned'); end;
+            "#,
+            poisoned.id
+        ))
+        .unwrap();
+
+    assert!(store.save_all_atomic(&records).await.is_err());
+    assert_eq!(store.count().await.unwrap(), 1);
+    assert_eq!(store.load(&existing.id).await.unwrap(), Some(existing));
+
+    // Without the poisoned record everything lands.
+    let records = [&records[..2], &records[3..]].concat();
+    store.save_all_atomic(&records).await.unwrap();
+    for record in &records {
+        assert_eq!(store.load(&record.id).await.unwrap().as_ref(), Some(record));
+    }
+}
+
+#[tokio::test]
+async fn operation_timeout_fires_while_the_database_is_locked() {
+    use tower_sessions_core::session_store::Error;
+
+    let db = TempDb::new();
+    let store = SqliteStore::builder()
+        .path(db.path())
+        .with_pragmas(crate::PragmaConfig {
+            busy_timeout: std::time::Duration::from_secs(1),
+            ..Default::default()
+        })
+        .build()
+        .unwrap()
+        .with_operation_timeout(std::time::Duration::from_millis(50));
+    store.migrate().unwrap();
+
+    let lock = lock_for_writing(&db);
+    let started = std::time::Instant::now();
+    assert!(matches!(
+        store.save(&record(Duration::hours(1))).await,
+        Err(Error::Backend(message)) if message.starts_with("Operation timed out")
+    ));
+    assert!(started.elapsed() < std::time::Duration::from_millis(500));
+    drop(lock);
+
+    // The timed-out save may still finish on its blocking thread, but the
+    // store isn't left stuck behind it.
+    let record = record(Duration::hours(1));
+    store.save(&record).await.unwrap();
+    assert_eq!(store.load(&record.id).await.unwrap(), Some(record));
+}
+
+#[tokio::test]
+async fn expiry_of_reads_the_expiry_without_decoding() {
+    let store = store();
+    let live = record(Duration::hours(1));
+    let expired = record(Duration::seconds(-1));
+    store.save(&live).await.unwrap();
+    store.save(&expired).await.unwrap();
+
+    assert_eq!(
+        store.expiry_of(&live.id).await.unwrap(),
+        Some(live.expiry_date)
+    );
+    assert_eq!(
+        store.expiry_of(&expired.id).await.unwrap(),
+        Some(expired.expiry_date)
+    );
+    assert_eq!(store.expiry_of(&Id::default()).await.unwrap(), None);
+
+    corrupt(&store, &live.id);
+    assert_eq!(
+        store.expiry_of(&live.id).await.unwrap(),
+        Some(live.expiry_date)
+    );
+}
+
+#[tokio::test]
+async fn new_migrated_stores_are_ready_to_use() {
+    let db = TempDb::new();
+    let pool = r2d2::Pool::new(r2d2_sqlite::SqliteConnectionManager::file(db.path())).unwrap();
+
+    let store = SqliteStore::new_migrated(pool.clone()).unwrap();
+    let mut record = record(Duration::hours(1));
+    store.create(&mut record).await.unwrap();
+    assert_eq!(store.load(&record.id).await.unwrap(), Some(record.clone()));
+
+    // Migrating an up-to-date table again is harmless.
+    let store = SqliteStore::new_migrated(pool).unwrap();
+    assert_eq!(store.load(&record.id).await.unwrap(), Some(record));
+}
+
+#[cfg(feature = "tracing")]
+mod spans {
+    use std::sync::{Arc, Mutex};
+
+    use time::Duration;
+    use tower_sessions_core::{SessionStore, session::Id};
+    use tracing::{
+        Event, Metadata, Subscriber,
+        field::{Field, Visit},
+        span::{self, Attributes},
+    };
+
+    use super::{corrupt, record, store};
+
+    /// The fields of a span or event, formatted with `Debug`.
+    #[derive(Debug, Default)]
+    struct Fields(Vec<(&'static str, String)>);
+
+    impl Fields {
+        fn get(&self, name: &str) -> Option<&str> {
+            self.0
+                .iter()
+                .rev()
+                .find(|(field, _)| *field == name)
+                .map(|(_, value)| value.as_str())
+        }
+    }
+
+    impl Visit for Fields {
+        fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+            self.0.push((field.name(), format!("{value:?}")));
+        }
+    }
+
+    /// Keeps every span and event, in the order they were created, and
+    /// tracks the entered spans so `Span::current` works.
+    #[derive(Clone, Default)]
+    struct Capture {
+        spans: Arc<Mutex<Vec<(&'static Metadata<'static>, Fields)>>>,
+        events: Arc<Mutex<Vec<Fields>>>,
+        entered: Arc<Mutex<Vec<span::Id>>>,
+    }
+
+    impl Capture {
+        fn take_spans(&self) -> Vec<(&'static str, Fields)> {
+            std::mem::take(&mut *self.spans.lock().unwrap())
+                .into_iter()
+                .map(|(metadata, fields)| (metadata.name(), fields))
+                .collect()
+        }
+    }
+
+    impl Subscriber for Capture {
+        fn enabled(&self, _: &Metadata<'_>) -> bool {
+            true
+        }
+
+        fn new_span(&self, attrs: &Attributes<'_>) -> span::Id {
+            let mut fields = Fields::default();
+            attrs.record(&mut fields);
+
+            let mut spans = self.spans.lock().unwrap();
+            spans.push((attrs.metadata(), fields));
+            span::Id::from_u64(spans.len() as u64)
+        }
+
+        fn record(&self, span: &span::Id, values: &span::Record<'_>) {
+            let mut spans = self.spans.lock().unwrap();
+            if let Some((_, fields)) = spans.get_mut(span.into_u64() as usize - 1) {
+                values.record(fields);
+            }
+        }
+
+        fn record_follows_from(&self, _: &span::Id, _: &span::Id) {}
+
+        fn event(&self, event: &Event<'_>) {
+            let mut fields = Fields::default();
+            event.record(&mut fields);
+            self.events.lock().unwrap().push(fields);
+        }
+
+        fn enter(&self, span: &span::Id) {
+            self.entered.lock().unwrap().push(span.clone());
+        }
+
+        fn exit(&self, _: &span::Id) {
+            self.entered.lock().unwrap().pop();
+        }
+
+        fn current_span(&self) -> tracing_core::span::Current {
+            let entered = self.entered.lock().unwrap();
+            let spans = self.spans.lock().unwrap();
+
+            match entered.last() {
+                Some(span) => tracing_core::span::Current::new(
+                    span.clone(),
+                    spans[span.into_u64() as usize - 1].0,
+                ),
+                None => tracing_core::span::Current::none(),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn operations_emit_spans_without_session_ids() {
+        let capture = Capture::default();
+        let _guard = tracing::subscriber::set_default(capture.clone());
+        let store = store();
+
+        let missing = Id::default();
+        store.load(&missing).await.unwrap();
+        let spans = capture.take_spans();
+        assert_eq!(spans.len(), 1);
+        let (name, fields) = &spans[0];
+        assert_eq!(*name, "session.load");
+        assert_eq!(fields.get("hit"), Some("false"));
+        let id_hash = fields.get("id_hash").unwrap();
+        assert!(!id_hash.contains(&missing.to_string()));
+        // The digest is pinned, so it doesn't change between builds.
+        assert_eq!(crate::id_hash(&Id(0)), "88201fb960ff6465");
+
+        let mut record = record(Duration::hours(1));
+        store.create(&mut record).await.unwrap();
+        store.load(&record.id).await.unwrap();
+        store.save(&record).await.unwrap();
+        store.delete(&record.id).await.unwrap();
+        let spans = capture.take_spans();
+        let names: Vec<_> = spans.iter().map(|(name, _)| *name).collect();
+        assert_eq!(
+            names,
+            [
+                "session.create",
+                "session.load",
+                "session.save",
+                "session.delete"
+            ]
+        );
+        assert_eq!(spans[1].1.get("hit"), Some("true"));
+        // Every span for one session carries the same hash.
+        let id_hash = spans[0].1.get("id_hash").unwrap();
+        assert!(
+            spans
+                .iter()
+                .all(|(_, fields)| fields.get("id_hash") == Some(id_hash))
+        );
+        for (_, fields) in &spans {
+            assert!(
+                fields
+                    .0
+                    .iter()
+                    .all(|(_, value)| !value.contains(&record.id.to_string()))
+            );
+        }
+
+        // Failures are reported as an error event inside the span.
+        store.save(&record).await.unwrap();
+        corrupt(&store, &record.id);
+        assert!(store.load(&record.id).await.is_err());
+        assert!(
+            capture
+                .events
+                .lock()
+                .unwrap()
+                .iter()
+                .any(|fields| fields.get("error").is_some())
+        );
+    }
+}
+
+#[tokio::test]
+async fn save_if_unchanged_rejects_stale_writes() {
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let listener = {
+        let events = events.clone();
+        Arc::new(move |event| events.lock().unwrap().push(event))
+    };
+    let store = store().with_change_listener(listener);
+    let mut record = record(Duration::hours(1));
+    assert!(!store.save_if_unchanged(&record, 0).await.unwrap());
+    assert_eq!(store.version_of(&record.id).await.unwrap(), None);
+
+    store.save(&record).await.unwrap();
+    let read = store.version_of(&record.id).await.unwrap().unwrap();
+
+    // Another writer gets in between the read and the write.
+    store.save(&record).await.unwrap();
+    assert_eq!(store.version_of(&record.id).await.unwrap(), Some(read + 1));
+
+    let mut stale = record.clone();
+    stale.expiry_date += Duration::days(1);
+    events.lock().unwrap().clear();
+    assert!(!store.save_if_unchanged(&stale, read).await.unwrap());
+    assert_eq!(store.load(&record.id).await.unwrap(), Some(record.clone()));
+    assert!(events.lock().unwrap().is_empty());
+
+    record.expiry_date += Duration::hours(1);
+    assert!(store.save_if_unchanged(&record, read + 1).await.unwrap());
+    assert_eq!(store.load(&record.id).await.unwrap(), Some(record.clone()));
+    assert_eq!(store.version_of(&record.id).await.unwrap(), Some(read + 2));
+    assert_eq!(
+        *events.lock().unwrap(),
+        [crate::SessionEvent::Saved(record.id)]
+    );
+}
+
+#[tokio::test]
+async fn binary_ids_round_trip() {
+    let store = SqliteStore::in_memory().unwrap().with_binary_ids(true);
+    store.migrate().unwrap();
+
+    let records: Vec<_> = [Id(0), Id(1), Id(-1), Id(i128::MAX), Id::default()]
+        .into_iter()
+        .map(|id| Record {
+            id,
+            ..record(Duration::hours(1))
+        })
+        .collect();
+    for record in &records {
+        store.save(record).await.unwrap();
+    }
+
+    {
+        let conn = store.pool().get().unwrap();
+        let stored: Vec<Vec<u8>> = conn
+            .prepare("select id from tower_sessions where typeof(id) = 'blob' order by id")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(stored.len(), records.len());
+        assert!(stored.contains(&1i128.to_le_bytes().to_vec()));
+    }
+
+    for record in &records {
+        assert_eq!(store.load(&record.id).await.unwrap().as_ref(), Some(record));
+        assert!(store.exists(&record.id).await.unwrap());
+    }
+
+    let mut created = record(Duration::hours(1));
+    store.create(&mut created).await.unwrap();
+    assert_eq!(
+        store.load(&created.id).await.unwrap(),
+        Some(created.clone())
+    );
+
+    store.delete(&records[2].id).await.unwrap();
+    assert_eq!(store.load(&records[2].id).await.unwrap(), None);
+    assert_eq!(store.count().await.unwrap(), records.len() as i64);
+}
+
+#[tokio::test]
+async fn touch_many_moves_only_the_given_expiries() {
+    let store = store();
+    let records: Vec<_> = (0..10).map(|_| record(Duration::hours(1))).collect();
+    store.save_many(&records).await.unwrap();
+
+    let (touched, untouched) = records.split_at(5);
+    let expiry = OffsetDateTime::now_utc() + Duration::days(7);
+    let ids: Vec<_> = touched
+        .iter()
+        .map(|record| record.id)
+        .chain([Id::default()])
+        .collect();
+    assert_eq!(store.touch_many(&ids, expiry).await.unwrap(), 5);
+
+    for record in touched {
+        assert_eq!(store.expiry_of(&record.id).await.unwrap(), Some(expiry));
+        assert_eq!(
+            store.load(&record.id).await.unwrap().unwrap().expiry_date,
+            expiry
+        );
+    }
+    for record in untouched {
+        assert_eq!(
+            store.expiry_of(&record.id).await.unwrap(),
+            Some(record.expiry_date)
+        );
+    }
+
+    let ids: Vec<_> = records.iter().map(|record| record.id).collect();
+    let loaded = store.load_map(&ids).await.unwrap();
+    assert_eq!(loaded.len(), 10);
+    for record in touched {
+        assert_eq!(loaded[&record.id].expiry_date, expiry);
+    }
+    for record in untouched {
+        assert_eq!(loaded[&record.id], *record);
+    }
+
+    // More ids than fit in one `in (...)` chunk.
+    let many: Vec<_> = (0..1000).map(|_| record(Duration::hours(1))).collect();
+    store.save_many(&many).await.unwrap();
+    let ids: Vec<_> = many.iter().map(|record| record.id).collect();
+    assert_eq!(store.touch_many(&ids, expiry).await.unwrap(), 1000);
+    assert_eq!(store.touch_many(&[], expiry).await.unwrap(), 0);
+}
+
+#[test]
+fn verify_schema_reports_the_first_discrepancy() {
+    use crate::SqliteStoreError;
+
+    let store = SqliteStore::in_memory().unwrap();
+    let mismatch =
+        |store: &SqliteStore| match store.verify_schema_with_conn(&store.pool().get().unwrap()) {
+            Err(SqliteStoreError::SchemaMismatch { expected, found }) => (expected, found),
+            other => panic!("expected a schema mismatch, got {other:?}"),
+        };
+
+    assert_eq!(
+        mismatch(&store),
+        (
+            "a table named tower_sessions".to_owned(),
+            "no such table".to_owned()
+        )
+    );
+
+    store
+        .pool()
+        .get()
+        .unwrap()
+        .execute_batch(&format!(
+            "create table tower_sessions (id text primary key, data {data}, expiry_date text)",
+            data = store.data_column_type()
+        ))
+        .unwrap();
+    assert_eq!(
+        mismatch(&store),
+        (
+            "column expiry_date integer".to_owned(),
+            "column expiry_date text".to_owned()
+        )
+    );
+
+    store
+        .pool()
+        .get()
+        .unwrap()
+        .execute_batch(&format!(
+            r#"
+            drop table tower_sessions;
+            create table tower_sessions (id text primary key, data {data}, expiry_date integer);
+            "#,
+            data = store.data_column_type()
+        ))
+        .unwrap();
+    assert_eq!(
+        mismatch(&store),
+        (
+            "column user_id text".to_owned(),
+            "no column user_id".to_owned()
+        )
+    );
+}
+
+#[test]
+fn schema_verification_runs_after_migrate_when_enabled() {
+    let store = store();
+    store
+        .pool()
+        .get()
+        .unwrap()
+        .execute_batch("alter table tower_sessions rename column user_id to owner")
+        .unwrap();
+
+    // Migrations are up to date, so nothing repairs the table.
+    store.migrate().unwrap();
+    let store = store.with_schema_verification(true);
+    let err = store.migrate().unwrap_err();
+    assert!(err.to_string().contains("no column user_id"), "{err}");
+}
+
+#[tokio::test]
+async fn close_checkpoints_and_truncates_the_wal() {
+    let db = TempDb::new();
+    let store = impatient_store(&db);
+    let records: Vec<_> = (0..100).map(|_| record(Duration::hours(1))).collect();
+    store.save_many(&records).await.unwrap();
+
+    let wal = || {
+        let mut path = db.path().to_owned().into_os_string();
+        path.push("-wal");
+        std::fs::metadata(path).unwrap().len()
+    };
+    assert!(wal() > 0);
+
+    // A reader in the middle of a transaction keeps the log in use.
+    let reader = Connection::open(db.path()).unwrap();
+    reader
+        .execute_batch("begin; select count(*) from tower_sessions;")
+        .unwrap();
+    assert!(store.clone().close().await.is_err());
+    reader.execute_batch("commit").unwrap();
+
+    // The clone keeps the pool, and with it the log, open.
+    let clone = store.clone();
+    store.close().await.unwrap();
+    assert_eq!(wal(), 0);
+
+    drop(reader);
+    let conn = Connection::open(db.path()).unwrap();
+    let count: i64 = conn
+        .query_row("select count(*) from tower_sessions", [], |row| row.get(0))
+        .unwrap();
+    assert_eq!(count, 100);
+    drop(clone);
+}
+
+#[tokio::test]
+async fn every_collision_strategy_recovers_from_a_taken_id() {
+    use crate::CollisionStrategy;
+
+    let store = store();
+    let taken = record(Duration::hours(1));
+    store.save(&taken).await.unwrap();
+
+    for strategy in [
+        CollisionStrategy::PreCheck,
+        CollisionStrategy::OnConflictRetry,
+        CollisionStrategy::AssumeUnique,
+    ] {
+        let store = store.clone().with_collision_strategy(strategy);
+
+        let mut fresh = record(Duration::hours(1));
+        let id = fresh.id;
+        store.create(&mut fresh).await.unwrap();
+        assert_eq!(fresh.id, id, "{strategy:?}");
+
+        let mut colliding = record(Duration::hours(1));
+        colliding.id = taken.id;
+        store.create(&mut colliding).await.unwrap();
+        assert_ne!(colliding.id, taken.id, "{strategy:?}");
+        assert_eq!(
+            store.load(&colliding.id).await.unwrap(),
+            Some(colliding),
+            "{strategy:?}"
+        );
+    }
+
+    assert_eq!(store.load(&taken.id).await.unwrap(), Some(taken));
+    assert_eq!(store.count().await.unwrap(), 7);
+}
+
+#[test]
+fn schema_ddl_matches_the_migrated_table() {
+    let store = store().with_table_name("my_sessions");
+    let ddl = store.schema_ddl().unwrap();
+    store.migrate().unwrap();
+
+    let recorded: Vec<String> = store
+        .pool()
+        .get()
+        .unwrap()
+        .prepare(
+            r#"
+            select sql || ';' from sqlite_master
+            where tbl_name = 'my_sessions' and sql is not null
+            order by type = 'table' desc, name
+            "#,
+        )
+        .unwrap()
+        .query_map([], |row| row.get(0))
+        .unwrap()
+        .collect::<Result<_, _>>()
+        .unwrap();
+    assert_eq!(recorded.len(), 6);
+    assert_eq!(ddl, recorded.join("\n"));
+}
+
+#[tokio::test]
+async fn strict_tables_reject_mistyped_values() {
+    let insert_text_expiry = |store: &SqliteStore| {
+        store.pool().get().unwrap().execute(
+            "insert into tower_sessions (id, data, expiry_date) values ('id', x'00', 'tomorrow')",
+            [],
+        )
+    };
+
+    let lax = store();
+    assert!(insert_text_expiry(&lax).is_ok());
+
+    let strict = SqliteStore::in_memory().unwrap().with_strict_tables(true);
+    strict.migrate().unwrap();
+    let err = insert_text_expiry(&strict).unwrap_err();
+    assert_eq!(
+        err.sqlite_error_code(),
+        Some(r2d2_sqlite::rusqlite::ErrorCode::ConstraintViolation)
+    );
+
+    let record = record(Duration::hours(1));
+    strict.save(&record).await.unwrap();
+    assert_eq!(strict.load(&record.id).await.unwrap(), Some(record));
+
+    // Too old a SQLite gets a clear error instead of a syntax error.
+    let old = on_old_sqlite(SqliteStore::in_memory().unwrap().with_strict_tables(true));
+    assert!(matches!(
+        old.migrate_with_conn(&mut old.conn().unwrap()),
+        Err(crate::SqliteStoreError::UnsupportedSqlite {
+            required: "3.37",
+            ..
+        })
+    ));
+}
+
+#[cfg(feature = "json")]
+#[test]
+fn json_strict_tables_keep_a_blob_data_column() {
+    let store = SqliteStore::in_memory().unwrap().with_strict_tables(true);
+    store.migrate().unwrap();
+
+    assert_eq!(data_column_type(&store), "blob");
+    store.verify_schema().unwrap();
+}
+
+#[test]
+fn schema_ddl_fails_on_configurations_migrate_rejects() {
+    let strict = on_old_sqlite(SqliteStore::in_memory().unwrap().with_strict_tables(true));
+    assert!(matches!(
+        strict.schema_ddl(),
+        Err(crate::SqliteStoreError::UnsupportedSqlite { .. })
+    ));
+
+    let binary_strict = SqliteStore::in_memory()
+        .unwrap()
+        .with_strict_tables(true)
+        .with_binary_ids(true);
+    assert!(matches!(
+        binary_strict.schema_ddl(),
+        Err(crate::SqliteStoreError::InvalidConfig(_))
+    ));
+
+    let ddl = SqliteStore::in_memory()
+        .unwrap()
+        .with_strict_tables(true)
+        .schema_ddl()
+        .unwrap();
+    assert!(ddl.contains(") strict;"));
+}
+
+#[tokio::test]
+async fn import_rejects_a_corrupt_archive_without_writing() {
+    let source = store();
+    let mut records = [record(Duration::hours(1)), record(Duration::hours(2))];
+    for record in &mut records {
+        source.create(record).await.unwrap();
+    }
+
+    let mut archive = Vec::new();
+    assert_eq!(source.export_to_writer(&mut archive).await.unwrap(), 2);
+
+    // A frame claiming nearly 4 GiB after two valid ones.
+    let mut corrupt = archive.clone();
+    corrupt.extend_from_slice(&u32::MAX.to_le_bytes());
+    corrupt.extend_from_slice(&[0x93, 0xa1]);
+
+    let target = store();
+    assert!(
+        target
+            .import_from_reader(&corrupt[..], false)
+            .await
+            .is_err()
+    );
+    assert_eq!(target.count().await.unwrap(), 0);
+
+    assert_eq!(
+        target
+            .import_from_reader(&archive[..], false)
+            .await
+            .unwrap(),
+        2
+    );
+    for record in records {
+        assert_eq!(target.load(&record.id).await.unwrap(), Some(record));
+    }
+}
+
+#[tokio::test]
+async fn import_skips_or_overwrites_existing_sessions() {
+    let source = store();
+    let (kept, new) = (record(Duration::hours(1)), record(Duration::seconds(-1)));
+    source.save(&kept).await.unwrap();
+    source.save(&new).await.unwrap();
+    let mut archive = Vec::new();
+    source.export_to_writer(&mut archive).await.unwrap();
+
+    let target = store();
+    let mut local = kept.clone();
+    local.expiry_date += Duration::hours(1);
+    target.save(&local).await.unwrap();
+
+    assert_eq!(
+        target
+            .import_from_reader(&archive[..], false)
+            .await
+            .unwrap(),
+        1
+    );
+    assert_eq!(target.load(&kept.id).await.unwrap(), Some(local));
+    // Expired sessions travel too.
+    assert_eq!(
+        target.expiry_of(&new.id).await.unwrap(),
+        Some(new.expiry_date)
+    );
+
+    assert_eq!(
+        target.import_from_reader(&archive[..], true).await.unwrap(),
+        2
+    );
+    assert_eq!(target.load(&kept.id).await.unwrap(), Some(kept));
+    assert_eq!(target.count().await.unwrap(), 2);
+}
+
+#[test]
+fn secure_delete_and_foreign_keys_reach_every_pooled_connection() {
+    let db = TempDb::new();
+    let build = |secure_delete, foreign_keys| {
+        SqliteStore::builder()
+            .path(db.path())
+            .with_pragmas(
+                crate::PragmaConfig::default()
+                    .with_secure_delete(secure_delete)
+                    .with_foreign_keys(foreign_keys),
+            )
+            .max_connections(3)
+            .build()
+            .unwrap()
+    };
+
+    let store = build(true, true);
+    let conns: Vec<_> = (0..3).map(|_| store.pool().get().unwrap()).collect();
+    for conn in &conns {
+        assert_eq!(pragma(conn, "secure_delete"), "1");
+        assert_eq!(pragma(conn, "foreign_keys"), "1");
+    }
+    drop((conns, store));
+
+    let store = build(false, false);
+    let conn = store.pool().get().unwrap();
+    assert_eq!(pragma(&conn, "secure_delete"), "0");
+    assert_eq!(pragma(&conn, "foreign_keys"), "0");
+}
+
+#[test]
+fn concurrent_migrations_converge() {
+    let db = TempDb::new();
+    let barrier = Arc::new(std::sync::Barrier::new(8));
+
+    let threads: Vec<_> = (0..8)
+        .map(|_| {
+            let store = SqliteStore::from_path(db.path()).unwrap();
+            let barrier = barrier.clone();
+            std::thread::spawn(move || {
+                barrier.wait();
+                store.migrate()
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap().unwrap();
+    }
+
+    let store = SqliteStore::from_path(db.path()).unwrap();
+    store.verify_schema().unwrap();
+    let conn = store.pool().get().unwrap();
+    let versions: Vec<(String, usize)> = conn
+        .prepare("select table_name, version from tower_sessions_schema_versions")
+        .unwrap()
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+        .unwrap()
+        .collect::<Result<_, _>>()
+        .unwrap();
+    assert_eq!(
+        versions,
+        [("tower_sessions".to_owned(), crate::MIGRATIONS.len())]
+    );
+}
+
+#[tokio::test]
+async fn expiring_within_returns_live_sessions_inside_the_window() {
+    let clock = ManualClock::new();
+    let store = store().with_clock(clock.clone());
+
+    let at = |offset: Duration| {
+        let mut record = record(Duration::ZERO);
+        record.expiry_date = clock.now() + offset;
+        record
+    };
+    let inside = [
+        at(Duration::nanoseconds(1)),
+        at(Duration::minutes(5)),
+        at(Duration::minutes(10)),
+    ];
+    let outside = [
+        at(Duration::minutes(-1)),
+        at(Duration::ZERO),
+        at(Duration::minutes(10) + Duration::nanoseconds(1)),
+        at(Duration::days(1)),
+    ];
+    for record in inside.iter().chain(&outside) {
+        store.save(record).await.unwrap();
+    }
+
+    let expected = sorted(inside.iter().map(|record| record.id).collect());
+    assert_eq!(
+        sorted(store.expiring_within(Duration::minutes(10)).await.unwrap()),
+        expected
+    );
+    assert!(
+        store
+            .expiring_within(Duration::ZERO)
+            .await
+            .unwrap()
+            .is_empty()
+    );
+}
+
+#[tokio::test]
+async fn load_map_holds_exactly_the_live_sessions() {
+    let store = store();
+    let live = [record(Duration::hours(1)), record(Duration::hours(2))];
+    let expired = record(Duration::seconds(-1));
+    for record in live.iter().chain([&expired]) {
+        store.save(record).await.unwrap();
+    }
+
+    let ids = [
+        live[0].id,
+        expired.id,
+        Id::default(),
+        live[1].id,
+        live[0].id,
+    ];
+    let map = store.load_map(&ids).await.unwrap();
+
+    assert_eq!(map.len(), 2);
+    for record in &live {
+        assert_eq!(map.get(&record.id), Some(record));
+    }
+    assert!(store.load_map(&[]).await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn bulk_reads_key_sessions_by_their_row() {
+    use tower_sessions_core::session_store::Error;
+
+    let store = store();
+    let (kept, moved) = (record(Duration::hours(1)), record(Duration::hours(1)));
+    store.save(&kept).await.unwrap();
+    store.save(&moved).await.unwrap();
+
+    // A row whose id was changed by hand still holds the old id's record.
+    let stray = Id::default();
+    store
+        .pool()
+        .get()
+        .unwrap()
+        .execute(
+            "update tower_sessions set id = ?1 where id = ?2",
+            [stray.to_string(), moved.id.to_string()],
+        )
+        .unwrap();
+
+    let ids = [kept.id, moved.id, stray];
+    assert_eq!(
+        store.load_many(&ids).await.unwrap(),
+        [(kept.id, kept.clone())]
+    );
+    let map = store.load_map(&ids).await.unwrap();
+    assert_eq!(map.len(), 1);
+    assert_eq!(map.get(&kept.id), Some(&kept));
+
+    let mut iterated: Vec<_> = store.iter_records().collect();
+    iterated.sort_by_key(|entry| entry.as_ref().is_err());
+    assert_eq!(*iterated[0].as_ref().unwrap(), (kept.id, kept));
+    assert!(
+        matches!(&iterated[1], Err(Error::Decode(message)) if message.contains(&stray.to_string()))
+    );
+}
+
+#[tokio::test]
+async fn expiry_inclusivity_decides_sessions_expiring_exactly_now() {
+    let clock = ManualClock::new();
+    let mut record = record(Duration::ZERO);
+    record.expiry_date = clock.now();
+
+    for inclusive in [false, true] {
+        let store = store()
+            .with_clock(clock.clone())
+            .with_expiry_inclusive(inclusive);
+        store.save(&record).await.unwrap();
+
+        let live = inclusive;
+        assert_eq!(store.load(&record.id).await.unwrap().is_some(), live);
+        assert_eq!(store.exists(&record.id).await.unwrap(), live);
+        assert_eq!(store.count_active().await.unwrap(), live as i64);
+        assert_eq!(store.active_ids().await.unwrap().len(), live as usize);
+        assert_eq!(store.count_expired().await.unwrap(), !live as i64);
+
+        store.delete_expired().await.unwrap();
+        assert_eq!(store.count().await.unwrap(), live as i64);
+    }
+}
+
+#[tokio::test]
+async fn reserve_id_returns_an_unused_id_without_inserting() {
+    let store = store();
+    let records: Vec<_> = (0..10).map(|_| record(Duration::hours(1))).collect();
+    store.save_many(&records).await.unwrap();
+
+    let id = store.reserve_id().await.unwrap();
+    assert!(records.iter().all(|record| record.id != id));
+    assert_eq!(store.load_raw(&id).await.unwrap(), None);
+    assert_eq!(store.count().await.unwrap(), 10);
+
+    let mut record = record(Duration::hours(1));
+    record.id = id;
+    store.create(&mut record).await.unwrap();
+    assert_eq!(record.id, id);
+}
+
+#[test]
+fn pool_state_tracks_checked_out_connections() {
+    let store = SqliteStore::builder()
+        .in_memory_shared("pool_state_tracks_checked_out_connections")
+        .max_connections(3)
+        .min_idle(3)
+        .build()
+        .unwrap();
+    let state = store.pool_state();
+    assert_eq!((state.connections, state.idle_connections), (3, 3));
+
+    let held: Vec<_> = (0..2).map(|_| store.pool().get().unwrap()).collect();
+    let state = store.pool_state();
+    assert_eq!(state.idle_connections, 1);
+    assert_eq!(state.in_use(), 2);
+
+    drop(held);
+    assert_eq!(store.pool_state().idle_connections, 3);
+}
+
+#[tokio::test]
+async fn unchanged_saves_skip_the_write() {
+    let store = store().with_skip_unchanged_saves(true);
+    let mut record = record(Duration::hours(1));
+    assert_eq!(store.save_returning_changes(&record).await.unwrap(), 1);
+    let version = store.version_of(&record.id).await.unwrap();
+
+    assert_eq!(store.save_returning_changes(&record).await.unwrap(), 0);
+    assert_eq!(store.version_of(&record.id).await.unwrap(), version);
+
+    // A new expiry alone only moves the expiry.
+    let raw = store.load_raw(&record.id).await.unwrap();
+    record.expiry_date += Duration::hours(1);
+    assert_eq!(store.save_returning_changes(&record).await.unwrap(), 1);
+    assert_eq!(store.load_raw(&record.id).await.unwrap(), raw);
+    assert_eq!(
+        store.expiry_of(&record.id).await.unwrap(),
+        Some(record.expiry_date)
+    );
+
+    // Reads take the expiry from the row, not the blob it didn't rewrite.
+    assert_eq!(store.load(&record.id).await.unwrap(), Some(record.clone()));
+    assert_eq!(
+        store.load_detailed(&record.id).await.unwrap(),
+        crate::LoadOutcome::Found(record.clone())
+    );
+    assert_eq!(
+        store.load_many(&[record.id]).await.unwrap(),
+        [(record.id, record.clone())]
+    );
+    let iterated: Vec<_> = store.iter_records().collect::<Result<_, _>>().unwrap();
+    assert_eq!(iterated, [(record.id, record.clone())]);
+
+    record
+        .data
+        .insert("user".to_owned(), serde_json::json!("bob"));
+    assert_eq!(store.save_returning_changes(&record).await.unwrap(), 1);
+    assert_ne!(store.version_of(&record.id).await.unwrap(), version);
+    assert_eq!(store.load(&record.id).await.unwrap(), Some(record));
+}
+
+#[test]
+fn pragmas_cover_the_attached_database() {
+    let (main, attached) = (TempDb::new(), TempDb::new());
+    let store = SqliteStore::builder()
+        .path(main.path())
+        .attach(attached.path(), "sessions")
+        .with_pragmas(
+            crate::PragmaConfig::default()
+                .with_synchronous(crate::Synchronous::Off)
+                .with_secure_delete(true)
+                .with_page_size(8192)
+                .with_cache_size(-4096),
+        )
+        .auto_migrate()
+        .build()
+        .unwrap();
+
+    let conn = store.pool().get().unwrap();
+    for schema in ["main", "sessions"] {
+        let pragma = |name: &str| -> String {
+            conn.query_row(&format!("pragma {schema}.{name}"), [], |row| {
+                Ok(match row.get_ref(0)? {
+                    r2d2_sqlite::rusqlite::types::ValueRef::Integer(n) => n.to_string(),
+                    value => value.as_str().unwrap().to_lowercase(),
+                })
+            })
+            .unwrap()
+        };
+
+        assert_eq!(pragma("journal_mode"), "wal", "{schema}");
+        assert_eq!(pragma("synchronous"), "0", "{schema}");
+        assert_eq!(pragma("secure_delete"), "1", "{schema}");
+        assert_eq!(pragma("page_size"), "8192", "{schema}");
+        assert_eq!(pragma("cache_size"), "-4096", "{schema}");
+    }
+}
+
+#[tokio::test]
+async fn attached_stores_write_to_the_attached_file() {
+    let (main, attached) = (TempDb::new(), TempDb::new());
+    let open = || {
+        SqliteStore::builder()
+            .path(main.path())
+            .attach(attached.path(), "sessions")
+            .auto_migrate()
+            .build()
+            .unwrap()
+    };
+
+    let store = open();
+    let mut record = record(Duration::hours(1));
+    store.create(&mut record).await.unwrap();
+    drop(store);
+
+    let tables = |db: &TempDb| -> Vec<String> {
+        Connection::open(db.path())
+            .unwrap()
+            .prepare("select name from sqlite_master where type = 'table' order by name")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap()
+    };
+    assert!(tables(&main).is_empty());
+    assert_eq!(
+        tables(&attached),
+        ["tower_sessions", "tower_sessions_schema_versions"]
+    );
+    let count: i64 = Connection::open(attached.path())
+        .unwrap()
+        .query_row("select count(*) from tower_sessions", [], |row| row.get(0))
+        .unwrap();
+    assert_eq!(count, 1);
+
+    assert_eq!(open().load(&record.id).await.unwrap(), Some(record));
+}
+
+#[tokio::test]
+async fn rotate_all_ids_keeps_every_session_under_a_new_id() {
+    let store = store();
+    let records: Vec<_> = (0..20)
+        .map(|n| record_of(&format!("user{n}"), Duration::hours(1)))
+        .collect();
+    store.save_many(&records).await.unwrap();
+
+    assert_eq!(store.rotate_all_ids().await.unwrap(), 20);
+
+    for record in &records {
+        assert_eq!(store.load_raw(&record.id).await.unwrap(), None);
+    }
+    let ids = store.active_ids().await.unwrap();
+    let rotated = store.load_map(&ids).await.unwrap();
+    assert_eq!(rotated.len(), 20);
+    for record in &records {
+        let (id, moved) = rotated
+            .iter()
+            .find(|(_, moved)| user_field(moved) == user_field(record))
+            .unwrap();
+        assert_eq!(moved.id, *id);
+        assert_eq!(moved.data, record.data);
+        assert_eq!(moved.expiry_date, record.expiry_date);
+    }
+
+    assert_eq!(store.rotate_all_ids().await.unwrap(), 20);
+    assert!(store.load_map(&ids).await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn create_unchecked_fails_on_a_taken_id() {
+    use tower_sessions_core::session_store::Error;
+
+    let store = store();
+    let record = record(Duration::hours(1));
+    store.create_unchecked(&record).await.unwrap();
+    assert_eq!(store.load(&record.id).await.unwrap(), Some(record.clone()));
+
+    let mut duplicate = self::record(Duration::days(1));
+    duplicate.id = record.id;
+    assert!(matches!(
+        store.create_unchecked(&duplicate).await,
+        Err(Error::Backend(message)) if message.contains(&record.id.to_string())
+    ));
+    assert!(matches!(
+        store.create_unchecked_with_conn(&duplicate),
+        Err(crate::SqliteStoreError::DuplicateId(id)) if id == record.id
+    ));
+    assert_eq!(store.load(&record.id).await.unwrap(), Some(record));
+}
+
+#[tokio::test]
+async fn tenant_operations_stay_within_their_tenant() {
+    let store = store().with_tenant_extractor(|record| {
+        record
+            .data
+            .get("tenant")
+            .and_then(|tenant| tenant.as_str())
+            .map(str::to_owned)
+    });
+    let of = |tenant: Option<&str>, expires_in| {
+        let mut record = record(expires_in);
+        if let Some(tenant) = tenant {
+            record
+                .data
+                .insert("tenant".to_owned(), serde_json::json!(tenant));
+        }
+        record
+    };
+
+    let acme = [
+        of(Some("acme"), Duration::hours(1)),
+        of(Some("acme"), Duration::hours(2)),
+    ];
+    let acme_expired = of(Some("acme"), Duration::seconds(-1));
+    let globex = of(Some("globex"), Duration::hours(1));
+    let untenanted = of(None, Duration::hours(1));
+    for record in acme.iter().chain([&acme_expired, &globex, &untenanted]) {
+        store.save(record).await.unwrap();
+    }
+
+    assert_eq!(store.count_for_tenant("acme").await.unwrap(), 2);
+    assert_eq!(store.count_for_tenant("globex").await.unwrap(), 1);
+    assert_eq!(store.count_for_tenant("initech").await.unwrap(), 0);
+    assert_eq!(
+        sorted(store.active_ids_for_tenant("acme").await.unwrap()),
+        sorted(acme.iter().map(|record| record.id).collect())
+    );
+
+    assert_eq!(store.delete_for_tenant("acme").await.unwrap(), 3);
+    assert_eq!(store.count_for_tenant("acme").await.unwrap(), 0);
+    assert_eq!(
+        store.active_ids_for_tenant("globex").await.unwrap(),
+        [globex.id]
+    );
+    assert_eq!(store.count().await.unwrap(), 2);
+    assert!(store.exists(&untenanted.id).await.unwrap());
+}
+
+/// Writes the id's raw bytes ahead of the MessagePack record, so encodings
+/// start with an arbitrary byte, like bincode's.
+#[derive(Debug)]
+struct IdPrefixedSerializer;
+
+impl crate::SessionSerializer for IdPrefixedSerializer {
+    fn encode(&self, record: &Record) -> Result<Vec<u8>, crate::SqliteStoreError> {
+        let mut bytes = record.id.0.to_le_bytes().to_vec();
+        bytes.extend(rmp_serde::to_vec(record)?);
+        Ok(bytes)
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<Record, crate::SqliteStoreError> {
+        let record = bytes
+            .get(16..)
+            .ok_or_else(|| crate::SqliteStoreError::Deserialize("too short".into()))?;
+        Ok(rmp_serde::from_slice(record)?)
+    }
+}
+
+#[tokio::test]
+async fn custom_serializer_output_is_tagged() {
+    let store = store().with_serializer(IdPrefixedSerializer);
+
+    let mut records = Vec::new();
+    for byte in 0..=0x1f_u8 {
+        let mut record = record(Duration::hours(1));
+        record.id = Id(i128::from_le_bytes([byte; 16]));
+        store.save(&record).await.unwrap();
+        records.push(record);
+    }
+
+    for record in &records {
+        assert_eq!(store.load(&record.id).await.unwrap().as_ref(), Some(record));
+
+        let raw = store.load_raw(&record.id).await.unwrap().unwrap();
+        assert_eq!(raw[0], crate::PLAIN_MARKER);
+    }
+}
+
+#[tokio::test]
+async fn builtin_serializer_output_is_untagged_by_default() {
+    let store = store();
+    let mut record = record(Duration::hours(1));
+    store.create(&mut record).await.unwrap();
+
+    let raw = store.load_raw(&record.id).await.unwrap().unwrap();
+    #[cfg(not(feature = "json"))]
+    assert_eq!(raw, rmp_serde::to_vec(&record).unwrap());
+    #[cfg(feature = "json")]
+    assert_eq!(raw, serde_json::to_vec(&record).unwrap());
+}
+
+#[tokio::test]
+async fn format_tags_tag_new_rows_and_read_legacy_ones() {
+    let store = store().with_format_tags(true);
+
+    let mut tagged = record(Duration::hours(1));
+    store.create(&mut tagged).await.unwrap();
+    let raw = store.load_raw(&tagged.id).await.unwrap().unwrap();
+    assert_eq!(raw[0], crate::PLAIN_MARKER);
+    assert_eq!(store.load(&tagged.id).await.unwrap(), Some(tagged));
+
+    let legacy = record(Duration::hours(1));
+    let bytes = rmp_serde::to_vec(&legacy).unwrap();
+    store
+        .save_raw(&legacy.id, &bytes, legacy.expiry_date)
+        .await
+        .unwrap();
+    assert_eq!(store.load(&legacy.id).await.unwrap(), Some(legacy));
+
+    let unknown = record(Duration::hours(1));
+    store
+        .save_raw(&unknown.id, &[0x1f, 0x90], unknown.expiry_date)
+        .await
+        .unwrap();
+    let err = store.decode_record(&[0x1f, 0x90]).unwrap_err();
+    assert!(matches!(err, crate::SqliteStoreError::UnknownFormat(0x1f)));
+    assert!(store.load(&unknown.id).await.is_err());
+}
+
+#[tokio::test]
+async fn sessions_migrate_from_the_sqlx_store_table() {
+    let store = store();
+    let mut by_seconds = record_of("alice", Duration::ZERO);
+    by_seconds.expiry_date =
+        OffsetDateTime::now_utc().replace_nanosecond(0).unwrap() + Duration::hours(1);
+    let mut by_string = record_of("bob", Duration::ZERO);
+    by_string.expiry_date = OffsetDateTime::from_unix_timestamp(1_893_549_845).unwrap(); // 2030-01-02T02:04:05Z
+
+    {
+        let conn = store.pool().get().unwrap();
+        conn.execute_batch(
+            r#"
+            create table sqlx_sessions (
+                id text primary key not null,
+                data blob not null,
+                expiry_date integer not null
+            );
+            create table broken_sessions as select * from sqlx_sessions;
+            "#,
+        )
+        .unwrap();
+        for (record, expiry) in [
+            (
+                &by_seconds,
+                by_seconds.expiry_date.unix_timestamp().to_string(),
+            ),
+            (&by_string, "2030-01-02T03:04:05+01:00".to_owned()),
+        ] {
+            conn.execute(
+                "insert into sqlx_sessions values (?1, ?2, ?3)",
+                r2d2_sqlite::rusqlite::params![
+                    record.id.to_string(),
+                    rmp_serde::to_vec(record).unwrap(),
+                    // Seconds arrive as an integer thanks to column affinity.
+                    expiry
+                ],
+            )
+            .unwrap();
+        }
+        conn.execute_batch(
+            r#"
+            insert into broken_sessions select * from sqlx_sessions;
+            insert into broken_sessions values ('broken', x'c1', 0);
+            "#,
+        )
+        .unwrap();
+    }
+
+    assert!(
+        store
+            .migrate_from_sqlx_store("broken_sessions")
+            .await
+            .is_err()
+    );
+    assert_eq!(store.count().await.unwrap(), 0);
+
+    assert_eq!(
+        store
+            .migrate_from_sqlx_store("sqlx_sessions")
+            .await
+            .unwrap(),
+        2
+    );
+    assert_eq!(store.load(&by_seconds.id).await.unwrap(), Some(by_seconds));
+    assert_eq!(store.load(&by_string.id).await.unwrap(), Some(by_string));
+
+    // Table names that aren't plain identifiers are rejected, not spliced
+    // into SQL.
+    assert!(matches!(
+        store.migrate_from_sqlx_store("sqlx_sessions; drop table x").await,
+        Err(tower_sessions_core::session_store::Error::Backend(message))
+            if message.starts_with("Invalid store configuration")
+    ));
+}
+
+#[tokio::test]
+async fn warmup_opens_the_pools_connections() {
+    let store = SqliteStore::builder()
+        .in_memory_shared("warmup_opens_the_pools_connections")
+        .max_connections(4)
+        .min_idle(0)
+        .build()
+        .unwrap();
+    assert_eq!(store.pool_state().connections, 0);
+
+    store.warmup_connections(2).await.unwrap();
+    let state = store.pool_state();
+    assert_eq!((state.connections, state.idle_connections), (2, 2));
+
+    store.warmup().await.unwrap();
+    let state = store.pool_state();
+    assert_eq!((state.connections, state.idle_connections), (4, 4));
+
+    // Asking for more than the pool holds is capped.
+    store.warmup_connections(10).await.unwrap();
+    assert_eq!(store.pool_state().connections, 4);
+}
+
+#[tokio::test]
+async fn evict_to_cap_removes_the_sessions_closest_to_expiry() {
+    let store = store();
+    // Expiring in -2h, -1h, 1h, ..., 8h.
+    let records: Vec<_> = (-2..=8)
+        .filter(|hours| *hours != 0)
+        .map(|hours| record(Duration::hours(hours)))
+        .collect();
+    assert_eq!(records.len(), 10);
+    store.save_many(&records).await.unwrap();
+
+    assert_eq!(store.evict_to_cap(20).await.unwrap(), 0);
+    assert_eq!(store.evict_to_cap(6).await.unwrap(), 4);
+    assert_eq!(store.count().await.unwrap(), 6);
+    for (n, record) in records.iter().enumerate() {
+        assert_eq!(
+            store.load_raw(&record.id).await.unwrap().is_some(),
+            n >= 4,
+            "session {n}"
+        );
+    }
+
+    assert_eq!(store.evict_to_cap(0).await.unwrap(), 6);
+    assert_eq!(store.count().await.unwrap(), 0);
+}
+
+#[test]
+fn strict_tables_reject_binary_ids() {
+    let store = SqliteStore::in_memory()
+        .unwrap()
+        .with_strict_tables(true)
+        .with_binary_ids(true);
+
+    let mut conn = store.conn().unwrap();
+    assert!(matches!(
+        store.migrate_with_conn(&mut conn),
+        Err(crate::SqliteStoreError::InvalidConfig(_))
+    ));
+    assert!(columns(&conn, "tower_sessions").is_empty());
+    drop(conn);
+
+    let store = store.with_binary_ids(false);
+    store.migrate().unwrap();
+    store.verify_schema().unwrap();
+}
+
+#[tokio::test]
+async fn change_listener_sees_committed_mutations_in_order() {
+    use crate::SessionEvent;
+
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let listener = {
+        let events = events.clone();
+        Arc::new(move |event| events.lock().unwrap().push(event))
+    };
+    let store = store()
+        .with_change_listener(listener)
+        .with_lazy_expiry_deletion(true);
+
+    let mut record = record(Duration::hours(1));
+    store.create(&mut record).await.unwrap();
+    store.save(&record).await.unwrap();
+    store.load(&record.id).await.unwrap();
+    store.delete(&record.id).await.unwrap();
+    // Nothing changes, so nothing is reported.
+    store.delete(&record.id).await.unwrap();
+
+    let expired = self::record(Duration::seconds(-1));
+    store.save(&expired).await.unwrap();
+    assert_eq!(store.load(&expired.id).await.unwrap(), None);
+
+    // A failed write isn't reported either.
+    let mut conflicting = self::record(Duration::hours(1));
+    conflicting.id = expired.id;
+    store.save(&expired).await.unwrap();
+    assert!(store.create_unchecked(&conflicting).await.is_err());
+
+    assert_eq!(
+        *events.lock().unwrap(),
+        [
+            SessionEvent::Created(record.id),
+            SessionEvent::Saved(record.id),
+            SessionEvent::Deleted(record.id),
+            SessionEvent::Saved(expired.id),
+            SessionEvent::Expired(expired.id),
+            SessionEvent::Saved(expired.id),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn raw_bytes_round_trip_untouched() {
+    let store = store().with_format_tags(true);
+    let expiry = OffsetDateTime::now_utc() + Duration::hours(1);
+
+    let id = Id::default();
+    let bytes: Vec<u8> = (0..=255).rev().collect();
+    store.save_raw(&id, &bytes, expiry).await.unwrap();
+    assert_eq!(store.load_raw(&id).await.unwrap(), Some(bytes));
+    assert_eq!(store.expiry_of(&id).await.unwrap(), Some(expiry));
+
+    store.save_raw(&id, b"", expiry).await.unwrap();
+    assert_eq!(store.load_raw(&id).await.unwrap(), Some(Vec::new()));
+
+    // Expired sessions aren't returned, however they were stored.
+    let expired = Id::default();
+    store
+        .save_raw(&expired, b"stale", expiry - Duration::days(1))
+        .await
+        .unwrap();
+    assert_eq!(store.load_raw(&expired).await.unwrap(), None);
+    assert_eq!(store.load_raw(&Id::default()).await.unwrap(), None);
+
+    // A blob copied from one store loads in another with the same settings.
+    let record = record(Duration::hours(1));
+    let source = self::store().with_format_tags(true);
+    source.save(&record).await.unwrap();
+    let blob = source.load_raw(&record.id).await.unwrap().unwrap();
+    store
+        .save_raw(&record.id, &blob, record.expiry_date)
+        .await
+        .unwrap();
+    assert_eq!(store.load(&record.id).await.unwrap(), Some(record));
+}
+
+#[tokio::test]
+async fn query_interrupt_aborts_runaway_statements() {
+    use tower_sessions_core::session_store::Error;
+
+    let store = store().with_query_interrupt(std::time::Duration::from_millis(20));
+    store
+        .pool()
+        .get()
+        .unwrap()
+        .execute_batch(
+            r#"
+            create trigger slow before insert on tower_sessions
+            begin
+                select count(*) from (
+                    with recursive n(x) as (select 1 union all select x + 1 from n)
+                    select x from n limit 10000000000
+                );
+            end;
+            "#,
+        )
+        .unwrap();
+
+    let record = record(Duration::hours(1));
+    let started = std::time::Instant::now();
+    assert!(matches!(
+        store.save(&record).await,
+        Err(Error::Backend(message)) if message.starts_with("Query interrupted")
+    ));
+    assert!(started.elapsed() < std::time::Duration::from_secs(5));
+    assert_eq!(store.count().await.unwrap(), 0);
+
+    // The connection goes back to the pool without the handler.
+    let conn = store.pool().get().unwrap();
+    conn.execute_batch("drop trigger slow").unwrap();
+    let sum: i64 = conn
+        .query_row(
+            "with recursive n(x) as (select 1 union all select x + 1 from n limit 1000000) select sum(x) from n",
+            [],
+            |row| row.get(0),
+        )
+        .unwrap();
+    assert_eq!(sum, 500_000_500_000);
+}
+
+fn last_accessed(store: &SqliteStore, id: &Id) -> Option<i64> {
+    store
+        .pool()
+        .get()
+        .unwrap()
+        .query_row(
+            "select last_accessed from tower_sessions where id = ?1",
+            [id.to_string()],
+            |row| row.get(0),
+        )
+        .unwrap()
+}
+
+#[tokio::test]
+async fn track_access_records_loads_and_finds_idle_sessions() {
+    let clock = ManualClock::new();
+    let store = store().with_track_access(true).with_clock(clock.clone());
+
+    let mut idle = record(Duration::hours(1));
+    let mut active = record(Duration::hours(1));
+    store.create(&mut idle).await.unwrap();
+    store.create(&mut active).await.unwrap();
+    assert_eq!(last_accessed(&store, &active.id), None);
+
+    clock.advance(Duration::minutes(10));
+    store.load(&active.id).await.unwrap().unwrap();
+    let first = last_accessed(&store, &active.id).unwrap();
+    assert_eq!(first, crate::unix_nanos(clock.now()));
+
+    clock.advance(Duration::minutes(10));
+    store.load(&active.id).await.unwrap().unwrap();
+    assert!(last_accessed(&store, &active.id).unwrap() > first);
+
+    let cutoff = clock.now() - Duration::minutes(5);
+    assert_eq!(store.idle_since(cutoff).await.unwrap(), vec![idle.id]);
+}
+
+#[tokio::test]
+async fn track_access_never_fails_a_load() {
+    let db = TempDb::new();
+    let store = impatient_store(&db).with_track_access(true);
+
+    let mut record = record(Duration::hours(1));
+    store.create(&mut record).await.unwrap();
+
+    let lock = lock_for_writing(&db);
+    assert_eq!(store.load(&record.id).await.unwrap(), Some(record.clone()));
+    assert_eq!(last_accessed(&store, &record.id), None);
+
+    drop(lock);
+    store.load(&record.id).await.unwrap().unwrap();
+    assert!(last_accessed(&store, &record.id).is_some());
+}
+
+#[tokio::test]
+async fn default_stores_are_ready_and_independent() {
+    let (store, other) = (SqliteStore::default(), SqliteStore::default());
+
+    let mut record = record(Duration::hours(1));
+    store.create(&mut record).await.unwrap();
+    assert_eq!(store.load(&record.id).await.unwrap(), Some(record.clone()));
+    assert_eq!(other.load(&record.id).await.unwrap(), None);
+}
+
+#[tokio::test]
+async fn delete_expired_reporting_reports_a_running_total_per_batch() {
+    let store = store();
+    let expired: Vec<_> = (0..250).map(|_| record(Duration::seconds(-1))).collect();
+    store.save_many(&expired).await.unwrap();
+    store.save(&record(Duration::hours(1))).await.unwrap();
+
+    let mut totals = Vec::new();
+    let deleted = store
+        .delete_expired_reporting(100, |total| totals.push(total))
+        .await
+        .unwrap();
+    assert_eq!(deleted, 250);
+    assert_eq!(totals, [100, 200, 250]);
+    assert_eq!(store.count().await.unwrap(), 1);
+
+    // An exact multiple of the batch ends on an empty batch, which isn't
+    // reported.
+    store.save_many(&expired[..200]).await.unwrap();
+    let mut totals = Vec::new();
+    store
+        .delete_expired_reporting(100, |total| totals.push(total))
+        .await
+        .unwrap();
+    assert_eq!(totals, [100, 200]);
+
+    let mut calls = 0;
+    assert_eq!(
+        store
+            .delete_expired_reporting(100, |_| calls += 1)
+            .await
+            .unwrap(),
+        0
+    );
+    assert_eq!(calls, 0);
+}
+
+#[tokio::test]
+async fn expiry_histogram_buckets_live_sessions_within_the_horizon() {
+    let clock = ManualClock::new();
+    let store = store().with_clock(clock.clone());
+    let now = clock.now();
+
+    for minutes in [-10, 10, 20, 59, 60, 61, 130, 180, 181, 300] {
+        let mut record = record(Duration::ZERO);
+        record.expiry_date = now + Duration::minutes(minutes);
+        store.save(&record).await.unwrap();
+    }
+
+    assert_eq!(
+        store
+            .expiry_histogram(Duration::hours(1), Duration::hours(3))
+            .await
+            .unwrap(),
+        [
+            (now, 3),
+            (now + Duration::hours(1), 2),
+            (now + Duration::hours(2), 1),
+            (now + Duration::hours(3), 1),
+        ]
+    );
+    assert!(
+        store
+            .expiry_histogram(Duration::hours(1), Duration::ZERO)
+            .await
+            .unwrap()
+            .is_empty()
+    );
+}
+
+#[test]
+fn parse_id_reports_the_offending_value() {
+    use r2d2_sqlite::rusqlite::types::ValueRef;
+
+    let id = Id::default();
+    let text = id.to_string();
+    assert_eq!(
+        crate::parse_id(ValueRef::Text(text.as_bytes())).unwrap(),
+        id
+    );
+    assert_eq!(
+        crate::parse_id(ValueRef::Blob(&id.0.to_le_bytes())).unwrap(),
+        id
+    );
+
+    for (value, shown) in [
+        (ValueRef::Text(b"not an id"), r#""not an id""#),
+        (ValueRef::Blob(&[0xde, 0xad]), "x'dead'"),
+        (ValueRef::Integer(42), "42"),
+        (ValueRef::Null, "null"),
+    ] {
+        assert!(matches!(
+            crate::parse_id(value),
+            Err(crate::SqliteStoreError::InvalidId(found)) if found == shown
+        ));
+    }
+}
+
+#[tokio::test]
+async fn enumerations_surface_invalid_ids() {
+    use tower_sessions_core::session_store::Error;
+
+    let store = store().with_user_id_extractor(user_field);
+    let record = record_of("alice", Duration::minutes(1));
+    store.save(&record).await.unwrap();
+    store
+        .pool()
+        .get()
+        .unwrap()
+        .execute("update tower_sessions set id = x'dead'", [])
+        .unwrap();
+
+    let is_invalid_id = |result: Result<Vec<Id>, Error>| matches!(result, Err(Error::Decode(message)) if message.contains("x'dead'"));
+    assert!(is_invalid_id(
+        store.expiring_within(Duration::hours(1)).await
+    ));
+    assert!(is_invalid_id(store.sessions_for_user("alice").await));
+    assert!(is_invalid_id(
+        store.idle_since(OffsetDateTime::now_utc()).await
+    ));
+}
+
+/// A store over a fresh file in rollback-journal mode with `synchronous =
+/// FULL`, so seeding has settings to change and restore.
+fn durable_store(db: &TempDb) -> SqliteStore {
+    SqliteStore::builder()
+        .path(db.path())
+        .with_pragmas(crate::PragmaConfig {
+            journal_mode: crate::JournalMode::Delete,
+            synchronous: crate::Synchronous::Full,
+            ..Default::default()
+        })
+        .auto_migrate()
+        .build()
+        .unwrap()
+}
+
+#[tokio::test]
+async fn bulk_seed_restores_the_connection_settings() {
+    let db = TempDb::new();
+    let store = durable_store(&db);
+    let settings = || {
+        let conn = store.pool().get().unwrap();
+        (pragma(&conn, "journal_mode"), pragma(&conn, "synchronous"))
+    };
+    let expected = ("delete".to_owned(), "2".to_owned());
+
+    let records: Vec<_> = (0..100).map(|_| record(Duration::hours(1))).collect();
+    store.bulk_seed(&records).await.unwrap();
+    assert_eq!(store.count().await.unwrap(), 100);
+    assert_eq!(settings(), expected);
+
+    // A failed seed writes nothing and restores them too.
+    store
+        .pool()
+        .get()
+        .unwrap()
+        .execute_batch(
+            r#"
+            create trigger reject_all before insert on tower_sessions
+            begin select raise(abort, 'rejected'); end;
+            "#,
+        )
+        .unwrap();
+    let more: Vec<_> = (0..100).map(|_| record(Duration::hours(1))).collect();
+    assert!(store.bulk_seed(&more).await.is_err());
+    assert_eq!(store.count().await.unwrap(), 100);
+    assert_eq!(settings(), expected);
+}
+
+#[tokio::test]
+#[ignore = "seeds 50k sessions; run with --ignored"]
+async fn bulk_seed_loads_fifty_thousand_sessions() {
+    let db = TempDb::new();
+    let store = durable_store(&db);
+    let records: Vec<_> = (0..50_000).map(|_| record(Duration::hours(1))).collect();
+
+    store.bulk_seed(&records).await.unwrap();
+
+    assert_eq!(store.count().await.unwrap(), 50_000);
+    let last = records.last().unwrap();
+    assert_eq!(store.load(&last.id).await.unwrap().as_ref(), Some(last));
+}
+
+/// Makes `store` act as if linked against SQLite 3.34, which predates
+/// `RETURNING` and `STRICT` tables.
+fn on_old_sqlite<M>(mut store: SqliteStore<M>) -> SqliteStore<M>
+where
+    M: r2d2::ManageConnection<Connection = Connection>,
+{
+    store.sqlite_version_override = Some(3_034_000);
+    store
+}
+
+#[test]
+fn sqlite_version_reports_capabilities() {
+    let store = store();
+    let (number, version) = store.sqlite_version();
+
+    assert!(number > 0);
+    assert_eq!(crate::Version::from_number(number), version);
+    assert_eq!(version.major, 3);
+    assert_eq!(store.supports_returning(), number >= 3_035_000);
+    assert_eq!(store.supports_strict(), number >= 3_037_000);
+
+    let old = on_old_sqlite(store);
+    assert_eq!(old.sqlite_version().1.to_string(), "3.34.0");
+    assert!(!old.supports_returning());
+    assert!(!old.supports_strict());
+}
+
+#[tokio::test]
+async fn returning_fallbacks_give_identical_results() {
+    let (current, old) = (store(), on_old_sqlite(store()));
+    assert!(current.supports_returning());
+
+    for store in [&current, &old] {
+        let mut live = record(Duration::hours(1));
+        let expired = record(Duration::seconds(-10));
+        store.create(&mut live).await.unwrap();
+        store.save(&expired).await.unwrap();
+
+        let new_expiry = OffsetDateTime::now_utc() + Duration::hours(2);
+        let touched = store.load_and_touch(&live.id, new_expiry).await.unwrap();
+        assert_eq!(
+            touched,
+            Some(Record {
+                expiry_date: new_expiry,
+                ..live.clone()
+            })
+        );
+        assert_eq!(store.expiry_of(&live.id).await.unwrap(), Some(new_expiry));
+        assert_eq!(
+            store.load_and_touch(&expired.id, new_expiry).await.unwrap(),
+            None
+        );
+        assert_eq!(
+            store
+                .load_and_touch(&Id::default(), new_expiry)
+                .await
+                .unwrap(),
+            None
+        );
+
+        assert_eq!(
+            store.delete_returning(&live.id).await.unwrap(),
+            Some(new_expiry)
+        );
+        assert_eq!(store.delete_returning(&live.id).await.unwrap(), None);
+        assert_eq!(store.count().await.unwrap(), 1);
+    }
+}
+
+#[tokio::test]
+async fn expired_deletion_fallback_reports_the_same_events() {
+    for old in [false, true] {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let listener = {
+            let events = events.clone();
+            Arc::new(move |event| events.lock().unwrap().push(event))
+        };
+        let mut store = store().with_change_listener(listener);
+        if old {
+            store = on_old_sqlite(store);
+        }
+
+        let expired = record(Duration::seconds(-10));
+        let mut live = record(Duration::hours(1));
+        store.save(&expired).await.unwrap();
+        store.create(&mut live).await.unwrap();
+        events.lock().unwrap().clear();
+
+        tower_sessions_core::ExpiredDeletion::delete_expired(&store)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            *events.lock().unwrap(),
+            [crate::SessionEvent::Expired(expired.id)]
+        );
+        assert_eq!(store.active_ids().await.unwrap(), [live.id]);
+        assert_eq!(store.count().await.unwrap(), 1);
+    }
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn session_locks_serialize_concurrent_updates() {
+    let store = store();
+    let mut session = record(Duration::hours(1));
+    session
+        .data
+        .insert("count".to_owned(), serde_json::json!(0));
+    store.save(&session).await.unwrap();
+
+    let tasks: Vec<_> = (0..50)
+        .map(|_| {
+            let store = store.clone();
+            let id = session.id;
+            tokio::spawn(async move {
+                store
+                    .with_session_lock(&id, |record| {
+                        let count = record.data["count"].as_u64().unwrap();
+                        record.data.insert("count".to_owned(), (count + 1).into());
+                    })
+                    .await
+                    .unwrap()
+                    .unwrap();
+            })
+        })
+        .collect();
+    for task in tasks {
+        task.await.unwrap();
+    }
+
+    let loaded = store.load(&session.id).await.unwrap().unwrap();
+    assert_eq!(loaded.data["count"], 50);
+    assert!(store.session_locks.is_empty());
+
+    // Missing sessions are left alone.
+    let missing = store
+        .with_session_lock(&Id::default(), |_| ())
+        .await
+        .unwrap();
+    assert_eq!(missing, None);
+}
+
+/// A session for `user` expiring `expires_in` after `clock`'s now.
+fn session_of(user: &str, clock: &ManualClock, expires_in: Duration) -> Record {
+    let mut data = std::collections::HashMap::new();
+    data.insert("user".to_owned(), serde_json::json!(user));
+
+    Record {
+        id: Id::default(),
+        data,
+        expiry_date: clock.now() + expires_in,
+    }
+}
+
+#[tokio::test]
+async fn deduplicate_keeps_one_session_per_group() {
+    let clock = ManualClock::new();
+    let store = store().with_clock(clock.clone());
+
+    // Per user: the oldest session expires last, the newest first.
+    let mut sessions = std::collections::HashMap::new();
+    for (i, expires_in) in [3, 2, 1].into_iter().enumerate() {
+        for user in ["alice", "bob"] {
+            let session = session_of(user, &clock, Duration::hours(expires_in));
+            store.save(&session).await.unwrap();
+            sessions.insert((user, i), session.id);
+        }
+        clock.advance(Duration::seconds(1));
+    }
+    let carol = session_of("carol", &clock, Duration::hours(1));
+    store.save(&carol).await.unwrap();
+    let expired = session_of("alice", &clock, -Duration::hours(1));
+    store.save(&expired).await.unwrap();
+
+    let live = |store: SqliteStore| async move {
+        let mut ids = store.active_ids().await.unwrap();
+        ids.sort_by_key(|id| id.0);
+        ids
+    };
+    let sorted = |mut ids: Vec<Id>| {
+        ids.sort_by_key(|id| id.0);
+        ids
+    };
+
+    // Copies of the table, so both policies run on the same sessions.
+    let latest_expiry = store.clone().with_table_name("by_expiry");
+    latest_expiry.migrate().unwrap();
+    store
+        .pool()
+        .get()
+        .unwrap()
+        .execute_batch("insert into by_expiry select * from tower_sessions")
+        .unwrap();
+
+    assert_eq!(store.deduplicate(DedupKeep::Newest).await.unwrap(), 4);
+    assert_eq!(
+        live(store.clone()).await,
+        sorted(vec![
+            sessions[&("alice", 2)],
+            sessions[&("bob", 2)],
+            carol.id
+        ])
+    );
+
+    assert_eq!(
+        latest_expiry
+            .deduplicate(DedupKeep::LatestExpiry)
+            .await
+            .unwrap(),
+        4
+    );
+    assert_eq!(
+        live(latest_expiry).await,
+        sorted(vec![
+            sessions[&("alice", 0)],
+            sessions[&("bob", 0)],
+            carol.id
+        ])
+    );
+
+    // Expired duplicates are left for `delete_expired`.
+    assert_eq!(store.count().await.unwrap(), 4);
+    assert_eq!(store.deduplicate(DedupKeep::Newest).await.unwrap(), 0);
+}
+
+#[test]
+fn page_size_only_applies_to_a_fresh_database() {
+    let db = TempDb::new();
+    let open = |page_size| {
+        SqliteStore::builder()
+            .path(db.path())
+            .with_pragmas(crate::PragmaConfig {
+                page_size: Some(page_size),
+                ..Default::default()
+            })
+            .auto_migrate()
+            .build()
+            .unwrap()
+    };
+
+    let store = open(8192);
+    assert_eq!(pragma(&store.pool().get().unwrap(), "page_size"), "8192");
+    drop(store);
+
+    let reopened = open(1024);
+    assert_eq!(pragma(&reopened.pool().get().unwrap(), "page_size"), "8192");
+}
+
+#[tokio::test]
+async fn await_expiry_returns_once_the_session_expires() {
+    let store = store();
+    let session = record(Duration::milliseconds(100));
+    store.save(&session).await.unwrap();
+
+    let started = std::time::Instant::now();
+    store.await_expiry(&session.id).await.unwrap();
+    let waited = started.elapsed();
+    assert!(waited >= std::time::Duration::from_millis(90), "{waited:?}");
+    assert!(waited < std::time::Duration::from_secs(2), "{waited:?}");
+    assert_eq!(store.load(&session.id).await.unwrap(), None);
+
+    // Expired and missing sessions don't wait at all.
+    let started = std::time::Instant::now();
+    store.await_expiry(&session.id).await.unwrap();
+    store.await_expiry(&Id::default()).await.unwrap();
+    assert!(started.elapsed() < std::time::Duration::from_millis(500));
+}
+
+#[tokio::test]
+async fn await_expiry_follows_an_extended_expiry() {
+    let store = store();
+    let mut session = record(Duration::milliseconds(100));
+    store.save(&session).await.unwrap();
+
+    let started = std::time::Instant::now();
+    let waiting = tokio::spawn({
+        let store = store.clone();
+        let id = session.id;
+        async move { store.await_expiry(&id).await }
+    });
+
+    tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+    session.expiry_date = OffsetDateTime::now_utc() + Duration::milliseconds(200);
+    store.save(&session).await.unwrap();
+
+    waiting.await.unwrap().unwrap();
+    assert!(started.elapsed() >= std::time::Duration::from_millis(240));
+}
+
+#[cfg(feature = "compression")]
+#[tokio::test]
+async fn reencode_all_compresses_rows_written_before_compression() {
+    let plain = store();
+    let mut sessions = Vec::new();
+    for _ in 0..5 {
+        // A single key, so the encoding doesn't depend on map order.
+        let mut session = record(Duration::hours(1));
+        session.data = [("notes".to_owned(), "lorem ipsum ".repeat(500).into())].into();
+        plain.save(&session).await.unwrap();
+        sessions.push(session);
+    }
+
+    let store = plain
+        .with_compression(crate::CompressionLevel::default())
+        .with_batch_size(2);
+    assert_eq!(store.reencode_all().await.unwrap(), 5);
+
+    for session in &sessions {
+        let raw = store.load_raw(&session.id).await.unwrap().unwrap();
+        assert_eq!(raw[0], crate::COMPRESSED_MARKER);
+        assert_eq!(
+            store.load(&session.id).await.unwrap().as_ref(),
+            Some(session)
+        );
+    }
+
+    // Rows already in the current format are left alone.
+    assert_eq!(store.reencode_all().await.unwrap(), 0);
+}
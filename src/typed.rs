@@ -0,0 +1,258 @@
+use std::{fmt, marker::PhantomData, sync::Arc};
+
+use r2d2::ManageConnection;
+use r2d2_sqlite::{
+    SqliteConnectionManager,
+    rusqlite::{Connection, OptionalExtension, params},
+};
+use serde::{Serialize, de::DeserializeOwned};
+use tower_sessions_core::{session::Id, session_store};
+
+use crate::{Data, SqliteStore, SqliteStoreError, is_valid_identifier};
+
+/// Keeps values of any serde type `T` by session id in a table of their own,
+/// e.g. metadata that belongs next to a session but not inside it.
+///
+/// Created through [`SqliteStore::typed`]. It shares the store's pool,
+/// schema and blob settings, so values are compressed and encrypted like
+/// session data, but it only offers plain `save`, `load` and `delete`:
+/// values don't expire and this is not a [`SessionStore`].
+/// Values are always serialized as MessagePack, whatever the store's
+/// [serializer](SqliteStore::with_serializer), and stored behind a format
+/// tag, since their encoding may start with any byte.
+///
+/// [`SessionStore`]: tower_sessions_core::SessionStore
+pub struct TypedSqliteStore<T, M = SqliteConnectionManager>
+where
+    M: ManageConnection<Connection = Connection>,
+{
+    store: SqliteStore<M>,
+    /// How queries refer to the table: its name, qualified by the store's
+    /// schema.
+    table: Arc<str>,
+    value: PhantomData<fn() -> T>,
+}
+
+impl<M> SqliteStore<M>
+where
+    M: ManageConnection<Connection = Connection>,
+{
+    /// Stores values of type `T` by session id in the table `table_name`,
+    /// next to the session table; see [`TypedSqliteStore`].
+    ///
+    /// The table has to be created with [`TypedSqliteStore::migrate`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if `table_name` isn't a plain identifier, as with
+    /// [`with_table_name`](Self::with_table_name).
+    pub fn typed<T>(&self, table_name: impl Into<String>) -> TypedSqliteStore<T, M> {
+        let table_name = table_name.into();
+
+        assert!(
+            is_valid_identifier(&table_name),
+            "invalid table name {table_name:?}: expected [A-Za-z_][A-Za-z0-9_]*"
+        );
+
+        TypedSqliteStore {
+            store: self.clone(),
+            table: format!("{}{table_name}", self.schema_prefix()).into(),
+            value: PhantomData,
+        }
+    }
+}
+
+// Derived impls would put bounds on `T` and `M`.
+impl<T, M> Clone for TypedSqliteStore<T, M>
+where
+    M: ManageConnection<Connection = Connection>,
+{
+    fn clone(&self) -> Self {
+        Self {
+            store: self.store.clone(),
+            table: self.table.clone(),
+            value: PhantomData,
+        }
+    }
+}
+
+impl<T, M> fmt::Debug for TypedSqliteStore<T, M>
+where
+    M: ManageConnection<Connection = Connection> + fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TypedSqliteStore")
+            .field("store", &self.store)
+            .field("table", &self.table)
+            .finish()
+    }
+}
+
+impl<T, M> TypedSqliteStore<T, M>
+where
+    T: Serialize + DeserializeOwned + Send + 'static,
+    M: ManageConnection<Connection = Connection>,
+{
+    /// Creates the table if it doesn't exist yet.
+    pub fn migrate(&self) -> session_store::Result<()> {
+        self.migrate_with_conn()?;
+        Ok(())
+    }
+
+    /// Stores `value` under `id`, replacing any value already there.
+    pub async fn save(&self, id: &Id, value: &T) -> session_store::Result<()> {
+        let payload = rmp_serde::to_vec(value).map_err(SqliteStoreError::from)?;
+        let id = *id;
+        let table = self.table.clone();
+
+        self.store
+            .run_blocking(move |store| {
+                store.ensure_writable()?;
+
+                let query = format!(
+                    r#"
+                    insert into {table} (id, data) values (?1, ?2)
+                    on conflict(id) do update set data = excluded.data
+                    "#
+                );
+
+                store.conn()?.prepare_cached(&query)?.execute(params![
+                    store.id_value(&id),
+                    // MessagePack encodes small integers, among others, as
+                    // a single byte that could pass for a format tag.
+                    store.data_value(store.seal(payload, true)?)
+                ])?;
+
+                Ok(())
+            })
+            .await?;
+
+        Ok(())
+    }
+
+    /// Returns the value stored under `id`, if any.
+    pub async fn load(&self, id: &Id) -> session_store::Result<Option<T>> {
+        let id = *id;
+        let table = self.table.clone();
+
+        let value = self
+            .store
+            .run_blocking(move |store| {
+                let query = format!("select data from {table} where id = ?1");
+
+                let data = store
+                    .conn()?
+                    .prepare_cached(&query)?
+                    .query_row([store.id_value(&id)], |row| {
+                        row.get::<_, Data>(0).map(|data| data.0)
+                    })
+                    .optional()?;
+
+                data.map(|data| Ok(rmp_serde::from_slice(&store.unseal(&data)?)?))
+                    .transpose()
+            })
+            .await?;
+
+        Ok(value)
+    }
+
+    /// Deletes the value stored under `id`, returning whether there was one.
+    pub async fn delete(&self, id: &Id) -> session_store::Result<bool> {
+        let id = *id;
+        let table = self.table.clone();
+
+        let deleted = self
+            .store
+            .run_blocking(move |store| {
+                store.ensure_writable()?;
+
+                let deleted = store
+                    .conn()?
+                    .prepare_cached(&format!("delete from {table} where id = ?1"))?
+                    .execute([store.id_value(&id)])?;
+
+                Ok(deleted > 0)
+            })
+            .await?;
+
+        Ok(deleted)
+    }
+
+    fn migrate_with_conn(&self) -> Result<(), SqliteStoreError> {
+        self.store.ensure_writable()?;
+        self.store.ensure_strict_supported()?;
+
+        let strict = if self.store.strict_tables {
+            " strict"
+        } else {
+            ""
+        };
+
+        self.store.conn()?.execute_batch(&format!(
+            r#"
+            create table if not exists {table} (
+                id text primary key not null,
+                data blob not null
+            ){strict};
+            "#,
+            table = self.table,
+        ))?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+    use tower_sessions_core::session::Id;
+
+    use super::*;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Device {
+        name: String,
+        trusted: bool,
+        logins: u32,
+    }
+
+    fn store() -> SqliteStore {
+        let store = SqliteStore::in_memory().unwrap();
+        store.migrate().unwrap();
+        store
+    }
+
+    #[tokio::test]
+    async fn round_trips_a_custom_struct() {
+        let devices = store().typed::<Device>("devices");
+        devices.migrate().unwrap();
+
+        let id = Id::default();
+        let device = Device {
+            name: "laptop".to_owned(),
+            trusted: true,
+            logins: 3,
+        };
+
+        assert_eq!(devices.load(&id).await.unwrap(), None);
+
+        devices.save(&id, &device).await.unwrap();
+        assert_eq!(devices.load(&id).await.unwrap(), Some(device));
+
+        assert!(devices.delete(&id).await.unwrap());
+        assert!(!devices.delete(&id).await.unwrap());
+        assert_eq!(devices.load(&id).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn round_trips_values_encoding_to_a_low_first_byte() {
+        let counters = store().typed::<u8>("counters");
+        counters.migrate().unwrap();
+
+        for value in [0, 1, 2, 3, 5, 0x1f, 0x20, 0xff] {
+            let id = Id::default();
+            counters.save(&id, &value).await.unwrap();
+            assert_eq!(counters.load(&id).await.unwrap(), Some(value));
+        }
+    }
+}